@@ -0,0 +1,57 @@
+//! Benchmarks for parsing and compiling. Run with `cargo bench`.
+
+use ascparse::ir::compile;
+use ascparse::parse;
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+
+/// A small script, like most of those compiled in practice.
+const SMALL: &str = r#"
+total = sum (map (\item -> index item 0 * index item 1) @items);
+discount = if total > 100 then 0.1 else 0;
+label = currency_fmt "EUR" (total * (1 - discount));
+"#;
+
+/// A program of mostly literals: numbers, strings and lists of them.
+fn literals() -> String {
+    let mut src = String::new();
+    for i in 0..200 {
+        src.push_str(&format!(
+            "n{0} = {0}; s{0} = \"item {0}\"; l{0} = [{0}, {0}.5, -{0}, [1, 2, 3]]; \
+             m{0} = [n{0}, s{0}, null, true];\n",
+            i
+        ));
+    }
+    src
+}
+
+/// Expressions nested moderately deeply, so that subexpressions are compiled at many levels.
+fn nested() -> String {
+    let mut src = String::new();
+    for i in 0..50 {
+        let mut expr = format!("x{}", i);
+        for j in 0..30 {
+            expr = format!("let y = (if {} > {} then {} else [{}, 1]) in y + 1", expr, j, j, j);
+        }
+        src.push_str(&format!("f{0} x{0} = {1};\n", i, expr));
+    }
+    src
+}
+
+fn bench(c: &mut Criterion) {
+    c.bench_function("compile_str small", |b| {
+        b.iter(|| ascparse::compile_str(black_box(SMALL)).unwrap())
+    });
+
+    for (name, src) in &[("literals", literals()), ("nested", nested())] {
+        c.bench_function(&format!("parse {}", name), |b| {
+            b.iter(|| parse(black_box(src)).unwrap())
+        });
+        let prog = parse(src).unwrap();
+        c.bench_function(&format!("compile {}", name), |b| {
+            b.iter_batched(|| prog.clone(), compile, BatchSize::SmallInput)
+        });
+    }
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
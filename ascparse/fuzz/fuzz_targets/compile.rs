@@ -0,0 +1,14 @@
+//! Compiles arbitrary source code, and decompiles and recompiles whatever compiles. Run with
+//! `cargo fuzz run compile` in the repository root.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|src: &str| {
+    if let Ok(defs) = ascparse::try_compile(src) {
+        let source = ascparse::decompile::to_source(&defs);
+        if let Err(err) = ascparse::try_compile(&source) {
+            panic!("decompiled source doesn't compile: {}\n{}", err, source);
+        }
+    }
+});
@@ -1,243 +1,875 @@
 use nom::branch::alt;
 use nom::bytes::complete::tag;
-use nom::bytes::complete::take_while;
+use nom::bytes::complete::tag_no_case;
 use nom::bytes::complete::take_while1;
+use nom::character::complete::digit1;
 use nom::character::complete::one_of;
-use nom::combinator::map;
+use nom::combinator::all_consuming;
 use nom::combinator::opt;
+use nom::combinator::value;
+use nom::error::ErrorKind;
+use nom::error::ParseError;
+use nom::sequence::preceded;
+use nom::sequence::tuple;
 use nom::IResult;
-use std::iter;
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::ops::Range;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Program(pub Vec<Decl>);
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Decl {
     pub name: Ident,
     pub params: Vec<Ident>,
     pub body: Expr,
+    /// Byte offsets of the entire declaration in the source, including its doc comment and
+    /// attributes.
+    pub span: Range<usize>,
+    /// The text of the doc comments (`/// ...`) before the declaration, one line each, without
+    /// the `///` and at most one space after it.
+    pub doc: Option<String>,
+    /// The contents of the attributes (`@[...]`) before the declaration. They have no meaning to
+    /// the compiler.
+    pub attrs: Vec<String>,
 }
 
-#[derive(Debug, Clone)]
-pub struct Ident(pub String);
+/// Returns the name in a quoted identifier (without the backticks), with escapes removed.
+pub(crate) fn unquote_ident(quoted: &str) -> String {
+    let mut out = String::with_capacity(quoted.len());
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        // the lexer only allows `\`` and `\\`
+        out.push(if c == '\\' { chars.next().unwrap() } else { c });
+    }
+    out
+}
 
-#[derive(Debug, Clone)]
-pub enum Expr {
+/// A doc comment line or attribute before a declaration.
+pub(crate) enum Annotation {
+    Doc(String),
+    Attr(String),
+}
+
+/// Joins doc comment lines and collects attributes.
+pub(crate) fn collect_annotations(annotations: Vec<Annotation>) -> (Option<String>, Vec<String>) {
+    let mut doc: Option<String> = None;
+    let mut attrs = Vec::new();
+    for annotation in annotations {
+        match annotation {
+            Annotation::Doc(line) => {
+                let line = line.strip_prefix(' ').unwrap_or(&line).trim_end();
+                match &mut doc {
+                    Some(doc) => {
+                        doc.push('\n');
+                        doc.push_str(line);
+                    }
+                    None => doc = Some(line.to_string()),
+                }
+            }
+            Annotation::Attr(attr) => attrs.push(attr.trim().to_string()),
+        }
+    }
+    (doc, attrs)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ident {
+    pub name: String,
+    /// Byte offsets of the identifier in the source.
+    pub span: Range<usize>,
+}
+
+impl Ident {
+    pub fn new(name: impl Into<String>, span: Range<usize>) -> Self {
+        Ident {
+            name: name.into(),
+            span,
+        }
+    }
+}
+
+/// A literal as written in the source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Literal {
+    pub text: String,
+    /// Byte offsets of the literal in the source.
+    pub span: Range<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    /// Byte offsets of the expression in the source, e.g. from the `(` to the `)` of a group.
+    pub span: Range<usize>,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Range<usize>) -> Self {
+        Expr { kind, span }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
     Ident(Ident),
     Group(Box<Expr>),
-    Let(Box<Decl>, Box<Expr>),
+    /// Local declarations (which can all reference each other) and the expression they're used in.
+    Let(Vec<Decl>, Box<Expr>),
+    /// An expression and the local declarations after it (`a where a = 1`), which are scoped like
+    /// those of `Let`.
+    Where(Box<Expr>, Vec<Decl>),
     Apply(Box<Expr>, Op, Box<Expr>),
+    Unary(Op, Box<Expr>),
     List(Vec<Expr>),
-    Number(f64),
+    /// `...xs` in a list, which inserts the items of `xs`. Only appears as an item of `List`.
+    Spread(Box<Expr>),
+    /// `xs[i]`, the item of a list (or character of a string) at an index, which counts from the
+    /// end if it's negative.
+    Index(Box<Expr>, Box<Expr>),
+    /// A number and, if it was parsed from source, the literal as written (e.g. `0xFF`).
+    Number(f64, Option<Literal>),
     String(String),
     Bool(bool),
     Null,
+    /// A date literal (`@2024-01-15`), as `YYYY-MM-DD`.
+    Date(String),
+    /// A date-time literal (`@2024-01-15T10:30:00Z`), as an ISO 8601 date-time.
+    DateTime(String),
     Lambda(Box<Lambda>),
     If(Box<Expr>, Box<Expr>, Box<Expr>),
+    /// `cond { c -> v, ..., else -> d }`: conditions paired with values, where the last arm is the
+    /// default (with a condition of `None`) and no other arm is.
+    Match(Vec<(Option<Expr>, Expr)>),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Lambda {
     pub params: Vec<Ident>,
     pub body: Expr,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Op {
     Apply,
     Infix(Ident),
 }
 
-const MAX_PREC_LEVEL: usize = 12;
-fn prec_level(op: &Op) -> usize {
-    match op {
-        Op::Infix(Ident(op)) => match &**op {
-            "||" => 12,
-            "&&" => 11,
-            "==" | "!=" => 10,
-            ">=" | "<=" | ">" | "<" => 9,
-            "|" => 8,
-            "&" => 7,
-            "<<" | ">>" => 6,
-            "+" | "-" => 5,
-            "*" | "/" | "%" => 4,
-            "^" => 3,
-            _ => 2,
-        },
-        Op::Apply => 1,
-    }
-}
-
-pub(crate) fn fix_expr_prec(expr: Expr) -> Expr {
-    #[derive(Clone)]
-    enum Item {
-        Expr(Expr),
-        Op(Op),
-    }
-
-    fn flatten_expr(expr: Expr) -> Vec<Item> {
-        match expr {
-            Expr::Apply(a, op, b) => flatten_expr(*a)
-                .into_iter()
-                .chain(iter::once(Item::Op(op)))
-                .chain(flatten_expr(*b).into_iter())
-                .collect(),
-            e => vec![Item::Expr(e)],
-        }
-    }
-
-    let mut items = flatten_expr(expr);
-
-    for level in 0..=MAX_PREC_LEVEL {
-        let mut i = 0;
-        while i < items.len() {
-            let item = &items[i];
-            match item {
-                Item::Op(op) if prec_level(&op) == level => {
-                    let op = op.clone();
-                    drop(item);
-                    let prev = items.remove(i - 1);
-                    i -= 1;
-                    let next = items.remove(i + 1);
-
-                    if let (Item::Expr(prev), Item::Expr(next)) = (prev, next) {
-                        items[i] = Item::Expr(Expr::Apply(Box::new(prev), op, Box::new(next)));
-                        i += 1;
-                    } else {
-                        panic!("binary operation does not have expression on either side");
+// The Display impls below print the syntax tree as compact S-expressions for debugging, e.g.
+// `(apply + 1 (apply f x))` for `1 + f x`. This is not valid source code.
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, decl) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", decl)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Decl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "(decl {} (", self.name.name)?;
+        write_params(f, &self.params)?;
+        write!(f, ") {})", self.body)
+    }
+}
+
+fn write_params(f: &mut fmt::Formatter, params: &[Ident]) -> fmt::Result {
+    for (i, param) in params.iter().enumerate() {
+        if i > 0 {
+            write!(f, " ")?;
+        }
+        write!(f, "{}", param.name)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            ExprKind::Ident(ident) => write!(f, "{}", ident.name),
+            ExprKind::Group(expr) => write!(f, "(group {})", expr),
+            ExprKind::Let(decls, body) => {
+                write!(f, "(let (")?;
+                for (i, decl) in decls.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", decl)?;
+                }
+                write!(f, ") {})", body)
+            }
+            ExprKind::Where(body, decls) => {
+                write!(f, "(where {} (", body)?;
+                for (i, decl) in decls.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", decl)?;
+                }
+                write!(f, "))")
+            }
+            ExprKind::Apply(a, Op::Apply, b) => write!(f, "(apply {} {})", a, b),
+            ExprKind::Apply(a, Op::Infix(op), b) => write!(f, "(apply {} {} {})", op.name, a, b),
+            ExprKind::Unary(Op::Infix(op), expr) => write!(f, "(unary {} {})", op.name, expr),
+            ExprKind::Unary(Op::Apply, expr) => write!(f, "(unary apply {})", expr),
+            ExprKind::Spread(expr) => write!(f, "(spread {})", expr),
+            ExprKind::Index(list, index) => write!(f, "(index {} {})", list, index),
+            ExprKind::List(items) => {
+                write!(f, "(list")?;
+                for item in items {
+                    write!(f, " {}", item)?;
+                }
+                write!(f, ")")
+            }
+            ExprKind::Number(n, _) => write!(f, "{}", n),
+            ExprKind::String(s) => write!(f, "{:?}", s),
+            ExprKind::Bool(b) => write!(f, "{}", b),
+            ExprKind::Null => write!(f, "null"),
+            ExprKind::Date(date) | ExprKind::DateTime(date) => write!(f, "@{}", date),
+            ExprKind::Lambda(lambda) => write!(f, "{}", lambda),
+            ExprKind::If(c, t, e) => write!(f, "(if {} {} {})", c, t, e),
+            ExprKind::Match(arms) => {
+                write!(f, "(match")?;
+                for (cond, value) in arms {
+                    match cond {
+                        Some(cond) => write!(f, " ({} {})", cond, value)?,
+                        None => write!(f, " (else {})", value)?,
                     }
                 }
-                _ => i += 1,
+                write!(f, ")")
             }
         }
     }
+}
 
-    assert_eq!(
-        items.len(),
-        1,
-        "binary expression was not reduced to one expression"
-    );
-    match items.remove(0) {
-        Item::Expr(e) => e,
-        _ => panic!("binary expression was reduced to an operator somehow"),
+impl fmt::Display for Lambda {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "(lambda (")?;
+        write_params(f, &self.params)?;
+        write!(f, ") {})", self.body)
     }
 }
 
-fn char_to_num(c: char) -> u8 {
-    match c {
-        '0' => 0,
-        '1' => 1,
-        '2' => 2,
-        '3' => 3,
-        '4' => 4,
-        '5' => 5,
-        '6' => 6,
-        '7' => 7,
-        '8' => 8,
-        '9' => 9,
-        'a' | 'A' => 0xA,
-        'b' | 'B' => 0xB,
-        'c' | 'C' => 0xC,
-        'd' | 'D' => 0xD,
-        'e' | 'E' => 0xE,
-        'f' | 'F' => 0xF,
-        _ => panic!("char_to_num on non-numeric char"),
+/// How operators of the same precedence level group.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Associativity {
+    /// `a op b op c` is `(a op b) op c`.
+    Left,
+    /// `a op b op c` is `a op (b op c)`.
+    Right,
+}
+
+/// Precedence level of function application. Lower levels bind tighter, so operator levels
+/// should be greater than this.
+const APPLY_PREC_LEVEL: usize = 1;
+
+/// Maps infix operators to their precedence level and associativity.
+///
+/// Lower levels bind tighter. Operators that are not in the table use the default entry.
+#[derive(Debug, Clone)]
+pub struct OperatorTable {
+    ops: HashMap<String, (usize, Associativity)>,
+    default: (usize, Associativity),
+}
+
+impl OperatorTable {
+    /// Creates an empty table where all operators use the given default entry.
+    pub fn new(default_level: usize, default_assoc: Associativity) -> Self {
+        OperatorTable {
+            ops: HashMap::new(),
+            default: (default_level, default_assoc),
+        }
+    }
+
+    /// Sets the precedence level and associativity of an operator.
+    pub fn insert(&mut self, op: &str, level: usize, assoc: Associativity) -> &mut Self {
+        self.ops.insert(op.to_string(), (level, assoc));
+        self
+    }
+
+    /// Returns the precedence level and associativity of an operator.
+    pub fn get(&self, op: &Op) -> (usize, Associativity) {
+        match op {
+            Op::Infix(Ident { name: op, .. }) => self.ops.get(op).cloned().unwrap_or(self.default),
+            Op::Apply => (APPLY_PREC_LEVEL, Associativity::Left),
+        }
+    }
+}
+
+impl Default for OperatorTable {
+    /// The built-in operators.
+    fn default() -> Self {
+        use Associativity::*;
+        let mut table = OperatorTable::new(2, Left);
+        table
+            .insert("|>", 14, Left)
+            .insert("||", 13, Left)
+            .insert("&&", 12, Left)
+            .insert("??", 11, Left)
+            .insert("==", 10, Left)
+            .insert("!=", 10, Left);
+        for op in &[">=", "<=", ">", "<"] {
+            table.insert(op, 9, Left);
+        }
+        table
+            .insert("|", 8, Left)
+            .insert("&", 7, Left)
+            .insert("<<", 6, Left)
+            .insert(">>", 6, Left)
+            .insert("+", 5, Left)
+            .insert("-", 5, Left)
+            .insert("*", 4, Left)
+            .insert("/", 4, Left)
+            .insert("%", 4, Left)
+            .insert("^", 3, Right);
+        table
     }
 }
 
-fn num_p_radix(f: char, s: &str, r: u8) -> f64 {
+/// Checks that groups, lists, subscripts and lambdas are not nested more deeply than `max_depth`
+/// anywhere in the expressions (e.g. the bodies of a program's declarations).
+///
+/// Only nesting in the source counts: the operands of a chain of operators or applications (e.g.
+/// `a + b + c`) are at the same depth, even though the chain is a tree of `Apply`s. This uses an
+/// explicit stack, since the expressions themselves may be nested too deeply for recursion.
+pub(crate) fn check_nesting<'a>(
+    exprs: impl IntoIterator<Item = &'a Expr>,
+    max_depth: usize,
+) -> Result<(), SyntaxError> {
+    let mut stack: Vec<_> = exprs.into_iter().map(|expr| (expr, 0)).collect();
+    while let Some((expr, depth)) = stack.pop() {
+        if depth > max_depth {
+            return Err(SyntaxError::TooDeep(max_depth));
+        }
+        match &expr.kind {
+            ExprKind::Group(expr) => stack.push((expr, depth + 1)),
+            ExprKind::Apply(a, _, b) => {
+                stack.push((a, depth));
+                stack.push((b, depth));
+            }
+            ExprKind::Index(list, index) => {
+                stack.push((list, depth));
+                stack.push((index, depth + 1));
+            }
+            ExprKind::List(items) => stack.extend(items.iter().map(|item| (item, depth + 1))),
+            ExprKind::Lambda(lambda) => stack.push((&lambda.body, depth + 1)),
+            ExprKind::Let(decls, expr) | ExprKind::Where(expr, decls) => {
+                stack.extend(decls.iter().map(|decl| (&decl.body, depth)));
+                stack.push((expr, depth));
+            }
+            ExprKind::Unary(_, expr) | ExprKind::Spread(expr) => stack.push((expr, depth)),
+            ExprKind::If(c, t, e) => {
+                stack.push((c, depth));
+                stack.push((t, depth));
+                stack.push((e, depth));
+            }
+            ExprKind::Match(arms) => {
+                for (cond, value) in arms {
+                    stack.extend(cond.iter().map(|cond| (cond, depth)));
+                    stack.push((value, depth));
+                }
+            }
+            ExprKind::Ident(_)
+            | ExprKind::Number(..)
+            | ExprKind::String(_)
+            | ExprKind::Bool(_)
+            | ExprKind::Null
+            | ExprKind::Date(_)
+            | ExprKind::DateTime(_) => (),
+        }
+    }
+    Ok(())
+}
+
+/// Drops expressions (e.g. the bodies of a program's declarations) without recursion. Dropping a
+/// syntax tree normally recurses once per level of nesting, which overflows the stack for trees
+/// that `check_nesting` (or the compiler) rejected for being too deep.
+pub(crate) fn drop_nested(exprs: impl IntoIterator<Item = Expr>) {
+    let mut stack: Vec<Expr> = exprs.into_iter().collect();
+    while let Some(expr) = stack.pop() {
+        match expr.kind {
+            ExprKind::Group(expr) | ExprKind::Unary(_, expr) | ExprKind::Spread(expr) => {
+                stack.push(*expr)
+            }
+            ExprKind::Let(decls, expr) | ExprKind::Where(expr, decls) => {
+                stack.extend(decls.into_iter().map(|decl| decl.body));
+                stack.push(*expr);
+            }
+            ExprKind::Apply(a, _, b) | ExprKind::Index(a, b) => {
+                stack.push(*a);
+                stack.push(*b);
+            }
+            ExprKind::List(items) => stack.extend(items),
+            ExprKind::Lambda(lambda) => stack.push(lambda.body),
+            ExprKind::If(c, t, e) => {
+                stack.push(*c);
+                stack.push(*t);
+                stack.push(*e);
+            }
+            ExprKind::Match(arms) => {
+                for (cond, value) in arms {
+                    stack.extend(cond);
+                    stack.push(value);
+                }
+            }
+            ExprKind::Ident(_)
+            | ExprKind::Number(..)
+            | ExprKind::String(_)
+            | ExprKind::Bool(_)
+            | ExprKind::Null
+            | ExprKind::Date(_)
+            | ExprKind::DateTime(_) => (),
+        }
+    }
+}
+
+/// An item in a sequence of operands and operators that has not been reduced by precedence yet.
+#[derive(Debug, Clone)]
+pub enum ChainItem {
+    Expr(Expr),
+    Op(Op),
+    Prefix(Op),
+}
+
+/// An error in a sequence of operators and operands.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrecError {
+    /// The operator does not have an expression on either side (or after it, for prefix operators).
+    MissingOperand(Op),
+    /// The sequence was reduced to more than one expression.
+    NotReduced(usize),
+    /// There were no expressions at all.
+    Empty,
+}
+
+impl fmt::Display for PrecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PrecError::MissingOperand(Op::Infix(Ident { name: op, .. })) => {
+                write!(f, "operator {} is missing an operand", op)
+            }
+            PrecError::MissingOperand(Op::Apply) => {
+                write!(f, "function application is missing an operand")
+            }
+            PrecError::NotReduced(n) => {
+                write!(
+                    f,
+                    "expression has {} operands that are not connected by operators",
+                    n
+                )
+            }
+            PrecError::Empty => write!(f, "empty expression"),
+        }
+    }
+}
+
+/// Adds an operand that follows a chain without an operator in between, which is usually an
+/// argument the chain's last operand is applied to. A list with one item directly after the last
+/// operand (`xs[i]`, but not `xs [i]`) is a subscript of that operand instead, and binds tighter
+/// than any operator (e.g. `-xs[0]` is `-(xs[0])`).
+pub(crate) fn push_argument(chain: &mut Vec<ChainItem>, mut expr: Expr) {
+    if let (Some(ChainItem::Expr(base)), ExprKind::List(items)) = (chain.last_mut(), &mut expr.kind)
+    {
+        let is_subscript = base.span.end == expr.span.start
+            && items.len() == 1
+            && !matches!(items[0].kind, ExprKind::Spread(_));
+        if is_subscript {
+            let index = items.pop().unwrap();
+            let span = base.span.start..expr.span.end;
+            let list = mem::replace(base, Expr::new(ExprKind::Null, 0..0));
+            *base = Expr::new(ExprKind::Index(Box::new(list), Box::new(index)), span);
+            return;
+        }
+    }
+    chain.push(ChainItem::Op(Op::Apply));
+    chain.push(ChainItem::Expr(expr));
+}
+
+/// Reduces a chain of operands and operators to a single expression (shunting-yard).
+///
+/// The chain must alternate between operands and infix operators (including `Op::Apply`),
+/// and each operand may be preceded by any number of prefix operators.
+pub(crate) fn fix_expr_prec(
+    items: Vec<ChainItem>,
+    table: &OperatorTable,
+) -> Result<Expr, PrecError> {
+    enum StackOp {
+        Infix(Op),
+        Prefix(Op),
+    }
+
+    fn reduce(operands: &mut Vec<Expr>, op: StackOp) -> Result<(), PrecError> {
+        match op {
+            StackOp::Infix(op) => match (operands.pop(), operands.pop()) {
+                (Some(next), Some(prev)) => {
+                    let span = prev.span.start..next.span.end;
+                    let kind = ExprKind::Apply(Box::new(prev), op, Box::new(next));
+                    operands.push(Expr::new(kind, span))
+                }
+                _ => return Err(PrecError::MissingOperand(op)),
+            },
+            StackOp::Prefix(op) => match operands.pop() {
+                Some(next) => {
+                    let start = match &op {
+                        Op::Infix(op) => op.span.start,
+                        Op::Apply => next.span.start,
+                    };
+                    let span = start..next.span.end;
+                    operands.push(Expr::new(ExprKind::Unary(op, Box::new(next)), span))
+                }
+                None => return Err(PrecError::MissingOperand(op)),
+            },
+        }
+        Ok(())
+    }
+
+    let mut operands = Vec::new();
+    let mut operators = Vec::new();
+
+    for item in items {
+        match item {
+            ChainItem::Expr(expr) => operands.push(expr),
+            ChainItem::Prefix(op) => operators.push(StackOp::Prefix(op)),
+            ChainItem::Op(op) => {
+                let (level, assoc) = table.get(&op);
+                while let Some(top) = operators.last() {
+                    let reduce_top = match top {
+                        // prefix operators bind tighter than infix operators, but not tighter
+                        // than application
+                        StackOp::Prefix(_) => level > APPLY_PREC_LEVEL,
+                        StackOp::Infix(top) => {
+                            let (top_level, _) = table.get(top);
+                            top_level < level
+                                || (top_level == level && assoc == Associativity::Left)
+                        }
+                    };
+                    if !reduce_top {
+                        break;
+                    }
+                    if let Some(top) = operators.pop() {
+                        reduce(&mut operands, top)?;
+                    }
+                }
+                operators.push(StackOp::Infix(op));
+            }
+        }
+    }
+
+    while let Some(op) = operators.pop() {
+        reduce(&mut operands, op)?;
+    }
+
+    match operands.pop() {
+        Some(expr) if operands.is_empty() => Ok(expr),
+        Some(_) => Err(PrecError::NotReduced(operands.len() + 1)),
+        None => Err(PrecError::Empty),
+    }
+}
+
+/// Checks that an operator is a valid prefix operator, i.e. `-` (negation) or `!` (logical not).
+pub(crate) fn prefix_op(op: Ident) -> Result<Op, SyntaxError> {
+    match &*op.name {
+        "-" | "!" => Ok(Op::Infix(op)),
+        _ => Err(SyntaxError::UnknownPrefixOp(op.name, op.span)),
+    }
+}
+
+/// Checks that only the last arm of a `cond` expression is the default.
+pub(crate) fn match_expr(
+    arms: Vec<(Option<Expr>, Expr)>,
+    span: Range<usize>,
+) -> Result<ExprKind, SyntaxError> {
+    match arms.split_last() {
+        Some(((None, _), rest)) if rest.iter().all(|(cond, _)| cond.is_some()) => {
+            Ok(ExprKind::Match(arms))
+        }
+        _ => Err(SyntaxError::MatchDefault(span)),
+    }
+}
+
+/// An error found by the parser actions rather than by the grammar itself.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyntaxError {
+    Number(NumberParseError),
+    String(StringParseError),
+    Date(DateParseError),
+    UnknownPrefixOp(String, Range<usize>),
+    Prec(PrecError),
+    /// Groups, lists, subscripts or lambdas are nested more deeply than the given limit.
+    TooDeep(usize),
+    /// A `cond` expression doesn't end with exactly one `else` arm.
+    MatchDefault(Range<usize>),
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyntaxError::Number(err) => write!(f, "{}", err),
+            SyntaxError::String(err) => write!(f, "{}", err),
+            SyntaxError::Date(err) => write!(f, "{}", err),
+            SyntaxError::UnknownPrefixOp(op, _) => write!(f, "unknown prefix operator {}", op),
+            SyntaxError::Prec(err) => write!(f, "{}", err),
+            SyntaxError::TooDeep(limit) => {
+                write!(
+                    f,
+                    "expression is nested too deeply (the limit is {})",
+                    limit
+                )
+            }
+            SyntaxError::MatchDefault(_) => {
+                write!(f, "`cond` must end with exactly one `else` arm")
+            }
+        }
+    }
+}
+
+impl From<NumberParseError> for SyntaxError {
+    fn from(err: NumberParseError) -> Self {
+        SyntaxError::Number(err)
+    }
+}
+
+impl From<PrecError> for SyntaxError {
+    fn from(err: PrecError) -> Self {
+        SyntaxError::Prec(err)
+    }
+}
+
+impl From<StringParseError> for SyntaxError {
+    fn from(err: StringParseError) -> Self {
+        SyntaxError::String(err)
+    }
+}
+
+impl From<DateParseError> for SyntaxError {
+    fn from(err: DateParseError) -> Self {
+        SyntaxError::Date(err)
+    }
+}
+
+/// An error in a number literal.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NumberParseError {
+    /// The literal does not have the shape of a number.
+    Malformed,
+    /// The literal contains a character that is not a digit in its radix.
+    InvalidDigit(char, u8),
+    /// The radix of an `<radix>r<digits>` literal is not in 2..=36.
+    InvalidRadix(String),
+    /// A literal with an exponent is too large to be represented (e.g. `1e400` or `0x1p1024`), or
+    /// too small but not zero (e.g. `1e-400`), which would otherwise silently become infinity or
+    /// zero.
+    ExponentOutOfRange,
+}
+
+impl fmt::Display for NumberParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NumberParseError::Malformed => write!(f, "malformed number literal"),
+            NumberParseError::InvalidDigit(c, r) => {
+                write!(f, "invalid digit '{}' for radix {} in number literal", c, r)
+            }
+            NumberParseError::InvalidRadix(r) => {
+                write!(
+                    f,
+                    "invalid radix {} in number literal (must be between 2 and 36)",
+                    r
+                )
+            }
+            NumberParseError::ExponentOutOfRange => {
+                write!(f, "number literal is out of range")
+            }
+        }
+    }
+}
+
+impl<I> ParseError<I> for NumberParseError {
+    fn from_error_kind(_: I, _: ErrorKind) -> Self {
+        NumberParseError::Malformed
+    }
+
+    fn append(_: I, _: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+type NumResult<'a, T> = IResult<&'a str, T, NumberParseError>;
+
+fn char_to_num(c: char) -> Option<u8> {
+    // 0-9, then a-z/A-Z for 10-35
+    c.to_digit(36).map(|n| n as u8)
+}
+
+fn digit_p_radix(c: char, r: u8) -> Result<u8, NumberParseError> {
+    char_to_num(c)
+        .filter(|n| *n < r)
+        .ok_or(NumberParseError::InvalidDigit(c, r))
+}
+
+fn num_p_radix(s: &str, r: u8) -> Result<f64, NumberParseError> {
     let mut v = 0.;
-    for c in iter::once(f).chain(s.chars()) {
-        let cx = char_to_num(c);
+    for c in s.chars().filter(|c| *c != '_') {
+        let cx = digit_p_radix(c, r)?;
         v *= r as f64;
         v += cx as f64;
     }
-    v
+    Ok(v)
+}
+
+/// Parses the digits after the point, i.e. sums up each digit times radix^-offset.
+fn frac_p_radix(s: &str, r: u8) -> Result<f64, NumberParseError> {
+    let mut v = 0.;
+    let mut offset = -1;
+    for c in s.chars().filter(|c| *c != '_') {
+        v += digit_p_radix(c, r)? as f64 * (r as f64).powi(offset);
+        offset -= 1;
+    }
+    Ok(v)
 }
 
-fn parse_number_binary(input: &str) -> IResult<&str, f64> {
-    let (input, _) = tag("0b")(input)?;
-    map(take_while1(|c| c == '0' || c == '1'), |s| {
-        num_p_radix('0', s, 2)
-    })(input)
+/// Parses a run of digits, allowing single underscores between digits as separators.
+fn digits<'a>(is_digit: impl Fn(char) -> bool) -> impl Fn(&'a str) -> NumResult<'a, &'a str> {
+    move |input: &'a str| {
+        let (rest, s) = take_while1(|c| c == '_' || is_digit(c))(input)?;
+        if s.starts_with('_') || s.ends_with('_') || s.contains("__") {
+            return Err(nom::Err::Error(NumberParseError::Malformed));
+        }
+        Ok((rest, s))
+    }
 }
 
-fn parse_number_octal(input: &str) -> IResult<&str, f64> {
-    let (input, _) = tag("0o")(input)?;
-    map(
-        take_while1(|c| match c {
-            '0'..='7' => true,
-            _ => false,
-        }),
-        |s| num_p_radix('0', s, 8),
-    )(input)
+/// Parses digits in the given radix, with an optional fractional part in the same radix.
+fn radix_number<'a>(r: u8, is_digit: fn(char) -> bool) -> impl Fn(&'a str) -> NumResult<'a, f64> {
+    move |input: &'a str| {
+        let (input, int) = digits(is_digit)(input)?;
+        let (input, frac) = opt(preceded(tag("."), digits(is_digit)))(input)?;
+
+        let mut value = num_p_radix(int, r).map_err(nom::Err::Failure)?;
+        if let Some(frac) = frac {
+            value += frac_p_radix(frac, r).map_err(nom::Err::Failure)?;
+        }
+        Ok((input, value))
+    }
 }
 
-fn parse_number_hex(input: &str) -> IResult<&str, f64> {
-    let (input, _) = tag("0x")(input)?;
-    map(
-        take_while1(|c| match c {
-            '0'..='9' | 'a'..='f' | 'A'..='F' => true,
-            _ => false,
-        }),
-        |s| num_p_radix('0', s, 16),
-    )(input)
+fn parse_number_binary(input: &str) -> NumResult<'_, f64> {
+    let (input, _) = tag_no_case("0b")(input)?;
+    radix_number(2, |c| c == '0' || c == '1')(input)
 }
 
-fn parse_number_dec(input: &str) -> IResult<&str, f64> {
-    let (input, fint) = one_of("0123456789")(input)?;
-    let (input, int) = take_while(|c| match c {
-        '0'..='9' => true,
-        _ => false,
-    })(input)?;
+fn parse_number_octal(input: &str) -> NumResult<'_, f64> {
+    let (input, _) = tag_no_case("0o")(input)?;
+    radix_number(8, |c| matches!(c, '0'..='7'))(input)
+}
+
+/// Parses a hexadecimal literal, with an optional binary exponent (`0x1.8p3` is 1.5 * 2^3).
+fn parse_number_hex(input: &str) -> NumResult<'_, f64> {
+    let (input, _) = tag_no_case("0x")(input)?;
+    let (input, mantissa) = radix_number(16, |c| c.is_ascii_hexdigit())(input)?;
+    let (input, exp) = opt(tuple((
+        one_of("pP"),
+        opt(one_of("+-")),
+        digits(|c: char| c.is_ascii_digit()),
+    )))(input)?;
+    let (sign, exp) = match exp {
+        Some((_, sign, exp)) => (sign, exp),
+        None => return Ok((input, mantissa)),
+    };
+    if mantissa == 0. {
+        return Ok((input, 0.));
+    }
+
+    let out_of_range = || nom::Err::Failure(NumberParseError::ExponentOutOfRange);
+    let exp: i32 = exp.replace('_', "").parse().map_err(|_| out_of_range())?;
+    let mut exp = if sign == Some('-') { -exp } else { exp };
+    // scale in steps, since 2^exp alone may not be representable even if the result is
+    let mut value = mantissa;
+    while exp != 0 && value != 0. && value.is_finite() {
+        let step = exp.clamp(-1000, 1000);
+        value *= 2f64.powi(step);
+        exp -= step;
+    }
+    if value == 0. || value.is_infinite() {
+        return Err(out_of_range());
+    }
+    Ok((input, value))
+}
+
+fn parse_number_radix(input: &str) -> NumResult<'_, f64> {
+    let (input, radix) = digit1(input)?;
+    let (input, _) = tag("r")(input)?;
+    let r = match radix.parse() {
+        Ok(r) if (2..=36).contains(&r) => r,
+        _ => {
+            return Err(nom::Err::Failure(NumberParseError::InvalidRadix(
+                radix.to_string(),
+            )))
+        }
+    };
+    // accept any alphanumeric digits here so that out-of-range digits are reported as such
+    radix_number(r, |c| c.is_ascii_alphanumeric())(input)
+}
+
+fn parse_number_dec(input: &str) -> NumResult<'_, f64> {
+    let start = input;
+    let (input, int) = opt(digits(|c: char| c.is_ascii_digit()))(input)?;
     let (input, frac) = opt(|input| {
         let (input, _) = tag(".")(input)?;
-        take_while1(|c| match c {
-            '0'..='9' => true,
-            _ => false,
-        })(input)
-    })(input)?;
-    let (input, exp) = opt(|input| {
-        let (input, _) = one_of("eE")(input)?;
-        let (input, sign) = opt(one_of("+-"))(input)?;
-        let (input, body) = take_while1(|c| match c {
-            '0'..='9' => true,
-            _ => false,
-        })(input)?;
-        Ok((input, (sign, body)))
+        opt(digits(|c: char| c.is_ascii_digit()))(input)
     })(input)?;
-
-    let mut value = num_p_radix(fint, int, 10);
-    if let Some(frac) = frac {
-        let mut offset = -1;
-        for c in frac.chars() {
-            value += char_to_num(c) as f64 * 10_f64.powf(offset as f64);
-            offset -= 1;
-        }
+    if int.is_none() && frac.flatten().is_none() {
+        // needs digits on at least one side of the decimal point
+        return Err(nom::Err::Error(NumberParseError::Malformed));
     }
+    let (input, _) = opt(tuple((
+        one_of("eE"),
+        opt(one_of("+-")),
+        digits(|c: char| c.is_ascii_digit()),
+    )))(input)?;
 
-    if let Some((sign, exp)) = exp {
-        let sign = match sign {
-            Some('+') | None => 1.,
-            _ => -1.,
-        };
-        let exp = sign * num_p_radix('0', exp, 10);
-        value *= 10_f64.powf(exp);
+    // the shape has been validated, so leave the correctly rounded conversion to std
+    let lexeme: String = start[..start.len() - input.len()]
+        .chars()
+        .filter(|c| *c != '_')
+        .collect();
+    let value: f64 = lexeme
+        .parse()
+        .map_err(|_| nom::Err::Failure(NumberParseError::Malformed))?;
+    let mantissa = lexeme.split(['e', 'E']).next().unwrap_or("");
+    let is_zero = !mantissa.chars().any(|c| ('1'..='9').contains(&c));
+    if value.is_infinite() || (value == 0. && !is_zero) {
+        return Err(nom::Err::Failure(NumberParseError::ExponentOutOfRange));
     }
 
     Ok((input, value))
 }
 
-fn parse_number_body(input: &str) -> IResult<&str, f64> {
+fn parse_number_non_finite(input: &str) -> NumResult<'_, f64> {
     alt((
+        value(f64::INFINITY, tag("Infinity")),
+        value(f64::NAN, tag("NaN")),
+    ))(input)
+}
+
+fn parse_number_body(input: &str) -> NumResult<'_, f64> {
+    alt((
+        parse_number_non_finite,
         parse_number_binary,
         parse_number_octal,
         parse_number_hex,
+        parse_number_radix,
         parse_number_dec,
     ))(input)
 }
 
-fn parse_number_i(input: &str) -> IResult<&str, f64> {
+fn parse_number_i(input: &str) -> NumResult<'_, f64> {
     let (input, sign) = opt(one_of("+-"))(input)?;
     let (input, body) = parse_number_body(input)?;
 
@@ -249,29 +881,1007 @@ fn parse_number_i(input: &str) -> IResult<&str, f64> {
     Ok((input, body * sign))
 }
 
-pub(crate) fn parse_number(s: String) -> f64 {
-    parse_number_i(&s).expect("failed to parse number").1
+pub(crate) fn parse_number(s: String) -> Result<f64, NumberParseError> {
+    match all_consuming(parse_number_i)(&s) {
+        Ok((_, value)) => Ok(value),
+        Err(nom::Err::Error(err)) | Err(nom::Err::Failure(err)) => Err(err),
+        Err(nom::Err::Incomplete(_)) => Err(NumberParseError::Malformed),
+    }
+}
+
+/// Returns true if the value a number literal was parsed to isn't exactly the integer it
+/// denotes, e.g. `9007199254740993` (2^53 + 1), which is rounded. Literals with a fractional part
+/// or an exponent are never exact in general, so they're not checked.
+pub(crate) fn loses_precision(literal: &str, value: f64) -> bool {
+    let (radix, digits) = match integer_digits(literal) {
+        Some(integer) => integer,
+        None => return false,
+    };
+    // the exact integer in base 10^9, least significant limb first
+    const BASE: u64 = 1_000_000_000;
+    let mut limbs = vec![0];
+    for c in digits.chars() {
+        let mut carry = match c.to_digit(radix) {
+            Some(digit) => u64::from(digit),
+            None => return false,
+        };
+        for limb in &mut limbs {
+            let next = *limb * u64::from(radix) + carry;
+            *limb = next % BASE;
+            carry = next / BASE;
+        }
+        if carry > 0 {
+            limbs.push(carry);
+        }
+    }
+    let mut exact = String::new();
+    for (i, limb) in limbs.iter().rev().enumerate() {
+        if i == 0 {
+            exact.push_str(&limb.to_string());
+        } else {
+            exact.push_str(&format!("{:09}", limb));
+        }
+    }
+    // formatting with a precision prints the exact value of the float
+    !value.is_finite() || format!("{:.0}", value.abs()) != exact
+}
+
+/// Returns the radix and digits (without the sign, prefix and underscores) of an integer literal,
+/// or `None` if it has a fractional part or an exponent.
+fn integer_digits(literal: &str) -> Option<(u32, String)> {
+    let literal = literal.trim_start_matches(['+', '-']).to_ascii_lowercase();
+    let (radix, digits) = if let Some(digits) = literal.strip_prefix("0x") {
+        if digits.contains('p') {
+            return None;
+        }
+        (16, digits)
+    } else if let Some(digits) = literal.strip_prefix("0b") {
+        (2, digits)
+    } else if let Some(digits) = literal.strip_prefix("0o") {
+        (8, digits)
+    } else if let Some((radix, digits)) = literal.split_once('r') {
+        (radix.parse().ok()?, digits)
+    } else if literal.contains('e') {
+        return None;
+    } else {
+        (10, &*literal)
+    };
+    if digits.contains('.') || !(2..=36).contains(&radix) {
+        return None;
+    }
+    Some((radix, digits.replace('_', "")))
+}
+
+/// An error in a string literal.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StringParseError {
+    /// The literal is not enclosed in double quotes.
+    MissingQuotes,
+    /// A `\u{...}` escape is malformed or does not encode a valid char.
+    InvalidUnicodeEscape(String),
+    /// A `\xNN` escape does not have two hex digits.
+    InvalidHexEscape(String),
+    /// A backslash followed by a character that isn't a known escape, when parsing strictly (the
+    /// character, span of the escape in the source).
+    UnknownEscape(char, Range<usize>),
+}
+
+impl fmt::Display for StringParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StringParseError::MissingQuotes => {
+                write!(f, "string literal is not enclosed in double quotes")
+            }
+            StringParseError::InvalidUnicodeEscape(e) => {
+                write!(f, "invalid unicode escape {} in string literal", e)
+            }
+            StringParseError::InvalidHexEscape(e) => {
+                write!(f, "invalid hex escape {} in string literal", e)
+            }
+            StringParseError::UnknownEscape(c, _) => {
+                write!(f, "unknown escape sequence \\{} in string literal", c)
+            }
+        }
+    }
+}
+
+/// Parses the rest of a `\u{...}` escape (i.e. after the `u`) into a char.
+fn parse_unicode_escape(chars: &mut impl Iterator<Item = char>) -> Result<char, StringParseError> {
+    let invalid = StringParseError::InvalidUnicodeEscape;
+    match chars.next() {
+        Some('{') => (),
+        Some(c) => return Err(invalid(format!("\\u{}", c))),
+        None => return Err(invalid("\\u".into())),
+    }
+
+    let mut digits = String::new();
+    loop {
+        match chars.next() {
+            Some('}') => break,
+            Some(c) if c.is_ascii_hexdigit() && digits.len() < 6 => digits.push(c),
+            Some(c) => return Err(invalid(format!("\\u{{{}{}", digits, c))),
+            None => return Err(invalid(format!("\\u{{{}", digits))),
+        }
+    }
+
+    // this also rejects surrogates and anything above U+10FFFF
+    u32::from_str_radix(&digits, 16)
+        .ok()
+        .and_then(std::char::from_u32)
+        .ok_or_else(|| invalid(format!("\\u{{{}}}", digits)))
+}
+
+/// Parses the rest of a `\xNN` escape (i.e. after the `x`) into a char.
+fn parse_hex_escape(chars: &mut impl Iterator<Item = char>) -> Result<char, StringParseError> {
+    let mut digits = String::with_capacity(2);
+    for _ in 0..2 {
+        match chars.next() {
+            Some(c) if c.is_ascii_hexdigit() => digits.push(c),
+            Some(c) => {
+                return Err(StringParseError::InvalidHexEscape(format!(
+                    "\\x{}{}",
+                    digits, c
+                )))
+            }
+            None => return Err(StringParseError::InvalidHexEscape(format!("\\x{}", digits))),
+        }
+    }
+    // two hex digits always fit in U+0000 to U+00FF
+    Ok(u8::from_str_radix(&digits, 16).map_or('\0', char::from))
+}
+
+/// Returns the char that a single-character escape (e.g. the `n` of `\n`) stands for.
+fn simple_escape(c: char) -> Option<char> {
+    Some(match c {
+        '"' => '"',
+        '\\' => '\\',
+        '\'' => '\'',
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        '0' => '\0',
+        'b' => '\u{8}',
+        'f' => '\u{c}',
+        'v' => '\u{b}',
+        _ => return None,
+    })
 }
 
-pub(crate) fn parse_string(s: String) -> String {
-    let mut out = String::with_capacity(s.len() - 2);
+/// Parses a string literal that starts at the byte offset `start` in the source. A backslash
+/// followed by a character that isn't a known escape is an error if `strict` is set, and is left
+/// out otherwise (e.g. `"\q"` is `q`).
+pub(crate) fn parse_string(
+    s: String,
+    start: usize,
+    strict: bool,
+) -> Result<String, StringParseError> {
+    let inner = s
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .ok_or(StringParseError::MissingQuotes)?;
+
+    let mut out = String::with_capacity(inner.len());
     let mut escape_next = false;
-    for c in s.chars().skip(1) {
-        // skip " at the beginning
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
         if !escape_next && c == '\\' {
             escape_next = true;
         } else if escape_next {
+            escape_next = false;
             out.push(match c {
-                '"' => '"',
-                'n' => '\n',
-                't' => '\t',
-                'r' => '\r',
-                _ => c,
+                // a line continuation: the line break is left out
+                '\n' => continue,
+                '\r' if chars.as_str().starts_with('\n') => {
+                    chars.next();
+                    continue;
+                }
+                'u' => parse_unicode_escape(&mut chars)?,
+                'x' => parse_hex_escape(&mut chars)?,
+                c => match simple_escape(c) {
+                    Some(c) => c,
+                    None if strict => {
+                        // after the opening quote and what has been read so far
+                        let end = start + 1 + inner.len() - chars.as_str().len();
+                        let span = end - c.len_utf8() - 1..end;
+                        return Err(StringParseError::UnknownEscape(c, span));
+                    }
+                    None => c,
+                },
             });
         } else {
             out.push(c);
         }
     }
-    out.pop(); // remove " at the end
-    out
+    if escape_next {
+        // the closing quote was escaped
+        return Err(StringParseError::MissingQuotes);
+    }
+    Ok(out)
+}
+
+/// Returns the escapes in a string literal (as written, with quotes) that aren't known escapes,
+/// with their byte offsets in the literal.
+pub(crate) fn unknown_escapes(literal: &str) -> Vec<(char, Range<usize>)> {
+    let mut unknown = Vec::new();
+    let mut chars = literal.char_indices();
+    while let Some((start, c)) = chars.next() {
+        if c != '\\' {
+            continue;
+        }
+        match chars.next() {
+            Some((_, c)) if simple_escape(c).is_some() || "ux\n\r".contains(c) => (),
+            Some((i, c)) => unknown.push((c, start..i + c.len_utf8())),
+            None => (),
+        }
+    }
+    unknown
+}
+
+/// An error in a date or date-time literal.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DateParseError {
+    /// The literal does not have the shape of a date or date-time.
+    Malformed(String),
+    /// The date does not exist, e.g. `@2023-02-29`.
+    InvalidDate(String),
+    /// The time of day or time zone offset is out of range, e.g. `@2024-01-15T24:00Z`.
+    InvalidTime(String),
+}
+
+impl fmt::Display for DateParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DateParseError::Malformed(s) => write!(f, "malformed date literal {}", s),
+            DateParseError::InvalidDate(s) => write!(f, "date {} does not exist", s),
+            DateParseError::InvalidTime(s) => write!(f, "invalid time in date literal {}", s),
+        }
+    }
+}
+
+/// Parses a fixed number of ASCII digits.
+fn fixed_digits(s: &str, len: usize) -> Option<u32> {
+    match s.get(..len) {
+        Some(digits) if digits.bytes().all(|b| b.is_ascii_digit()) => digits.parse().ok(),
+        _ => None,
+    }
+}
+
+// `u32::is_multiple_of` needs Rust 1.87
+#[allow(clippy::manual_is_multiple_of)]
+fn days_in_month(year: u32, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Parses a `YYYY-MM-DD` date at the start of the string, returning the rest.
+fn parse_date_i<'a>(s: &'a str, lit: &str) -> Result<&'a str, DateParseError> {
+    let malformed = || DateParseError::Malformed(lit.to_string());
+    let (year, month, day) = match (
+        fixed_digits(s, 4),
+        s.get(4..5),
+        s.get(5..).and_then(|s| fixed_digits(s, 2)),
+        s.get(7..8),
+        s.get(8..).and_then(|s| fixed_digits(s, 2)),
+    ) {
+        (Some(y), Some("-"), Some(m), Some("-"), Some(d)) => (y, m, d),
+        _ => return Err(malformed()),
+    };
+    if !(1..=12).contains(&month) || day == 0 || day > days_in_month(year, month) {
+        return Err(DateParseError::InvalidDate(lit.to_string()));
+    }
+    Ok(&s[10..])
+}
+
+/// Parses a date literal `@YYYY-MM-DD` into the date without the `@`.
+pub(crate) fn parse_date(s: String) -> Result<String, DateParseError> {
+    let date = s
+        .strip_prefix('@')
+        .ok_or_else(|| DateParseError::Malformed(s.clone()))?;
+    match parse_date_i(date, &s)? {
+        "" => Ok(date.to_string()),
+        _ => Err(DateParseError::Malformed(s.clone())),
+    }
+}
+
+/// Parses a date-time literal `@YYYY-MM-DDTHH:MM[:SS](Z|+HH:MM|-HH:MM)` into the ISO 8601 date-time
+/// without the `@`.
+pub(crate) fn parse_date_time(s: String) -> Result<String, DateParseError> {
+    let malformed = || DateParseError::Malformed(s.clone());
+    let invalid_time = || DateParseError::InvalidTime(s.clone());
+    let date_time = s.strip_prefix('@').ok_or_else(malformed)?;
+    let rest = parse_date_i(date_time, &s)?
+        .strip_prefix('T')
+        .ok_or_else(malformed)?;
+
+    // hours and minutes, then optional seconds
+    let (hour, minute) = match (fixed_digits(rest, 2), rest.get(2..3), rest.get(3..)) {
+        (Some(h), Some(":"), Some(rest)) => (h, fixed_digits(rest, 2).ok_or_else(malformed)?),
+        _ => return Err(malformed()),
+    };
+    let mut rest = &rest[5..];
+    if let Some(seconds) = rest.strip_prefix(':') {
+        if fixed_digits(seconds, 2).ok_or_else(malformed)? > 59 {
+            return Err(invalid_time());
+        }
+        rest = &seconds[2..];
+    }
+    if hour > 23 || minute > 59 {
+        return Err(invalid_time());
+    }
+
+    match rest {
+        "Z" => (),
+        _ if rest.starts_with(['+', '-']) && rest.len() == 6 => {
+            let offset = (
+                fixed_digits(&rest[1..], 2),
+                rest.get(3..4),
+                rest.get(4..).and_then(|m| fixed_digits(m, 2)),
+            );
+            match offset {
+                (Some(h), Some(":"), Some(m)) if h <= 23 && m <= 59 => (),
+                (Some(_), Some(":"), Some(_)) => return Err(invalid_time()),
+                _ => return Err(malformed()),
+            }
+        }
+        _ => return Err(malformed()),
+    }
+    Ok(date_time.to_string())
+}
+
+/// Parses a raw string literal `r"..."`, which has no escapes at all.
+pub(crate) fn parse_raw_string(s: String) -> Result<String, StringParseError> {
+    s.strip_prefix("r\"")
+        .and_then(|s| s.strip_suffix('"'))
+        .map(|s| s.to_string())
+        .ok_or(StringParseError::MissingQuotes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(s: &str) -> Option<f64> {
+        parse_number(s.to_string()).ok()
+    }
+
+    #[test]
+    fn digit_separators() {
+        assert_eq!(num("1_000_000"), Some(1_000_000.));
+        assert_eq!(num("0xFF_FF"), Some(65535.));
+        assert_eq!(num("0b1010_1010"), Some(170.));
+        assert_eq!(num("0o7_7"), Some(63.));
+        assert_eq!(num("1_0.2_5e1_0"), Some(10.25e10));
+        assert_eq!(num("0x_FF"), None);
+        assert_eq!(num("1__0"), None);
+        assert_eq!(num("1_.0"), None);
+        assert_eq!(num("1._0"), None);
+        assert_eq!(num("10_"), None);
+    }
+
+    #[test]
+    fn leading_dot_decimals() {
+        assert_eq!(num(".25"), Some(0.25));
+        assert_eq!(num("-.5"), Some(-0.5));
+        assert_eq!(num("+.5"), Some(0.5));
+        assert_eq!(num(".5e1"), Some(5.));
+        assert_eq!(num("."), None);
+        assert_eq!(num("-."), None);
+    }
+
+    #[test]
+    fn trailing_dot_decimals() {
+        assert_eq!(num("5."), Some(5.));
+        assert_eq!(num("5.e2"), Some(500.));
+        assert_eq!(num("5.e3"), Some(5000.));
+        assert_eq!(num("."), None);
+        assert_eq!(num(".e2"), None);
+    }
+
+    #[test]
+    fn radix_fractions() {
+        assert_eq!(num("0xA.8"), Some(10.5));
+        assert_eq!(num("0b1.1"), Some(1.5));
+        assert_eq!(num("0o7.4"), Some(7.5));
+        assert_eq!(num("-0x0.0_1"), Some(-1. / 256.));
+        assert_eq!(num("0x1."), None);
+        assert_eq!(num("0b1.2"), None);
+        assert_eq!(num("0o1.8"), None);
+        assert_eq!(num("0xA.G"), None);
+    }
+
+    #[test]
+    fn non_finite_numbers() {
+        assert_eq!(num("Infinity"), Some(f64::INFINITY));
+        assert_eq!(num("+Infinity"), Some(f64::INFINITY));
+        assert_eq!(num("-Infinity"), Some(f64::NEG_INFINITY));
+        assert!(num("NaN").unwrap().is_nan());
+        assert_eq!(num("infinity"), None);
+    }
+
+    #[test]
+    fn decimal_precision() {
+        for s in &[
+            "0.1",
+            "0.3",
+            "1.7976931348623157e308",
+            "2.2250738585072014e-308",
+            "4.9e-324",
+            "9007199254740993",
+            "12345678901234567890",
+            "0.30000000000000004",
+            "3.141592653589793238462643383279",
+            "1.0000000000000002",
+            "123.456e-7",
+        ] {
+            assert_eq!(num(s), Some(s.parse::<f64>().unwrap()), "{}", s);
+        }
+        assert_eq!(num("1_234.567_8"), Some(1234.5678));
+    }
+
+    #[test]
+    fn uppercase_radix_prefixes() {
+        assert_eq!(num("0XA"), num("0xa"));
+        assert_eq!(num("0XA"), Some(10.));
+        assert_eq!(num("0O17"), num("0o17"));
+        assert_eq!(num("0O17"), Some(15.));
+        assert_eq!(num("0B11"), num("0b11"));
+        assert_eq!(num("0B11"), Some(3.));
+    }
+
+    #[test]
+    fn hex_exponents() {
+        assert_eq!(num("0x1.8p3"), Some(12.));
+        assert_eq!(num("0x1.8P+3"), Some(12.));
+        assert_eq!(num("0x1p-2"), Some(0.25));
+        assert_eq!(num("-0xAp1_0"), Some(-10240.));
+        assert_eq!(num("0x1p-1074"), Some(f64::from_bits(1)));
+        assert_eq!(num("0x1p1023"), Some(2f64.powi(1023)));
+        assert_eq!(num("0x0p99999999999"), Some(0.));
+        for s in &["0x1p1024", "0x1p-1075", "0x1p99999999999"] {
+            assert_eq!(
+                parse_number(s.to_string()),
+                Err(NumberParseError::ExponentOutOfRange),
+                "{}",
+                s
+            );
+        }
+        assert_eq!(num("0x1p"), None);
+        // the exponent is decimal, and only hex literals have one
+        assert_eq!(num("0x1pA"), None);
+        assert_eq!(num("1p3"), None);
+        assert_eq!(num("0b1p3"), None);
+
+        let prog = crate::parse("a = 0x1.8p3 + 0x1P-1").unwrap();
+        assert_eq!(prog.0[0].body.to_string(), "(apply + 12 0.5)");
+    }
+
+    #[test]
+    fn arbitrary_radix() {
+        assert_eq!(num("16rFF"), Some(255.));
+        assert_eq!(num("2r1010"), Some(10.));
+        assert_eq!(num("36rZz"), Some(35. * 36. + 35.));
+        assert_eq!(num("8r7.4"), Some(7.5));
+        assert_eq!(num("-3r1_2"), Some(-5.));
+        assert_eq!(
+            parse_number("2r1012".into()),
+            Err(NumberParseError::InvalidDigit('2', 2))
+        );
+        assert_eq!(
+            parse_number("16rFG".into()),
+            Err(NumberParseError::InvalidDigit('G', 16))
+        );
+        assert_eq!(
+            parse_number("1r0".into()),
+            Err(NumberParseError::InvalidRadix("1".into()))
+        );
+        assert_eq!(
+            parse_number("37r0".into()),
+            Err(NumberParseError::InvalidRadix("37".into()))
+        );
+        assert_eq!(num("16r"), None);
+    }
+
+    #[test]
+    fn number_errors() {
+        assert_eq!(parse_number("".into()), Err(NumberParseError::Malformed));
+        assert_eq!(parse_number("0x".into()), Err(NumberParseError::Malformed));
+        assert_eq!(parse_number("1e".into()), Err(NumberParseError::Malformed));
+        assert_eq!(
+            num_p_radix("1z", 10),
+            Err(NumberParseError::InvalidDigit('z', 10))
+        );
+        assert_eq!(
+            num_p_radix("19", 8),
+            Err(NumberParseError::InvalidDigit('9', 8))
+        );
+        assert_eq!(
+            frac_p_radix("a", 10),
+            Err(NumberParseError::InvalidDigit('a', 10))
+        );
+        assert_eq!(num_p_radix("1_f", 16), Ok(31.));
+
+        for s in &[
+            "1e400",
+            "1e-400",
+            "-1e400",
+            "0.001e-322",
+            &"9".repeat(400),
+            "1e99999999",
+        ] {
+            assert_eq!(
+                parse_number(s.to_string()),
+                Err(NumberParseError::ExponentOutOfRange),
+                "{}",
+                s
+            );
+        }
+        assert_eq!(num("0e400"), Some(0.));
+        assert_eq!(num("0.000e-99999"), Some(0.));
+        assert_eq!(num("1e-323"), Some(1e-323));
+        assert_eq!(num("1.7976931348623157e308"), Some(f64::MAX));
+    }
+
+    #[test]
+    fn unicode_escapes() {
+        let string = |s: &str| parse_string(s.to_string(), 0, false);
+        assert_eq!(string(r#""\u{1F600}""#), Ok("\u{1F600}".into()));
+        assert_eq!(string(r#""a\u{41}b\u{00e9}""#), Ok("aAbé".into()));
+        assert_eq!(string(r#""\u{10FFFF}""#), Ok("\u{10FFFF}".into()));
+        assert_eq!(string(r#""\tn""#), Ok("\tn".into()));
+        for (s, escape) in &[
+            (r#""\u{}""#, r"\u{}"),
+            (r#""\u{110000}""#, r"\u{110000}"),
+            (r#""\u{D800}""#, r"\u{D800}"),
+            (r#""\u{1234567}""#, r"\u{1234567"),
+            (r#""\u{12x}""#, r"\u{12x"),
+            (r#""\u41""#, r"\u4"),
+        ] {
+            assert_eq!(
+                string(s),
+                Err(StringParseError::InvalidUnicodeEscape(escape.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn hex_escapes() {
+        let string = |s: &str| parse_string(s.to_string(), 0, false);
+        assert_eq!(string(r#""\x41\x62""#), Ok("Ab".into()));
+        assert_eq!(string(r#""\xff\x00""#), Ok("\u{ff}\u{0}".into()));
+        assert_eq!(string(r#""\x412""#), Ok("A2".into()));
+        assert_eq!(
+            string(r#""\x4""#),
+            Err(StringParseError::InvalidHexEscape(r"\x4".into()))
+        );
+        assert_eq!(
+            string(r#""\xg1""#),
+            Err(StringParseError::InvalidHexEscape(r"\xg".into()))
+        );
+    }
+
+    #[test]
+    fn malformed_strings() {
+        let string = |s: &str| parse_string(s.to_string(), 0, false);
+        assert_eq!(string(r#""""#), Ok("".into()));
+        for s in &["", "\"", "abc", "\"abc", "abc\"", r#""abc\""#] {
+            assert_eq!(string(s), Err(StringParseError::MissingQuotes), "{}", s);
+        }
+    }
+
+    #[test]
+    fn control_escapes() {
+        let chars = |s: &str| {
+            parse_string(s.to_string(), 0, false)
+                .unwrap()
+                .chars()
+                .map(|c| c as u32)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(chars(r#""\0\b\f\v""#), vec![0x0, 0x8, 0xC, 0xB]);
+        assert_eq!(chars(r#""\n\t\r""#), vec![0xA, 0x9, 0xD]);
+    }
+
+    #[test]
+    fn unknown_escape_sequences() {
+        let string = |s: &str| parse_string(s.to_string(), 10, true);
+        assert_eq!(string(r#""\\\"\'""#), Ok(r#"\"'"#.into()));
+        assert_eq!(
+            string(r#""\q""#),
+            Err(StringParseError::UnknownEscape('q', 11..13))
+        );
+        assert_eq!(
+            string(r#""aé\ ""#),
+            Err(StringParseError::UnknownEscape(' ', 14..16))
+        );
+        assert_eq!(
+            string(r#""\\\é""#),
+            Err(StringParseError::UnknownEscape('é', 13..16))
+        );
+        // unless parsing strictly, the backslash is left out
+        assert_eq!(parse_string(r#""\q""#.into(), 0, false), Ok("q".into()));
+        let err = crate::parse_strict("a = 1;\nb = \"\\q\"").unwrap_err();
+        assert!(matches!(
+            err,
+            crate::ParseError::User {
+                error: SyntaxError::String(StringParseError::UnknownEscape('q', ref span))
+            } if *span == (12..14)
+        ));
+        assert!(crate::parse("a = 1;\nb = \"\\q\"").is_ok());
+
+        assert_eq!(unknown_escapes(r#""\\\"\'\n\u{41}\x41""#), vec![]);
+        assert_eq!(unknown_escapes("\"a\\\nb\""), vec![]);
+        assert_eq!(
+            unknown_escapes(r#""\q \\\é""#),
+            vec![('q', 1..3), ('é', 6..9)]
+        );
+    }
+
+    #[test]
+    fn raw_and_multiline_strings() {
+        assert_eq!(
+            parse_raw_string(r#"r"C:\path\no\escapes""#.into()),
+            Ok(r"C:\path\no\escapes".into())
+        );
+        assert_eq!(parse_raw_string("r\"a\nb\"".into()), Ok("a\nb".into()));
+        assert_eq!(
+            parse_raw_string(r#""a""#.into()),
+            Err(StringParseError::MissingQuotes)
+        );
+        assert_eq!(parse_string("\"a\nb\"".into(), 0, false), Ok("a\nb".into()));
+    }
+
+    #[test]
+    fn line_continuations() {
+        // an escaped line break is left out, while `\n` is one
+        assert_eq!(parse_string("\"a\\\nb\"".into(), 0, false), Ok("ab".into()));
+        assert_eq!(
+            parse_string("\"a\\\r\nb\"".into(), 0, false),
+            Ok("ab".into())
+        );
+        assert_eq!(
+            parse_string(r#""a\nb""#.into(), 0, false),
+            Ok("a\nb".into())
+        );
+        assert_eq!(
+            parse_string(r#""a\\nb""#.into(), 0, false),
+            Ok("a\\nb".into())
+        );
+        assert_eq!(
+            parse_string("\"a\\n\\\n  b\"".into(), 0, false),
+            Ok("a\n  b".into())
+        );
+
+        let prog = crate::parse("a = \"foo\\\nbar\"; b = \"foo\\\\\nbar\"").unwrap();
+        assert!(matches!(&prog.0[0].body.kind, ExprKind::String(s) if s == "foobar"));
+        // an escaped backslash doesn't escape the line break
+        assert!(matches!(&prog.0[1].body.kind, ExprKind::String(s) if s == "foo\\\nbar"));
+    }
+
+    #[test]
+    fn long_chain() {
+        use crate::grammar::ProgramParser;
+        let n = 5000;
+        let src = format!("x = a{}", " + a".repeat(n));
+        let mut prog = ProgramParser::new()
+            .parse(&OperatorTable::default(), &src)
+            .unwrap();
+
+        // should be left-associative all the way down
+        let mut depth = 0;
+        let mut expr = prog.0.remove(0).body;
+        while let ExprKind::Apply(a, _, b) = expr.kind {
+            assert!(matches!(b.kind, ExprKind::Ident(_)));
+            expr = *a;
+            depth += 1;
+        }
+        assert_eq!(depth, n);
+
+        // and precedence should still work
+        let mut prog = ProgramParser::new()
+            .parse(&OperatorTable::default(), "x = a + b * c - d")
+            .unwrap();
+        match prog.0.remove(0).body.kind {
+            ExprKind::Apply(a, Op::Infix(Ident { name: op, .. }), _) => {
+                assert_eq!(op, "-");
+                match a.kind {
+                    ExprKind::Apply(_, Op::Infix(Ident { name: op, .. }), bc) => {
+                        assert_eq!(op, "+");
+                        assert!(
+                            matches!(bc.kind, ExprKind::Apply(_, Op::Infix(Ident { name: ref op, .. }), _) if op == "*")
+                        );
+                    }
+                    _ => panic!("expected apply"),
+                }
+            }
+            _ => panic!("expected apply"),
+        }
+    }
+
+    #[test]
+    fn malformed_chains() {
+        let a = || ChainItem::Expr(Expr::new(ExprKind::Null, 0..0));
+        let plus = || Op::Infix(Ident::new("+", 0..1));
+        let table = OperatorTable::default();
+        assert_eq!(
+            fix_expr_prec(vec![ChainItem::Op(plus()), a()], &table).unwrap_err(),
+            PrecError::MissingOperand(plus())
+        );
+        assert_eq!(
+            fix_expr_prec(vec![ChainItem::Prefix(plus())], &table).unwrap_err(),
+            PrecError::MissingOperand(plus())
+        );
+        assert_eq!(
+            fix_expr_prec(vec![a(), a(), a()], &table).unwrap_err(),
+            PrecError::NotReduced(3)
+        );
+        assert_eq!(fix_expr_prec(vec![], &table).unwrap_err(), PrecError::Empty);
+        assert!(fix_expr_prec(vec![a(), ChainItem::Op(plus()), a()], &table).is_ok());
+    }
+
+    #[test]
+    fn custom_operator_table() {
+        use crate::grammar::ProgramParser;
+        let mut table = OperatorTable::new(7, Associativity::Right);
+        table.insert("+", 5, Associativity::Left);
+        table.insert("<>", 3, Associativity::Left);
+
+        // a + b <> c |> d |> e => (a + (b <> c)) |> (d |> e)
+        let prog = ProgramParser::new()
+            .parse(&table, "x = a + b <> c |> d |> e")
+            .unwrap();
+        let body = &prog.0[0].body;
+        match &body.kind {
+            ExprKind::Apply(lhs, Op::Infix(Ident { name: op, .. }), rhs) => {
+                assert_eq!(op, "|>");
+                match &lhs.kind {
+                    ExprKind::Apply(_, Op::Infix(Ident { name: op, .. }), inner) => {
+                        assert_eq!(op, "+");
+                        assert!(
+                            matches!(&inner.kind, ExprKind::Apply(_, Op::Infix(Ident { name: op, .. }), _) if op == "<>")
+                        );
+                    }
+                    _ => panic!("unexpected lhs {:?}", lhs),
+                }
+                assert!(
+                    matches!(&rhs.kind, ExprKind::Apply(_, Op::Infix(Ident { name: op, .. }), _) if op == "|>")
+                );
+            }
+            _ => panic!("unexpected expression {:?}", body),
+        }
+    }
+
+    #[test]
+    fn prefix_ops() {
+        use crate::grammar::ProgramParser;
+        let body = |src: &str| {
+            let mut prog = ProgramParser::new()
+                .parse(&OperatorTable::default(), src)
+                .unwrap();
+            prog.0.remove(0).body
+        };
+        let is_ident = |e: &Expr, name: &str| matches!(&e.kind, ExprKind::Ident(Ident { name: i, .. }) if i == name);
+        let is_op =
+            |op: &Op, name: &str| matches!(op, Op::Infix(Ident { name: o, .. }) if o == name);
+
+        // (-a) + b
+        match body("x = -a + b").kind {
+            ExprKind::Apply(a, op, b) => {
+                assert!(is_op(&op, "+"));
+                assert!(is_ident(&b, "b"));
+                match a.kind {
+                    ExprKind::Unary(op, a) => assert!(is_op(&op, "-") && is_ident(&a, "a")),
+                    _ => panic!("expected unary"),
+                }
+            }
+            _ => panic!("expected apply"),
+        }
+
+        // a * (-(f b))
+        match body("x = a * -f b").kind {
+            ExprKind::Apply(_, op, b) => {
+                assert!(is_op(&op, "*"));
+                match b.kind {
+                    ExprKind::Unary(op, b) => {
+                        assert!(is_op(&op, "-"));
+                        assert!(matches!(b.kind, ExprKind::Apply(_, Op::Apply, _)));
+                    }
+                    _ => panic!("expected unary"),
+                }
+            }
+            _ => panic!("expected apply"),
+        }
+
+        // !(!a)
+        match body("x = ! !a").kind {
+            ExprKind::Unary(_, a) => assert!(matches!(a.kind, ExprKind::Unary(..))),
+            _ => panic!("expected unary"),
+        }
+
+        assert!(ProgramParser::new()
+            .parse(&OperatorTable::default(), "x = * a")
+            .is_err());
+    }
+
+    #[test]
+    fn display() {
+        use crate::grammar::ProgramParser;
+        let src = r#"a = 1 + f x; g x y = let z = [x, "s"]; w = -y in \v -> if v then (z) else null; h = a where a = 1"#;
+        let prog = ProgramParser::new()
+            .parse(&OperatorTable::default(), src)
+            .unwrap();
+        assert_eq!(
+            prog.to_string(),
+            "(decl a () (apply + 1 (apply f x)))\n\
+             (decl g (x y) (let ((decl z () (list x \"s\")) (decl w () (unary - y))) \
+             (lambda (v) (if v (group z) null))))\n\
+             (decl h () (where a ((decl a () 1))))"
+        );
+    }
+
+    #[test]
+    fn annotations() {
+        use crate::grammar::ProgramParser;
+        let src = "/// Adds one.\n///\n///  More.  \n@[inline] @[ since 1.0 ]\nf x = x + 1;\n\
+                   // not a doc comment\ng = let /// local\nh = 1 in h";
+        let prog = ProgramParser::new()
+            .parse(&OperatorTable::default(), src)
+            .unwrap();
+        let f = &prog.0[0];
+        assert_eq!(f.doc.as_deref(), Some("Adds one.\n\n More."));
+        assert_eq!(f.attrs, ["inline", "since 1.0"]);
+        assert_eq!(&src[f.span.clone()], &src[..src.find(';').unwrap()]);
+        let g = &prog.0[1];
+        assert_eq!((&g.doc, g.attrs.len()), (&None, 0));
+        match &g.body.kind {
+            ExprKind::Let(decls, _) => assert_eq!(decls[0].doc.as_deref(), Some("local")),
+            _ => panic!("expected let, got {}", g.body),
+        }
+        // doc comments must be followed by a declaration
+        assert!(ProgramParser::new()
+            .parse(&OperatorTable::default(), "a = 1 /// one")
+            .is_err());
+    }
+
+    #[test]
+    fn trailing_commas() {
+        use crate::grammar::ProgramParser;
+        let parse = |src| {
+            ProgramParser::new()
+                .parse(&OperatorTable::default(), src)
+                .map(|prog| prog.to_string())
+        };
+        let expected = "(decl a () (list 1 2 (list 3)))\n\
+                        (decl b () (lambda (x y) (list x y)))\n\
+                        (decl c () (lambda (x) x))";
+        assert_eq!(
+            parse(r"a = [1, 2, [3]]; b = \(x, y) -> [x, y]; c = \(x) -> x").unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse(r"a = [1, 2, [3,],]; b = \(x, y,) -> [x, y,]; c = \(x,) -> x").unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse(r"a = \x y -> [x, y]").unwrap(),
+            parse(r"a = \(x, y) -> [x, y]").unwrap()
+        );
+
+        for src in &[
+            "a = [,]",
+            "a = [, 1]",
+            "a = [1,,]",
+            r"a = \() -> 1",
+            r"a = \(,) -> 1",
+        ] {
+            assert!(parse(src).is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn dates() {
+        let date = |s: &str| parse_date(s.into());
+        let date_time = |s: &str| parse_date_time(s.into());
+        assert_eq!(date("@2024-01-15"), Ok("2024-01-15".into()));
+        assert_eq!(date("@2024-02-29"), Ok("2024-02-29".into()));
+        assert_eq!(date("@2000-02-29"), Ok("2000-02-29".into()));
+        for s in &[
+            "@2023-02-29",
+            "@1900-02-29",
+            "@2024-04-31",
+            "@2024-13-01",
+            "@2024-00-10",
+        ] {
+            assert!(
+                matches!(date(s), Err(DateParseError::InvalidDate(_))),
+                "{}",
+                s
+            );
+        }
+        assert!(matches!(
+            date("@2024-1-15"),
+            Err(DateParseError::Malformed(_))
+        ));
+
+        for s in &[
+            "2024-01-15T10:30:00Z",
+            "2024-01-15T23:59Z",
+            "2024-01-15T00:00:00+14:00",
+        ] {
+            assert_eq!(date_time(&format!("@{}", s)), Ok(s.to_string()));
+        }
+        for s in &[
+            "@2024-01-15T24:00Z",
+            "@2024-01-15T10:60Z",
+            "@2024-01-15T10:30:60Z",
+            "@2024-01-15T10:30+01:60",
+        ] {
+            assert!(
+                matches!(date_time(s), Err(DateParseError::InvalidTime(_))),
+                "{}",
+                s
+            );
+        }
+        assert!(matches!(
+            date_time("@2024-02-30T10:30Z"),
+            Err(DateParseError::InvalidDate(_))
+        ));
+        assert!(matches!(
+            date_time("@2024-01-15T10:30"),
+            Err(DateParseError::Malformed(_))
+        ));
+
+        use crate::grammar::ProgramParser;
+        let prog = ProgramParser::new()
+            .parse(
+                &OperatorTable::default(),
+                "a = [@2024-01-15, @2024-01-15T10:30:00Z, @external]",
+            )
+            .unwrap();
+        assert_eq!(
+            prog.to_string(),
+            "(decl a () (list @2024-01-15 @2024-01-15T10:30:00Z @external))"
+        );
+        match &prog.0[0].body.kind {
+            ExprKind::List(items) => {
+                assert!(matches!(&items[0].kind, ExprKind::Date(_)));
+                assert!(matches!(&items[1].kind, ExprKind::DateTime(_)));
+                assert!(matches!(&items[2].kind, ExprKind::Ident(_)));
+            }
+            _ => panic!("expected list, got {}", prog.0[0].body),
+        }
+        assert!(ProgramParser::new()
+            .parse(&OperatorTable::default(), "a = @2024-02-30")
+            .is_err());
+    }
+
+    #[test]
+    fn number_lexemes() {
+        use crate::grammar::ProgramParser;
+        let prog = ProgramParser::new()
+            .parse(&OperatorTable::default(), "a = [0xFF, 1_000.0, +2]")
+            .unwrap();
+        let number = |value, text: &str, start| {
+            let span = start..start + text.len();
+            let literal = Literal {
+                text: text.into(),
+                span: span.clone(),
+            };
+            Expr::new(ExprKind::Number(value, Some(literal)), span)
+        };
+        match &prog.0[0].body.kind {
+            ExprKind::List(items) => assert_eq!(
+                items,
+                &[
+                    number(255., "0xFF", 5),
+                    number(1000., "1_000.0", 11),
+                    number(2., "+2", 20),
+                ]
+            ),
+            _ => panic!("expected list, got {}", prog.0[0].body),
+        }
+    }
 }
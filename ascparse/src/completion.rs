@@ -0,0 +1,168 @@
+//! Completions for editors, from what the parser expects at a position in the source.
+
+use crate::ast::{Decl, Lambda, Program};
+use crate::format::is_plain_ident;
+use crate::ir::{is_name_char, is_name_start, stdlib_names};
+use crate::parse_error::Expected;
+use crate::visit::{walk_decl, walk_lambda, Visitor};
+use crate::{parse, ParseError};
+use std::collections::BTreeSet;
+
+/// Returns the possible completions at the byte offset `pos` in the source: the keywords and
+/// punctuation the parser expects there, followed by the names that are in scope if it expects a
+/// name. If `pos` is in the middle of a name (or at its end), only completions that start with
+/// what's been written so far are returned.
+///
+/// Only the source before `pos` decides what's expected, and nothing is if there's a syntax
+/// error before it. Names in scope are those declared at the top level and anywhere in the
+/// declaration that `pos` is in (even if their scope doesn't actually include `pos`), and those in
+/// the standard library.
+pub fn parse_completions(src: &str, pos: usize) -> Vec<String> {
+    let prefix = match src.get(..pos) {
+        Some(prefix) => prefix,
+        None => return Vec::new(),
+    };
+    // the name being written, which the lexer would read starting at the first letter
+    let run_start = prefix
+        .rfind(|c| !is_name_char(c))
+        .map_or(0, |i| i + prefix[i..].chars().next().unwrap().len_utf8());
+    let word_start = prefix[run_start..]
+        .find(is_name_start)
+        .map_or(pos, |i| run_start + i);
+    let word = &prefix[word_start..];
+
+    let expected = match expected_tokens(&prefix[..word_start]) {
+        Some(expected) => expected,
+        None => return Vec::new(),
+    };
+    let mut tokens = BTreeSet::new();
+    let mut expects_name = false;
+    for expected in expected {
+        match expected {
+            Expected::Token(token) => {
+                tokens.insert(token);
+            }
+            Expected::Name => expects_name = true,
+            _ => (),
+        }
+    }
+
+    let mut names = BTreeSet::new();
+    if expects_name {
+        names.extend(
+            stdlib_names()
+                .filter(|name| is_plain_ident(name))
+                .map(String::from),
+        );
+        // the source after `pos` probably doesn't parse either while it's being written, but it
+        // might with a name in place of the one being written
+        let placeholder = format!("{} null {}", &src[..word_start], &src[pos..]);
+        if let Ok(prog) = parse(&placeholder).or_else(|_| parse(src)) {
+            names.extend(names_in_scope(&prog, word_start));
+        }
+    }
+
+    tokens
+        .into_iter()
+        .chain(names)
+        .filter(|completion| completion.starts_with(word))
+        .collect()
+}
+
+/// Returns the tokens the parser expects at the end of the source, or `None` if there's a syntax
+/// error before that.
+fn expected_tokens(src: &str) -> Option<Vec<Expected>> {
+    match parse(src) {
+        Err(ParseError::UnrecognizedEOF { expected, .. }) => Some(expected),
+        Err(_) => None,
+        // the source is a complete program, so everything is balanced and a `)` can't come next
+        Ok(_) => match parse(&format!("{} )", src)) {
+            Err(ParseError::UnrecognizedToken { token, expected }) if token.0 >= src.len() => {
+                Some(expected)
+            }
+            _ => None,
+        },
+    }
+}
+
+/// Collects the names declared at the top level and in the declaration that `pos` is in.
+fn names_in_scope(prog: &Program, pos: usize) -> Vec<String> {
+    let mut names = Names(Vec::new());
+    for decl in &prog.0 {
+        names.0.push(decl.name.name.clone());
+        if decl.span.contains(&pos) || decl.span.end == pos {
+            names.visit_decl(decl);
+        }
+    }
+    names.0.retain(|name| is_plain_ident(name));
+    names.0
+}
+
+struct Names(Vec<String>);
+
+impl<'ast> Visitor<'ast> for Names {
+    fn visit_decl(&mut self, decl: &'ast Decl) {
+        self.0.push(decl.name.name.clone());
+        self.0
+            .extend(decl.params.iter().map(|param| param.name.clone()));
+        walk_decl(self, decl);
+    }
+
+    fn visit_lambda(&mut self, lambda: &'ast Lambda) {
+        self.0
+            .extend(lambda.params.iter().map(|param| param.name.clone()));
+        walk_lambda(self, lambda);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn completions(src: &str) -> Vec<String> {
+        let pos = src.find('|').unwrap();
+        parse_completions(&src.replace('|', ""), pos)
+    }
+
+    #[test]
+    fn completions_at_positions() {
+        // an expression is expected: keywords, punctuation and names
+        let all = completions("numbers = [1, 2]; f x = let y = 1 in |; g z = z");
+        for expected in &[
+            "let", "if", "(", "[", "numbers", "f", "g", "x", "y", "map", "null",
+        ] {
+            assert!(
+                all.contains(&expected.to_string()),
+                "{}: {:?}",
+                expected,
+                all
+            );
+        }
+        // parameters of other declarations aren't in scope, and operators aren't names
+        assert!(!all.contains(&"z".to_string()));
+        assert!(!all.contains(&"+".to_string()));
+
+        // the name being written filters the completions
+        assert_eq!(
+            completions("numbers = [1, 2]; nope = 1; f = len| numbers"),
+            ["length"]
+        );
+        assert_eq!(
+            completions("numbers = [1, 2]; nope = 1; f = n|"),
+            ["null", "nope", "not", "numbers"]
+        );
+        assert_eq!(completions("a = if true th|"), ["then"]);
+
+        // after a complete expression
+        let after = completions("a = 1 |");
+        assert!(after.contains(&";".to_string()), "{:?}", after);
+        assert!(after.contains(&"where".to_string()), "{:?}", after);
+        assert!(completions("a = let b = 1 |").contains(&"in".to_string()));
+
+        // nothing is expected after a syntax error
+        assert!(completions("a = ) 1 |").is_empty());
+        assert!(completions("a = \"unterminated |").is_empty());
+        assert!(parse_completions("a = ü", 5).is_empty());
+        assert!(parse_completions("a = 1", 10).is_empty());
+    }
+}
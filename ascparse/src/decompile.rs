@@ -0,0 +1,382 @@
+//! Turns compiled definitions back into source code.
+//!
+//! The output is not the original source, but compiling it again yields equivalent definitions.
+//! Compiler-generated definitions that are only used once are inlined into their users.
+
+use crate::format::is_plain_ident;
+use crate::ir::{Def, Defs, Id};
+use crate::passes::{escaped_ids, is_private, referenced_ids};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+/// Rendered source of an expression.
+struct Source {
+    text: String,
+    /// If false, this needs parentheses when used as an operand.
+    is_atom: bool,
+}
+
+impl Source {
+    fn atom(text: String) -> Self {
+        Source {
+            text,
+            is_atom: true,
+        }
+    }
+
+    fn compound(text: String) -> Self {
+        Source {
+            text,
+            is_atom: false,
+        }
+    }
+
+    fn operand(self) -> String {
+        if self.is_atom {
+            self.text
+        } else {
+            format!("({})", self.text)
+        }
+    }
+}
+
+/// Definitions in one scope (the program or a function body).
+struct Scope<'a> {
+    defs: &'a Defs,
+    /// Definitions that are rendered in place of their only reference.
+    inline: HashSet<&'a Id>,
+}
+
+impl<'a> Scope<'a> {
+    fn new(defs: &'a Defs) -> Self {
+        let mut uses = HashMap::new();
+        for def in defs.values() {
+            for id in def.refs() {
+                *uses.entry(id).or_insert(0) += 1;
+            }
+        }
+        // references from function bodies need a name
+        let escaped = escaped_ids(defs);
+
+        let inline = defs
+            .keys()
+            .filter(|id| is_private(id) && uses.get(id) == Some(&1) && !escaped.contains(id))
+            .collect();
+        Scope { defs, inline }
+    }
+
+    /// Renders a reference to a definition.
+    fn reference(&self, id: &Id, visiting: &mut HashSet<Id>) -> Source {
+        if self.inline.contains(id) && visiting.insert(id.clone()) {
+            let source = self.def(&self.defs[id], visiting);
+            visiting.remove(id);
+            source
+        } else if is_private(id) {
+            Source::atom(name(id))
+        } else {
+            Source::atom(name_operand(id))
+        }
+    }
+
+    fn def(&self, def: &Def, visiting: &mut HashSet<Id>) -> Source {
+        match def {
+            Def::Number { value } => number(*value),
+            Def::String { value } => Source::atom(string_literal(value)),
+            Def::Bool { value } => Source::atom(value.to_string()),
+            Def::Null => Source::atom("null".into()),
+            Def::Matrix { value } => Source::atom(matrix(&Value::Array(value.clone()))),
+            Def::List { items } => {
+                let items: Vec<_> = items
+                    .iter()
+                    .map(|id| self.reference(id, visiting).text)
+                    .collect();
+                Source::atom(format!("[{}]", items.join(", ")))
+            }
+            Def::Call { f, args } => {
+                let args: Vec<_> = args
+                    .iter()
+                    .map(|id| self.reference(id, visiting).operand())
+                    .collect();
+                if args.is_empty() {
+                    return self.reference(f, visiting);
+                }
+                if is_operator(f) {
+                    if let [a, b] = &args[..] {
+                        return Source::compound(format!("{} {} {}", a, f, b));
+                    }
+                    return Source::compound(format!("{} {}", name_operand(f), args.join(" ")));
+                }
+                let f = self.reference(f, visiting).operand();
+                Source::compound(format!("{} {}", f, args.join(" ")))
+            }
+            Def::Fn { params, body } => {
+                let params: Vec<_> = params.iter().map(name).collect();
+                Source::compound(format!("\\{} -> {}", params.join(" "), body_source(body)))
+            }
+            Def::Switch { cases } => {
+                let mut out = String::new();
+                let mut default = None;
+                for case in cases {
+                    let value = self.reference(&case.value, visiting).operand();
+                    match &case.cond {
+                        Some(cond) => {
+                            let cond = self.reference(cond, visiting).operand();
+                            write!(out, "if {} then {} else ", cond, value).unwrap();
+                        }
+                        None => {
+                            default = Some(value);
+                            break;
+                        }
+                    }
+                }
+                out.push_str(default.as_deref().unwrap_or("null"));
+                Source::compound(out)
+            }
+        }
+    }
+
+    /// Returns the named definitions that a definition depends on, looking through inlined ones.
+    fn deps(&self, id: &'a Id, out: &mut Vec<&'a Id>, visiting: &mut HashSet<&'a Id>) {
+        if !visiting.insert(id) {
+            return;
+        }
+        let def = &self.defs[id];
+        let mut refs = def.refs();
+        if let Def::Fn { params, body } = def {
+            refs.extend(
+                referenced_ids(body)
+                    .into_iter()
+                    .filter(|id| !params.contains(id) && !body.contains_key(*id)),
+            );
+        }
+        for dep in refs {
+            if !self.defs.contains_key(dep) {
+                continue;
+            }
+            if self.inline.contains(dep) {
+                self.deps(dep, out, visiting);
+            } else {
+                out.push(dep);
+            }
+        }
+    }
+
+    /// Returns the named definitions in this scope (except `skip`) so that dependencies come
+    /// before their users.
+    fn sorted_bindings(&self, skip: &str) -> Vec<&'a Id> {
+        fn visit<'a>(
+            scope: &Scope<'a>,
+            id: &'a Id,
+            done: &mut HashSet<&'a Id>,
+            out: &mut Vec<&'a Id>,
+        ) {
+            if !done.insert(id) {
+                return;
+            }
+            let mut deps = Vec::new();
+            scope.deps(id, &mut deps, &mut HashSet::new());
+            for dep in deps {
+                visit(scope, dep, done, out);
+            }
+            out.push(id);
+        }
+
+        let mut done = HashSet::new();
+        let mut out = Vec::new();
+        for id in self.defs.keys() {
+            if id != skip && !self.inline.contains(id) {
+                visit(self, id, &mut done, &mut out);
+            }
+        }
+        out.retain(|id| *id != skip);
+        out
+    }
+
+    /// Renders a declaration of a named definition, e.g. `a = 1` or `f x = x + 1`.
+    fn decl(&self, id: &Id) -> String {
+        match &self.defs[id] {
+            Def::Fn { params, body } if !params.is_empty() => {
+                let params: Vec<_> = params.iter().map(name).collect();
+                format!("{} {} = {}", name(id), params.join(" "), body_source(body))
+            }
+            def => format!("{} = {}", name(id), self.def(def, &mut HashSet::new()).text),
+        }
+    }
+}
+
+/// Renders a function body, with any named definitions in a `let`. They're separated by `;` so
+/// that they're all in the same scope, since they may reference each other in any order.
+fn body_source(body: &Defs) -> String {
+    let scope = Scope::new(body);
+    let result = match body.get("=") {
+        Some(def) => scope.def(def, &mut HashSet::new()).text,
+        None => "null".into(),
+    };
+    let bindings: Vec<_> = scope
+        .sorted_bindings("=")
+        .into_iter()
+        .map(|id| scope.decl(id))
+        .collect();
+    if bindings.is_empty() {
+        result
+    } else {
+        format!("let {} in {}", bindings.join("; "), result)
+    }
+}
+
+/// Returns a name that can be declared for the id, quoted if necessary. Compiler-generated ids
+/// (like `_1x` or `_2`) can't be declared in source code, so they're renamed to e.g. `x'1` or
+/// `t'2`.
+fn name(id: &Id) -> String {
+    if !is_private(id) {
+        return if is_plain_ident(id) {
+            id.clone()
+        } else {
+            quoted_ident(id)
+        };
+    }
+    let digits = id[1..].chars().take_while(|c| c.is_ascii_digit()).count();
+    let (n, suffix) = id[1..].split_at(digits);
+    let suffix = match suffix.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() => suffix,
+        _ => "t",
+    };
+    format!("{}'{}", suffix, n)
+}
+
+/// Returns true if the id is an infix operator.
+pub(crate) fn is_operator(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| "!#$%^&*+-/'<>=|".contains(c))
+}
+
+/// Returns a reference to a name as an operand, i.e. the name itself, or the name in parentheses
+/// (`(+)`), quoted if necessary (``(`my name`)``).
+pub(crate) fn name_operand(name: &str) -> String {
+    if is_plain_ident(name) {
+        name.to_string()
+    } else if is_operator(name) && !name.contains("//") && !name.contains("/*") {
+        // unless it would start a comment
+        format!("({})", name)
+    } else {
+        format!("({})", quoted_ident(name))
+    }
+}
+
+fn number(value: f64) -> Source {
+    let text = if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0. { "Infinity" } else { "-Infinity" }.to_string()
+    } else {
+        value.to_string()
+    };
+    // negative literals would be ambiguous as operands (`f -1` is `f (-1)`, but `a - 1` isn't)
+    if text.starts_with('-') {
+        Source::compound(text)
+    } else {
+        Source::atom(text)
+    }
+}
+
+/// Returns the name in backticks, escaping backticks and backslashes.
+pub(crate) fn quoted_ident(name: &str) -> String {
+    let mut out = String::from("`");
+    for c in name.chars() {
+        if c == '`' || c == '\\' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('`');
+    out
+}
+
+pub(crate) fn string_literal(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if c.is_control() => write!(out, "\\u{{{:x}}}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn matrix(value: &Value) -> String {
+    match value {
+        Value::Null => "null".into(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => number(n.as_f64().unwrap_or(0.)).text,
+        Value::String(s) => string_literal(s),
+        Value::Array(items) => {
+            let items: Vec<_> = items.iter().map(matrix).collect();
+            format!("[{}]", items.join(", "))
+        }
+        // matrices don't contain objects
+        Value::Object(_) => "null".into(),
+    }
+}
+
+/// Renders definitions as source code.
+pub fn to_source(defs: &Defs) -> String {
+    let scope = Scope::new(defs);
+    let mut out = String::new();
+    for (id, _) in defs.iter().filter(|(id, _)| !scope.inline.contains(id)) {
+        out.push_str(&scope.decl(id));
+        out.push_str(";\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile_str;
+
+    #[test]
+    fn round_trip() {
+        let src = r#"
+            numbers = map (\x -> x + 1) [1, 2, 3, 4];
+            fib a = if a <= 0 then 0 else if a <= 2 then 1 else fib (a - 1) + fib (a - 2);
+            cats = map fib numbers;
+            infix_fn = 1 `mod` 2;
+            strings = ["a\"b\n", "c"];
+            mixed = [[], [1, 2], [-Infinity, null], numbers];
+            neg = -numbers;
+            folded = fold (`+`) 0 numbers;
+            ops = [(`*`), (`mod`)];
+            `my field` `x y` = \`a\`b` -> (`x y`) + (`a\`b`);
+            quoted = map ((`my field`) 1) [2];
+        "#;
+        let source = to_source(&compile_str(src).unwrap());
+        let recompiled = compile_str(&source).unwrap_or_else(|err| panic!("{}\n{}", err, source));
+        assert_eq!(to_source(&recompiled), source);
+    }
+
+    #[test]
+    fn shared_definitions() {
+        // `y` is used twice, so it stays a binding
+        let src = "f x = let y = x * 2 in y + y; g = f 1";
+        let source = to_source(&compile_str(src).unwrap());
+        assert!(source.contains("let "));
+        let recompiled = compile_str(&source).unwrap_or_else(|err| panic!("{}\n{}", err, source));
+        assert_eq!(recompiled.len(), 3);
+
+        // arguments of partial applications are used in a function, so they need a name
+        let source = to_source(&compile_str("a = map ((+) 1) [1]").unwrap());
+        assert!(source.contains("t'1 = 1;"), "{}", source);
+        compile_str(&source).unwrap_or_else(|err| panic!("{}\n{}", err, source));
+
+        // bindings that reference each other are in the same `let`
+        let src = "f a = let even n = n == 0 `or` odd (n - 1); odd n = even (n - 1) in even a";
+        let source = to_source(&compile_str(src).unwrap());
+        compile_str(&source).unwrap_or_else(|err| panic!("{}\n{}", err, source));
+    }
+}
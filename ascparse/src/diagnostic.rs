@@ -0,0 +1,277 @@
+//! Human-readable errors and warnings with their position in the source.
+
+use crate::ir::CompileError;
+use crate::lint::Warning;
+use std::fmt;
+use std::ops::Range;
+
+/// A position in source code. Lines and columns start at 1, and columns count characters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    /// Returns the position of a byte offset in the source.
+    pub fn of(src: &str, offset: usize) -> Position {
+        let before = &src[..offset.min(src.len())];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Position {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// How bad a diagnostic is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The program can't be compiled.
+    Error,
+    /// The program compiles, but probably doesn't do what was intended.
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A short, stable identifier for the kind of a diagnostic: the `kind` of the error or warning
+/// (e.g. `cannot_resolve` or `shadow`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DiagCode(pub &'static str);
+
+impl fmt::Display for DiagCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// An error or warning message with the part of the source it refers to. It's displayed with the
+/// offending line and a caret under the offending part, e.g.:
+///
+/// ```text
+/// error: unexpected `;`, expected an expression
+///  --> 1:5
+///   |
+/// 1 | a = ;
+///   |     ^
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: DiagCode,
+    pub message: String,
+    /// Byte offsets of the offending part of the source, if known.
+    pub span: Option<Range<usize>>,
+    /// Where the span starts, if known.
+    pub position: Option<Position>,
+    /// The line the span starts on, and the byte range of the span in that line.
+    snippet: Option<(String, Range<usize>)>,
+}
+
+impl Diagnostic {
+    /// Creates an error diagnostic. Its code is the generic `error`; use `with_code` for errors of
+    /// a known kind, or for warnings.
+    pub fn new(src: &str, message: String, span: Option<Range<usize>>) -> Diagnostic {
+        Diagnostic::with_code(src, Severity::Error, DiagCode("error"), message, span)
+    }
+
+    /// Creates a diagnostic with the given severity and code.
+    pub fn with_code(
+        src: &str,
+        severity: Severity,
+        code: DiagCode,
+        message: String,
+        span: Option<Range<usize>>,
+    ) -> Diagnostic {
+        let position = span.as_ref().map(|span| Position::of(src, span.start));
+        let snippet = span.as_ref().map(|span| {
+            let start = span.start.min(src.len());
+            let line_start = src[..start].rfind('\n').map_or(0, |i| i + 1);
+            let line_end = src[start..].find('\n').map_or(src.len(), |i| start + i);
+            let line = src[line_start..line_end].trim_end_matches('\r');
+            let end = span.end.clamp(start, line_start + line.len());
+            (line.to_string(), start - line_start..end - line_start)
+        });
+        Diagnostic {
+            severity,
+            code,
+            message,
+            span,
+            position,
+            snippet,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.severity, self.message)?;
+        if let (Some(position), Some((line, range))) = (&self.position, &self.snippet) {
+            let gutter = " ".repeat(position.line.to_string().len());
+            writeln!(f)?;
+            writeln!(f, "{}--> {}", gutter, position)?;
+            writeln!(f, "{} |", gutter)?;
+            writeln!(f, "{} | {}", position.line, line)?;
+            // keep tabs so that the caret lines up
+            let pad: String = line[..range.start]
+                .chars()
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let width = line[range.clone()].chars().count().max(1);
+            write!(f, "{} | {}{}", gutter, pad, "^".repeat(width))?;
+        }
+        Ok(())
+    }
+}
+
+impl CompileError {
+    /// Returns the error as a diagnostic for the source it was compiled from.
+    pub fn diagnostic(&self, src: &str) -> Diagnostic {
+        Diagnostic::with_code(
+            src,
+            Severity::Error,
+            DiagCode(self.kind()),
+            self.to_string(),
+            self.span(),
+        )
+    }
+}
+
+impl Warning {
+    /// Returns the warning as a diagnostic for the source it was found in.
+    pub fn diagnostic(&self, src: &str) -> Diagnostic {
+        Diagnostic::with_code(
+            src,
+            Severity::Warning,
+            DiagCode(self.kind()),
+            self.to_string(),
+            Some(self.span()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::CompileOptions;
+    use crate::{compile_str, compile_with_diagnostics};
+
+    #[test]
+    fn positions() {
+        let src = "a = 1;\n\tb = \"ü\" + c;";
+        assert_eq!(Position::of(src, 0), Position { line: 1, column: 1 });
+        assert_eq!(Position::of(src, 7), Position { line: 2, column: 1 });
+        let c = src.find('c').unwrap();
+        assert_eq!(
+            Position::of(src, c),
+            Position {
+                line: 2,
+                column: 12
+            }
+        );
+        assert_eq!(Position::of(src, src.len()).line, 2);
+
+        let diagnostic = compile_str(src).unwrap_err().diagnostic(src);
+        assert_eq!(
+            diagnostic.position,
+            Some(Position {
+                line: 2,
+                column: 12
+            })
+        );
+        assert_eq!(
+            diagnostic.to_string(),
+            "error: cannot resolve 'c'\n \
+             --> 2:12\n  \
+             |\n\
+             2 | \tb = \"ü\" + c;\n  \
+             | \t          ^"
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let src = "a = 1;\nb = ;";
+        let diagnostic = compile_str(src).unwrap_err().diagnostic(src);
+        assert!(
+            diagnostic.message.starts_with("unexpected `;`, expected "),
+            "{}",
+            diagnostic.message
+        );
+        for description in &["a name", "a number", "a string"] {
+            assert!(
+                diagnostic.message.contains(description),
+                "{}",
+                diagnostic.message
+            );
+        }
+        assert!(diagnostic.to_string().ends_with("2 | b = ;\n  |     ^"));
+
+        let src = "a = (1";
+        let diagnostic = compile_str(src).unwrap_err().diagnostic(src);
+        assert!(diagnostic.message.starts_with("unexpected end of input"));
+        assert_eq!(diagnostic.position, Some(Position { line: 1, column: 7 }));
+        assert!(diagnostic.to_string().ends_with("1 | a = (1\n  |       ^"));
+
+        // errors without a position have no snippet
+        let src = "a = b; b = a";
+        let diagnostic = compile_str(src).unwrap_err().diagnostic(src);
+        assert_eq!(diagnostic.position, None);
+        assert_eq!(
+            diagnostic.to_string(),
+            "error: cyclic definition: a -> b -> a"
+        );
+    }
+
+    #[test]
+    fn severities() {
+        let src = "a = 9007199254740993;\nf floor = let a = 1 in floor + a";
+        let (defs, diagnostics) =
+            compile_with_diagnostics(src, &CompileOptions::default()).unwrap();
+        assert!(defs.contains_key("f"));
+        let codes: Vec<_> = diagnostics.iter().map(|d| d.code.0).collect();
+        assert_eq!(codes, ["imprecise_number", "shadow", "shadow"]);
+        assert!(diagnostics.iter().all(|d| d.severity == Severity::Warning));
+        assert_eq!(
+            diagnostics[1].to_string(),
+            "warning: 'floor' shadows a standard library definition\n \
+             --> 2:3\n  \
+             |\n\
+             2 | f floor = let a = 1 in floor + a\n  \
+             |   ^^^^^"
+        );
+
+        // errors are fatal, and come after the warnings
+        let src = "a = 9007199254740993 + b";
+        let diagnostics = compile_with_diagnostics(src, &CompileOptions::default()).unwrap_err();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[1].severity, Severity::Error);
+        assert_eq!(diagnostics[1].code, DiagCode("cannot_resolve"));
+        assert!(diagnostics[1]
+            .to_string()
+            .starts_with("error: cannot resolve 'b'"));
+
+        let diagnostics = compile_with_diagnostics("a =", &CompileOptions::default()).unwrap_err();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, DiagCode("parse"));
+
+        let diagnostic = Diagnostic::new("a = 1", "bad".into(), Some(4..5));
+        assert_eq!(diagnostic.severity, Severity::Error);
+        assert_eq!(diagnostic.code, DiagCode("error"));
+        assert!(diagnostic.to_string().starts_with("error: bad\n --> 1:5"));
+    }
+}
@@ -0,0 +1,552 @@
+//! Canonical source formatting.
+//!
+//! Source is parsed and printed back with normalized whitespace, so formatting is idempotent.
+//! Besides whitespace, this also removes redundant parentheses, merges nested `let`s, and writes
+//! `c ? t : e` as `if c then t else e`. Number literals are kept as written.
+//!
+//! Comments between declarations are kept, as are blank lines between them. Comments inside a
+//! declaration are moved to the lines before it, since the syntax tree has no place for them.
+//! Doc comments and attributes stay with the declaration they belong to.
+
+use crate::ast::{Decl, Expr, ExprKind, Op};
+use crate::decompile::{is_operator, name_operand, quoted_ident, string_literal};
+use crate::ir::{is_name_char, is_name_start, KEYWORDS};
+use crate::ParseError;
+use std::ops::Range;
+
+/// Maximum line width before lists, `if`s and `let`s are broken up into multiple lines.
+const WIDTH: usize = 100;
+const INDENT: usize = 4;
+
+/// Formats source code.
+pub fn format(src: &str) -> Result<String, ParseError> {
+    let prog = crate::parse(src)?;
+    // doc comments are part of declarations
+    let comments: Vec<_> = comments(src)
+        .into_iter()
+        .filter(|comment| !src[comment.clone()].starts_with("///"))
+        .collect();
+    let mut comments = comments.iter().peekable();
+
+    let mut out = String::new();
+    // end of the last comment or declaration that was written in its original position
+    let mut last_end = None;
+
+    let write_item = |out: &mut String, last_end: &mut Option<usize>, range: &Range<usize>| {
+        if let Some(end) = *last_end {
+            let has_blank_line = src[end..range.start].matches('\n').count() > 1;
+            out.push_str(if has_blank_line { "\n\n" } else { "\n" });
+        }
+        *last_end = Some(range.end);
+    };
+
+    for (i, decl) in prog.0.iter().enumerate() {
+        while let Some(comment) = comments.next_if(|c| c.start < decl.span.start) {
+            write_item(&mut out, &mut last_end, comment);
+            out.push_str(&src[comment.clone()]);
+        }
+        write_item(&mut out, &mut last_end, &decl.span);
+        while let Some(comment) = comments.next_if(|c| c.start < decl.span.end) {
+            out.push_str(&src[comment.clone()]);
+            out.push('\n');
+        }
+        out.push_str(&format_decl(decl, 0));
+        out.push(';');
+
+        // comments on the same line as the end of the declaration stay there
+        let next_start = prog.0.get(i + 1).map_or(src.len(), |next| next.span.start);
+        while let Some(comment) = comments.next_if(|c| {
+            let end = last_end.unwrap_or(0);
+            c.start < next_start && !src[end..c.start].contains(&['\n', '\r'][..])
+        }) {
+            out.push(' ');
+            out.push_str(&src[comment.clone()]);
+            last_end = Some(comment.end);
+        }
+    }
+    for comment in comments {
+        write_item(&mut out, &mut last_end, comment);
+        out.push_str(&src[comment.clone()]);
+    }
+    out.push('\n');
+    Ok(out)
+}
+
+/// Finds the byte ranges of comments in the source, skipping over anything that looks like a
+/// comment inside other tokens.
+fn comments(src: &str) -> Vec<Range<usize>> {
+    fn is_op_char(c: u8) -> bool {
+        b"!#$%^&*+-/'<>=|".contains(&c)
+    }
+    fn is_ident_char(c: u8) -> bool {
+        c.is_ascii_alphanumeric() || b"!@#$%^&*_+-/'<>=|".contains(&c)
+    }
+    let bytes = src.as_bytes();
+    let find = |from: usize, pattern: &str| src[from..].find(pattern).map(|i| from + i);
+
+    let mut comments = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c == b'"' {
+            i += 1;
+            while i < bytes.len() && bytes[i] != b'"' {
+                i += if bytes[i] == b'\\' { 2 } else { 1 };
+            }
+            i += 1;
+        } else if c == b'r' && bytes.get(i + 1) == Some(&b'"') && find(i + 2, "\"").is_some() {
+            i = find(i + 2, "\"").unwrap() + 1;
+        } else if c == b'`' {
+            i += 1;
+            while i < bytes.len() && !b"`\n\r".contains(&bytes[i]) {
+                i += if bytes[i] == b'\\' { 2 } else { 1 };
+            }
+            i += 1;
+        } else if c == b'_' || c == b'@' || c.is_ascii_alphabetic() {
+            while i < bytes.len() && is_ident_char(bytes[i]) {
+                i += 1;
+            }
+        } else if is_op_char(c) {
+            // the lexer takes the longest match, preferring comments if they're the same length
+            let op_end = (i..bytes.len())
+                .find(|&j| !is_op_char(bytes[j]))
+                .unwrap_or(bytes.len());
+            let comment_end = if src[i..].starts_with("//") {
+                Some(find(i, "\n").unwrap_or(bytes.len())).map(|end| {
+                    if end > i && bytes[end - 1] == b'\r' {
+                        end - 1
+                    } else {
+                        end
+                    }
+                })
+            } else if src[i..].starts_with("/*") {
+                find(i + 2, "*/").map(|end| end + 2)
+            } else {
+                None
+            };
+            match comment_end {
+                Some(end) if end >= op_end => {
+                    comments.push(i..end);
+                    i = end;
+                }
+                _ => i = op_end,
+            }
+        } else {
+            i += 1;
+        }
+    }
+    comments
+}
+
+/// Returns true if the name can be written as-is, without backticks.
+pub(crate) fn is_plain_ident(name: &str) -> bool {
+    name.starts_with(is_name_start) && name.chars().all(is_name_char) && !KEYWORDS.contains(&name)
+}
+
+/// Returns the name as it can be declared, i.e. in backticks unless it's a plain identifier.
+fn name(name: &str) -> String {
+    if is_plain_ident(name) {
+        name.into()
+    } else {
+        quoted_ident(name)
+    }
+}
+
+fn number_literal(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".into()
+    } else if value.is_infinite() {
+        if value > 0. { "Infinity" } else { "-Infinity" }.into()
+    } else {
+        // Debug output is the shortest representation that parses back to the same value
+        let s = format!("{:?}", value);
+        s.strip_suffix(".0").map(str::to_string).unwrap_or(s)
+    }
+}
+
+fn pad(indent: usize) -> String {
+    " ".repeat(indent)
+}
+
+/// Returns true if the text fits on the current line.
+fn fits(indent: usize, text: &str) -> bool {
+    indent + text.len() <= WIDTH && !text.contains('\n')
+}
+
+fn format_decl(decl: &Decl, indent: usize) -> String {
+    let mut out = String::new();
+    for line in decl.doc.iter().flat_map(|doc| doc.split('\n')) {
+        out.push_str("///");
+        if !line.is_empty() {
+            out.push(' ');
+            out.push_str(line);
+        }
+        out.push('\n');
+        out.push_str(&pad(indent));
+    }
+    for attr in &decl.attrs {
+        out.push_str(&format!("@[{}] ", attr));
+    }
+
+    let mut head = name(&decl.name.name);
+    for param in &decl.params {
+        head.push(' ');
+        head.push_str(&name(&param.name));
+    }
+    out.push_str(&format!("{} = {}", head, format_body(&decl.body, indent)));
+    out
+}
+
+/// Formats an expression in a position where parentheses around it are never needed.
+fn format_body(mut expr: &Expr, indent: usize) -> String {
+    while let ExprKind::Group(inner) = &expr.kind {
+        expr = inner;
+    }
+    format_expr(expr, indent)
+}
+
+/// Formats an expression. `indent` is the indentation of the line it starts on.
+fn format_expr(expr: &Expr, indent: usize) -> String {
+    match &expr.kind {
+        ExprKind::Group(inner) => format!("({})", format_body(inner, indent)),
+        ExprKind::Ident(ident) => name_operand(&ident.name),
+        ExprKind::Number(_, Some(literal)) => literal.text.clone(),
+        ExprKind::Number(value, None) => number_literal(*value),
+        ExprKind::String(value) => string_literal(value),
+        ExprKind::Bool(value) => value.to_string(),
+        ExprKind::Null => "null".into(),
+        ExprKind::Date(date) | ExprKind::DateTime(date) => format!("@{}", date),
+        ExprKind::Apply(a, Op::Apply, b) => {
+            format!("{} {}", format_expr(a, indent), format_expr(b, indent))
+        }
+        ExprKind::Apply(a, Op::Infix(op), b) => {
+            let op = if is_operator(&op.name) || op.name == "??" {
+                op.name.clone()
+            } else {
+                quoted_ident(&op.name)
+            };
+            let a = format_expr(a, indent);
+            format!("{} {} {}", a, op, format_expr(b, indent))
+        }
+        ExprKind::Unary(op, operand) => {
+            let op = match op {
+                Op::Infix(op) => &op.name,
+                Op::Apply => unreachable!("application is not a prefix operator"),
+            };
+            let operand_src = format_expr(operand, indent);
+            // `- 1` must not become the literal `-1`, and `- -a` must not become `--a`
+            let needs_space = matches!(operand.kind, ExprKind::Number(..))
+                || operand_src.starts_with(|c: char| "!#$%^&*+-/'<>=|".contains(c));
+            if needs_space {
+                format!("{} {}", op, operand_src)
+            } else {
+                format!("{}{}", op, operand_src)
+            }
+        }
+        ExprKind::Lambda(lambda) => {
+            let params: Vec<_> = lambda.params.iter().map(|p| name(&p.name)).collect();
+            let body = format_body(&lambda.body, indent);
+            format!("\\{} -> {}", params.join(" "), body)
+        }
+        ExprKind::Spread(inner) => format!("...{}", format_body(inner, indent)),
+        ExprKind::Index(list, index) => {
+            let list = match list.kind {
+                ExprKind::Apply(..)
+                | ExprKind::Unary(..)
+                | ExprKind::Let(..)
+                | ExprKind::Where(..)
+                | ExprKind::If(..)
+                | ExprKind::Lambda(_) => format!("({})", format_expr(list, indent)),
+                _ => format_expr(list, indent),
+            };
+            format!("{}[{}]", list, format_body(index, indent))
+        }
+        ExprKind::List(items) => {
+            let items: Vec<_> = items
+                .iter()
+                .map(|item| format_body(item, indent + INDENT))
+                .collect();
+            let flat = format!("[{}]", items.join(", "));
+            if items.is_empty() || fits(indent, &flat) {
+                return flat;
+            }
+            let mut out = String::from("[\n");
+            for item in items {
+                out.push_str(&pad(indent + INDENT));
+                out.push_str(&item);
+                out.push_str(",\n");
+            }
+            out.push_str(&pad(indent));
+            out.push(']');
+            out
+        }
+        ExprKind::If(c, t, e) => {
+            // else-if chains are written as such, even if the inner `if` was in parentheses
+            let mut branches = vec![(c, t)];
+            let mut else_expr = e;
+            loop {
+                let mut inner = &**else_expr;
+                while let ExprKind::Group(group) = &inner.kind {
+                    inner = group;
+                }
+                match &inner.kind {
+                    ExprKind::If(c, t, e) => {
+                        branches.push((c, t));
+                        else_expr = e;
+                    }
+                    _ => break,
+                }
+            }
+
+            let inner_indent = indent + INDENT;
+            let mut lines: Vec<_> = branches
+                .into_iter()
+                .map(|(c, t)| {
+                    let c = format_body(c, inner_indent);
+                    format!("if {} then {}", c, format_body(t, inner_indent))
+                })
+                .collect();
+            lines.push(format_body(else_expr, inner_indent));
+
+            let flat = lines.join(" else ");
+            if fits(indent, &flat) {
+                flat
+            } else {
+                lines.join(&format!("\n{}else ", pad(inner_indent)))
+            }
+        }
+        ExprKind::Match(arms) => {
+            let arms: Vec<_> = arms
+                .iter()
+                .map(|(cond, value)| {
+                    let cond = match cond {
+                        Some(cond) => format_body(cond, indent + INDENT),
+                        None => "else".into(),
+                    };
+                    format!("{} -> {}", cond, format_body(value, indent + INDENT))
+                })
+                .collect();
+            let flat = format!("cond {{ {} }}", arms.join(", "));
+            if fits(indent, &flat) {
+                return flat;
+            }
+            let mut out = String::from("cond {\n");
+            for arm in arms {
+                out.push_str(&pad(indent + INDENT));
+                out.push_str(&arm);
+                out.push_str(",\n");
+            }
+            out.push_str(&pad(indent));
+            out.push('}');
+            out
+        }
+        ExprKind::Let(decls, next) => {
+            let mut decls: Vec<&Decl> = decls.iter().collect();
+            let mut inner = &**next;
+            let sep = if decls.len() > 1 {
+                ";"
+            } else {
+                // nested `let`s with one declaration each are written as a single `let` with commas
+                loop {
+                    while let ExprKind::Group(group) = &inner.kind {
+                        inner = group;
+                    }
+                    match &inner.kind {
+                        ExprKind::Let(next_decls, next) if next_decls.len() == 1 => {
+                            decls.push(&next_decls[0]);
+                            inner = next;
+                        }
+                        _ => break,
+                    }
+                }
+                ","
+            };
+            let decls: Vec<_> = decls
+                .into_iter()
+                .map(|decl| format_decl(decl, indent + INDENT))
+                .collect();
+            let body = format_body(inner, indent + INDENT);
+
+            let flat = format!("let {} in {}", decls.join(&format!("{} ", sep)), body);
+            if fits(indent, &flat) {
+                return flat;
+            }
+            let sep = format!("{}\n{}", sep, pad(indent + INDENT));
+            format!(
+                "let {}\n{}in {}",
+                decls.join(&sep),
+                pad(indent + INDENT),
+                body
+            )
+        }
+        ExprKind::Where(body, decls) => {
+            let body = format_body(body, indent);
+            let decls: Vec<_> = decls
+                .iter()
+                .map(|decl| format_decl(decl, indent + INDENT))
+                .collect();
+
+            let flat = format!("{} where {}", body, decls.join(", "));
+            if fits(indent, &flat) {
+                return flat;
+            }
+            let sep = format!(",\n{}", pad(indent + INDENT));
+            format!(
+                "{}\n{}where {}",
+                body,
+                pad(indent + INDENT),
+                decls.join(&sep)
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile_str;
+
+    const CORPUS: &[&str] = &[
+        r#"
+numbers = map (\x -> x + 1) [1, 2, 3, 4];
+
+fib a = if a <= 0 then 0 else (
+    if a <= 2 then 1 else (
+        fib (a - 1) + fib (a - 2)
+    )
+);
+fact a = if a < 0 then null else (if a <= 1 then 1 else (a * fact (a - 1)));
+clamp_unit a = a > 1 ? 1 : a < -1 ? -1 : a;
+
+cats = map fib numbers;
+
+infix_fn = 1 `mod` 2;
+
+let_bindings a = let b = 2, c = 1 in a * b + c;
+
+some_data = [[], [1, 2, 3], [null, true, false]];
+
+strings = ["escapes: \"\t\u{1F600}\x41\"", r"raw: C:\no\escapes", "line breaks
+are kept as-is"];
+"#,
+        "// leading comment\n\n\na = 1 + 2*3;b = -a; // trailing\n/* block\n   comment */ c = - -a;\n\n\
+         d = [a, /* inline */ b];e = (`+`) 1 2; f = 0x10 + 1_000.5 + 1e30 + - 1\n// at the end",
+        "a = \\x y -> x ^ y ^ 2; `+++` = 1; b = 1 `+++` 2; c = !(true) `and` (a `mod` 2 == 0); \
+         d = [@2024-01-15, @2024-01-15T10:30+01:00]",
+        "long_list = [\"aaaaaaaaaaaa\", \"bbbbbbbbbbbbbbbbbb\", \"cccccccccccccccccccc\", \
+         \"dddddddddddddddddddd\", [1, 2, 3], null];\n\
+         maybe = long_list ?? [];\n\
+         long_if x = if x == 1 then \"one one one one one\" else if x == 2 then \"two two two two \
+         two\" else let y = x * 2, z = y + 1 in [y, z, y, z, y, z, y, z, y, z, y, z, y, z, y, z, y, z];\n\
+         rec = let even n = n == 0 `or` odd (n - 1); odd n = n != 0 `and` even (n - 1); in even 10;\n\
+         total x = (y * 2) where y = x + z, z = 1,;\n\
+         long_where = [a, b, a, b] where a = \"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\", b = \"bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb\"",
+        "/// Doubles a number.\n///\n///   indented\n@[inline]  @[ since = \"1.0\" ] double x = x * 2;\n\
+         @[test] /// attributes first\nt = let /// local\nb = 1 in b; u = c where /// where\nc = 1",
+        r"`my field` = 1; `odd \` name\\` `x y` = (`x y`) + (`my field`); g = \`p q` -> 1 `odd \` name\\` (`p q`)",
+        "xs = [1]; ys = [ ... xs, 2, ...(xs ++ [3]),]; zs = [...ys]; last = ys[-1]; \
+         at i = zs[ i + 1 ] ?? xs[0]",
+        "sign_of x = cond {x < 0 -> -1, x == 0 -> 0, else -> 1}; \
+         long_cond x = cond { x == 1 -> \"one one one one one\", x == 2 -> \"two two two two two two two\", x == 3 -> \"three\", else -> null }",
+        "m = [[1, 2]]; f x = [x]; a = m[0][-1] + (f 1)[0] + [1, 2][1] + sum[1, 2]",
+    ];
+
+    #[test]
+    fn annotations() {
+        assert_eq!(
+            format(CORPUS[4]).unwrap(),
+            "/// Doubles a number.\n///\n///   indented\n@[inline] @[since = \"1.0\"] double x = x * 2;\n\
+             /// attributes first\n@[test] t = let /// local\n    b = 1\n    in b;\n\
+             u = c\n    where /// where\n    c = 1;\n"
+        );
+    }
+
+    #[test]
+    fn quoted_names() {
+        assert_eq!(
+            format(CORPUS[5]).unwrap(),
+            "`my field` = 1;\n`odd \\` name\\\\` `x y` = (`x y`) + (`my field`);\n\
+             g = \\`p q` -> 1 `odd \\` name\\\\` (`p q`);\n"
+        );
+        // names are only quoted if they need to be
+        assert_eq!(
+            format("`a` `b` = (`c`) `d` (`+`)").unwrap(),
+            "a b = c `d` (+);\n"
+        );
+    }
+
+    #[test]
+    fn idempotent() {
+        for src in CORPUS {
+            let formatted = format(src).unwrap();
+            assert_eq!(format(&formatted).unwrap(), formatted, "{}", formatted);
+        }
+    }
+
+    #[test]
+    fn preserves_semantics() {
+        for src in CORPUS {
+            let formatted = format(src).unwrap();
+            assert_eq!(
+                compile_str(&formatted).unwrap(),
+                compile_str(src).unwrap(),
+                "{}",
+                formatted
+            );
+        }
+    }
+
+    #[test]
+    fn layout() {
+        assert_eq!(
+            format("a =1 + 2 ; b  x = ( x ) ; c = (\\x -> x)").unwrap(),
+            "a = 1 + 2;\nb x = x;\nc = \\x -> x;\n"
+        );
+        assert_eq!(
+            format("a = [ ... xs, 1, ...(ys ++ zs),]").unwrap(),
+            "a = [...xs, 1, ...ys ++ zs];\n"
+        );
+        // number literals are kept as written
+        assert_eq!(
+            format("a = [0xFF, 1_000, 1.50, 2r101, -0o7]").unwrap(),
+            "a = [0xFF, 1_000, 1.50, 2r101, -0o7];\n"
+        );
+        assert_eq!(
+            format("a = 1; // one\n\n\n// two\nb = let x = 1 in let y = 2 in x /* three */ + y")
+                .unwrap(),
+            "a = 1; // one\n\n// two\n/* three */\nb = let x = 1, y = 2 in x + y;\n"
+        );
+        assert_eq!(
+            format(CORPUS[3]).unwrap(),
+            r#"long_list = [
+    "aaaaaaaaaaaa",
+    "bbbbbbbbbbbbbbbbbb",
+    "cccccccccccccccccccc",
+    "dddddddddddddddddddd",
+    [1, 2, 3],
+    null,
+];
+maybe = long_list ?? [];
+long_if x = if x == 1 then "one one one one one"
+    else if x == 2 then "two two two two two"
+    else let y = x * 2, z = y + 1 in [y, z, y, z, y, z, y, z, y, z, y, z, y, z, y, z, y, z];
+rec = let even n = n == 0 `or` odd (n - 1); odd n = n != 0 `and` even (n - 1) in even 10;
+total x = y * 2 where y = x + z, z = 1;
+long_where = [a, b, a, b]
+    where a = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+    b = "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+"#
+        );
+        assert_eq!(
+            format(CORPUS[7]).unwrap(),
+            r#"sign_of x = cond { x < 0 -> -1, x == 0 -> 0, else -> 1 };
+long_cond x = cond {
+    x == 1 -> "one one one one one",
+    x == 2 -> "two two two two two two two",
+    x == 3 -> "three",
+    else -> null,
+};
+"#
+        );
+    }
+}
@@ -1,14 +1,26 @@
 use crate::ast::*;
-use serde::Serialize;
+use crate::passes::is_private;
+use crate::types::{self, Type};
+use crate::ParseError;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::{Range, RangeInclusive};
+use std::{error, fmt, iter, mem};
 
-type Id = String;
+pub type Id = String;
 
-pub type Defs = HashMap<Id, Def>;
+/// Definitions by id. This is ordered so that serialized output is deterministic (note that ids
+/// are compared as strings, so `_10` comes before `_2`).
+pub type Defs = BTreeMap<Id, Def>;
 
-#[derive(Serialize)]
+/// Source spans of compiled definitions (see `compile_with_source_map`). Definitions are identified
+/// by the path of ids leading to them from the top level, since function bodies have their own
+/// scope (e.g. every function body defines `=`).
+pub type SourceMap = HashMap<Vec<Id>, Range<usize>>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "t")]
 pub enum Def {
     #[serde(rename = "n")]
@@ -58,135 +70,482 @@ pub enum Def {
     },
 }
 
-#[derive(Serialize)]
+impl Def {
+    /// Returns the ids this definition refers to in its own scope (not including function bodies).
+    pub(crate) fn refs(&self) -> Vec<&Id> {
+        match self {
+            Def::List { items } => items.iter().collect(),
+            Def::Call { f, args } => iter::once(f).chain(args).collect(),
+            Def::Switch { cases } => cases
+                .iter()
+                .flat_map(|case| case.cond.iter().chain(iter::once(&case.value)))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Like `refs`, but mutable.
+    pub(crate) fn refs_mut(&mut self) -> Vec<&mut Id> {
+        match self {
+            Def::List { items } => items.iter_mut().collect(),
+            Def::Call { f, args } => iter::once(f).chain(args).collect(),
+            Def::Switch { cases } => cases
+                .iter_mut()
+                .flat_map(|case| case.cond.iter_mut().chain(iter::once(&mut case.value)))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SwitchCase {
     #[serde(rename = "c")]
-    cond: Option<Id>,
+    pub cond: Option<Id>,
     #[serde(rename = "v")]
-    value: Id,
-}
-
-const STDLIB_NAMES: &[&str] = &[
-    "+",
-    "-",
-    "*",
-    "/",
-    "^",
-    "mod",
-    "floor",
-    "ceil",
-    "round",
-    "trunc",
-    "sign",
-    "abs",
-    "==",
-    "!=",
-    ">",
-    "<",
-    ">=",
-    "<=",
-    "and",
-    "or",
-    "not",
-    "xor",
-    "++",
-    "map",
-    "flat_map",
-    "fold",
-    "fold1",
-    "index",
-    "length",
-    "contains",
-    "head",
-    "tail",
-    "sum",
-    "min",
-    "max",
-    "avg",
-    "med",
-    "sort",
-    "date_sub",
-    "date_add",
-    "date_today",
-    "date_fmt",
-    "date_get",
-    "date_set",
-    "ts_now",
-    "tz_utc",
-    "tz_local",
-    "ts_from_unix",
-    "ts_to_unix",
-    "ts_from_date",
-    "ts_to_date",
-    "ts_parse",
-    "ts_to_string",
-    "ts_fmt",
-    "ts_add",
-    "ts_sub",
-    "ts_get",
-    "ts_set",
-    "datetime_fmt",
-    "currency_fmt",
-    "country_fmt",
-    "phone_fmt",
-    "id",
+    pub value: Id,
+}
+
+/// Standard library definitions and how many arguments they can be called with.
+///
+/// Definitions that take no arguments (e.g. `tz_utc`) are plain values.
+const STDLIB: &[(&str, RangeInclusive<usize>)] = &[
+    ("+", 2..=2),
+    ("-", 2..=2),
+    ("*", 2..=2),
+    ("/", 2..=2),
+    ("^", 2..=2),
+    ("mod", 2..=2),
+    ("floor", 1..=1),
+    ("ceil", 1..=1),
+    ("round", 1..=1),
+    ("trunc", 1..=1),
+    ("sign", 1..=1),
+    ("abs", 1..=1),
+    ("==", 2..=2),
+    ("!=", 2..=2),
+    (">", 2..=2),
+    ("<", 2..=2),
+    (">=", 2..=2),
+    ("<=", 2..=2),
+    ("and", 2..=2),
+    ("or", 2..=2),
+    ("not", 1..=1),
+    ("xor", 2..=2),
+    ("++", 2..=2),
+    ("map", 2..=2),
+    ("flat_map", 2..=2),
+    ("fold", 3..=3),
+    ("fold1", 2..=2),
+    ("index", 2..=2),
+    ("length", 1..=1),
+    ("contains", 2..=2),
+    ("head", 2..=2),
+    ("tail", 2..=2),
+    ("sum", 1..=1),
+    ("min", 1..=1),
+    ("max", 1..=1),
+    ("avg", 1..=1),
+    ("med", 1..=1),
+    ("sort", 1..=1),
+    ("date_sub", 3..=3),
+    ("date_add", 3..=3),
+    ("date_today", 0..=0),
+    ("date_fmt", 1..=1),
+    ("date_get", 2..=2),
+    ("date_set", 3..=3),
+    ("ts_now", 0..=0),
+    ("tz_utc", 0..=0),
+    ("tz_local", 0..=0),
+    ("ts_from_unix", 1..=1),
+    ("ts_to_unix", 1..=1),
+    ("ts_from_date", 5..=5),
+    ("ts_to_date", 2..=2),
+    ("ts_parse", 1..=1),
+    ("ts_to_string", 1..=1),
+    ("ts_fmt", 1..=1),
+    ("ts_add", 3..=3),
+    ("ts_sub", 3..=3),
+    ("ts_get", 3..=3),
+    ("ts_set", 4..=4),
+    ("datetime_fmt", 1..=1),
+    ("currency_fmt", 2..=2),
+    ("country_fmt", 1..=1),
+    ("phone_fmt", 1..=1),
+    ("id", 1..=1),
+];
+
+/// Words that have a meaning in the grammar.
+pub(crate) const KEYWORDS: &[&str] = &[
+    "let", "in", "where", "if", "then", "else", "cond", "true", "false", "null", "Infinity", "NaN",
 ];
 
+/// Returns true if a name that isn't quoted can start with the character.
+pub(crate) fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '@'
+}
+
+/// Returns true if a name that isn't quoted can contain the character (after the first one).
+pub(crate) fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_'!@#$%^&*+-/<>=|".contains(c)
+}
+
+/// Returns true if the name can't be declared because it's a keyword, or because it starts with
+/// `@` (used for external definitions) or `_` (used for generated definitions).
+fn is_reserved_name(name: &str) -> bool {
+    KEYWORDS.contains(&name) || name.starts_with('@') || name.starts_with('_')
+}
+
+/// Returns true if the name is non-empty, contains no control characters, and doesn't start or
+/// end with whitespace. Names that aren't a letter followed by letters, digits and any of
+/// `_'!@#$%^&*+-/<>=|` have to be quoted in backticks.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.chars().any(char::is_control) && name.trim() == name
+}
+
+/// Returns true if the id is a standard library definition.
+pub(crate) fn is_stdlib(id: &str) -> bool {
+    STDLIB.iter().any(|(name, _)| *name == id)
+}
+
+/// Returns the names of all standard library definitions.
+pub(crate) fn stdlib_names() -> impl Iterator<Item = &'static str> {
+    STDLIB.iter().map(|(name, _)| *name)
+}
+
 #[derive(Debug, Clone)]
 pub enum CompileError {
-    DupIdent(String),
-    CantResolve(String),
+    Parse(ParseError),
+    DupIdent(String, Range<usize>),
+    CantResolve(String, Range<usize>),
+    /// Definitions that depend on each other in a cycle, in order.
+    Cycle(Vec<Id>),
+    /// An expression is nested more deeply than the given limit.
+    TooDeep(usize),
+    /// A built-in function called with too many arguments (name, expected number, actual number,
+    /// span). Fewer arguments are a partial application.
+    Arity(String, RangeInclusive<usize>, usize, Range<usize>),
+    /// A declared name is a keyword or starts with `@` or `_`.
+    ReservedIdent(String, Range<usize>),
+    /// A declared name contains characters that aren't allowed (see `is_valid_name`).
+    InvalidIdent(String, Range<usize>),
+    /// A built-in function called with an argument of the wrong type, if types are checked (name,
+    /// index of the argument starting at 1, accepted types, actual type, span).
+    TypeMismatch(String, usize, Vec<Type>, Type, Range<usize>),
+    /// A list literal with items of different types, in strict mode (the type of the first item,
+    /// the type of the offending item, and its span).
+    MixedList(Type, Type, Range<usize>),
+    /// An error in one of the programs compiled by `compile_many` (the index of the program, and
+    /// the error, whose span is relative to that program's source).
+    InProgram(usize, Box<CompileError>),
+}
+
+/// The default limit for how deeply expressions may be nested.
+pub const DEFAULT_MAX_DEPTH: usize = 200;
+
+impl CompileError {
+    /// Returns a short machine-readable name for the kind of error.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CompileError::Parse(_) => "parse",
+            CompileError::DupIdent(..) => "duplicate_identifier",
+            CompileError::CantResolve(..) => "cannot_resolve",
+            CompileError::Cycle(_) => "cycle",
+            CompileError::TooDeep(_) => "too_deep",
+            CompileError::Arity(..) => "arity",
+            CompileError::ReservedIdent(..) => "reserved_identifier",
+            CompileError::InvalidIdent(..) => "invalid_identifier",
+            CompileError::TypeMismatch(..) => "type_mismatch",
+            CompileError::MixedList(..) => "mixed_list",
+            CompileError::InProgram(_, err) => err.kind(),
+        }
+    }
+
+    /// Returns the byte offsets in the source that caused this error, if known.
+    pub fn span(&self) -> Option<Range<usize>> {
+        match self {
+            CompileError::Parse(err) => match err {
+                ParseError::InvalidToken { location }
+                | ParseError::UnrecognizedEOF { location, .. } => Some(*location..*location),
+                ParseError::UnrecognizedToken {
+                    token: (start, _, end),
+                    ..
+                }
+                | ParseError::ExtraToken {
+                    token: (start, _, end),
+                } => Some(*start..*end),
+                ParseError::User { error } => match error {
+                    SyntaxError::UnknownPrefixOp(_, span)
+                    | SyntaxError::MatchDefault(span)
+                    | SyntaxError::String(StringParseError::UnknownEscape(_, span)) => {
+                        Some(span.clone())
+                    }
+                    _ => None,
+                },
+            },
+            CompileError::DupIdent(_, span)
+            | CompileError::CantResolve(_, span)
+            | CompileError::Arity(_, _, _, span)
+            | CompileError::ReservedIdent(_, span)
+            | CompileError::InvalidIdent(_, span)
+            | CompileError::TypeMismatch(.., span)
+            | CompileError::MixedList(_, _, span) => Some(span.clone()),
+            CompileError::Cycle(_) | CompileError::TooDeep(_) => None,
+            CompileError::InProgram(_, err) => err.span(),
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::Parse(err) => write!(f, "{}", err),
+            CompileError::InProgram(index, err) => write!(f, "in program {}: {}", index, err),
+            CompileError::DupIdent(id, _) => write!(f, "duplicate identifier '{}'", id),
+            CompileError::CantResolve(id, _) => write!(f, "cannot resolve '{}'", id),
+            CompileError::Cycle(ids) => {
+                write!(f, "cyclic definition: ")?;
+                for id in ids {
+                    write!(f, "{} -> ", id)?;
+                }
+                write!(f, "{}", ids[0])
+            }
+            CompileError::TooDeep(limit) => {
+                write!(
+                    f,
+                    "expression is nested too deeply (the limit is {})",
+                    limit
+                )
+            }
+            CompileError::Arity(id, expected, got, _) => {
+                write!(f, "'{}' takes ", id)?;
+                if expected.start() == expected.end() {
+                    write!(f, "{}", expected.start())?;
+                } else {
+                    write!(f, "{} to {}", expected.start(), expected.end())?;
+                }
+                let plural = if *expected.end() == 1 { "" } else { "s" };
+                write!(f, " argument{}, but was given {}", plural, got)
+            }
+            CompileError::ReservedIdent(id, _) => write!(f, "'{}' is a reserved name", id),
+            CompileError::InvalidIdent(id, _) => write!(f, "'{}' is not a valid name", id),
+            CompileError::TypeMismatch(id, index, expected, actual, _) => {
+                let expected: Vec<_> = expected.iter().map(Type::to_string).collect();
+                write!(
+                    f,
+                    "argument {} of '{}' should be a {}, but is a {}",
+                    index,
+                    id,
+                    expected.join(" or "),
+                    actual
+                )
+            }
+            CompileError::MixedList(first, other, _) => write!(
+                f,
+                "items of a list should all have the same type, but this is a {} and an earlier item is a {}",
+                other, first
+            ),
+        }
+    }
 }
 
+impl error::Error for CompileError {}
+
 struct CompileContext<'a> {
     parent: Option<&'a CompileContext<'a>>,
-    names: RefCell<HashSet<Id>>,
+    /// Maps names in this scope to their assigned ids.
+    names: RefCell<HashMap<String, Id>>,
+    /// The number of parameters of functions declared in this scope, by name.
+    arities: RefCell<HashMap<String, usize>>,
     priv_counter: RefCell<usize>,
     /// If true, parent must be Some.
     is_pseudo: bool,
+    /// If set (only in the global context), recoverable errors are collected here instead of
+    /// aborting compilation.
+    errors: Option<RefCell<Vec<CompileError>>>,
+    /// If set (only in the global context), checks whether an external name exists.
+    resolve_external: Option<&'a dyn Fn(&str) -> bool>,
+    /// If set (only in the global context), list literals are never compiled to matrices.
+    lists_only: bool,
+    /// If set (only in the global context), arguments of stdlib calls are type checked.
+    check_types: bool,
+    /// If set (only in the global context), lists are never matrices and can't have items of
+    /// different types.
+    strict: bool,
+    /// Ids that names are compiled to instead of themselves if they refer to the standard library
+    /// or aren't defined (only in the global context).
+    aliases: HashMap<String, Id>,
+    /// If this is a function scope, the id of the function.
+    fn_id: Option<Id>,
+    /// If set (only in the global context), source spans of definitions are recorded here.
+    spans: Option<RefCell<SourceMap>>,
+    /// True if the global context records source spans.
+    records_spans: bool,
+    /// If set (only in the global context), the index of the program each top-level declaration
+    /// is from, for `compile_many`.
+    decl_programs: Option<Vec<usize>>,
 }
 
 impl<'a> CompileContext<'a> {
-    fn global() -> CompileContext<'static> {
+    /// Creates the global context with the stdlib and the given extra built-in names, which are
+    /// compiled to their alias if they have one.
+    fn global(extra_names: &HashSet<Id>, aliases: &HashMap<String, Id>) -> CompileContext<'a> {
         CompileContext {
             parent: None,
-            names: RefCell::new(STDLIB_NAMES.iter().map(|name| name.to_string()).collect()),
+            names: RefCell::new(
+                STDLIB
+                    .iter()
+                    .map(|(name, _)| name.to_string())
+                    .chain(extra_names.iter().cloned())
+                    .map(|name| {
+                        let id = aliases.get(&name).unwrap_or(&name).clone();
+                        (name, id)
+                    })
+                    .collect(),
+            ),
+            arities: RefCell::new(HashMap::new()),
             priv_counter: RefCell::new(0),
             is_pseudo: false,
+            errors: None,
+            resolve_external: None,
+            lists_only: false,
+            check_types: false,
+            strict: false,
+            aliases: aliases.clone(),
+            fn_id: None,
+            spans: None,
+            records_spans: false,
+            decl_programs: None,
         }
     }
 
-    fn create_child(&self) -> CompileContext {
+    /// Creates the context for the body of the function `fn_id`.
+    fn create_child(&self, fn_id: Id) -> CompileContext {
         CompileContext {
             parent: Some(self),
-            names: RefCell::new(HashSet::new()),
+            names: RefCell::new(HashMap::new()),
+            arities: RefCell::new(HashMap::new()),
             priv_counter: RefCell::new(0),
             is_pseudo: false,
+            errors: None,
+            resolve_external: None,
+            lists_only: false,
+            check_types: false,
+            strict: false,
+            aliases: HashMap::new(),
+            fn_id: Some(fn_id),
+            spans: None,
+            records_spans: self.records_spans,
+            decl_programs: None,
         }
     }
 
     fn create_pseudo_child(&self) -> CompileContext {
         CompileContext {
             parent: Some(self),
-            names: RefCell::new(HashSet::new()),
+            names: RefCell::new(HashMap::new()),
+            arities: RefCell::new(HashMap::new()),
             priv_counter: RefCell::new(0),
             is_pseudo: true,
+            errors: None,
+            resolve_external: None,
+            lists_only: false,
+            check_types: false,
+            strict: false,
+            aliases: HashMap::new(),
+            fn_id: None,
+            spans: None,
+            records_spans: self.records_spans,
+            decl_programs: None,
         }
     }
 
-    fn add_ident(&mut self, id: String) -> Result<Id, CompileError> {
-        let mut names = self.names.borrow_mut();
-        if names.contains(&id) {
-            return Err(CompileError::DupIdent(id));
+    /// Records the span of the definitions in this scope that don't have one yet.
+    fn record_spans<'b>(&self, ids: impl Iterator<Item = &'b Id>, span: &Range<usize>) {
+        let mut path = Vec::new();
+        let mut cursor = Some(self);
+        while let Some(ctx) = cursor {
+            if let Some(id) = &ctx.fn_id {
+                path.push(id.clone());
+            }
+            if let Some(spans) = &ctx.spans {
+                path.reverse();
+                let mut spans = spans.borrow_mut();
+                for id in ids {
+                    let mut key = path.clone();
+                    key.push(id.clone());
+                    spans.entry(key).or_insert_with(|| span.clone());
+                }
+                return;
+            }
+            cursor = ctx.parent;
         }
-        names.insert(id.clone());
-        if self.is_pseudo {
-            Ok(self.parent.unwrap().add_sub_ident(id))
-        } else {
-            Ok(id)
+    }
+
+    /// Reports a recoverable error. Returns it back if errors aren't being collected.
+    fn report(&self, err: CompileError) -> Result<(), CompileError> {
+        match (self.parent, &self.errors) {
+            (Some(parent), _) => parent.report(err),
+            (None, Some(errors)) => {
+                errors.borrow_mut().push(err);
+                Ok(())
+            }
+            (None, None) => Err(err),
         }
     }
 
+    /// Wraps an error in the top-level declaration with the given index in `InProgram`, if the
+    /// declarations are from several programs.
+    fn in_program(&self, decl: usize, err: CompileError) -> CompileError {
+        match &self.decl_programs {
+            Some(programs) => CompileError::InProgram(programs[decl], Box::new(err)),
+            None => err,
+        }
+    }
+
+    fn add_ident(&mut self, ident: &Ident) -> Result<Id, CompileError> {
+        let name = ident.name.clone();
+        if is_reserved_name(&name) {
+            self.report(CompileError::ReservedIdent(
+                name.clone(),
+                ident.span.clone(),
+            ))?;
+        } else if !is_valid_name(&name) {
+            self.report(CompileError::InvalidIdent(name.clone(), ident.span.clone()))?;
+        }
+        if let Some(id) = self.names.borrow().get(&name) {
+            self.report(CompileError::DupIdent(name, ident.span.clone()))?;
+            return Ok(id.clone());
+        }
+        let id = if self.is_pseudo {
+            self.parent.unwrap().add_sub_ident(name.clone())
+        } else if self.parent.is_some() && self.shadows_stdlib_id(&name) {
+            // references the compiler generates itself (e.g. to `index` for `xs[i]`) would
+            // resolve to the parameter otherwise
+            self.next_priv(&name)
+        } else {
+            name.clone()
+        };
+        self.names.borrow_mut().insert(name, id.clone());
+        Ok(id)
+    }
+
+    /// Returns true if a parameter with this id would shadow a standard library function.
+    fn shadows_stdlib_id(&self, id: &str) -> bool {
+        stdlib_names().any(|name| self.stdlib_id(name) == id)
+    }
+
+    /// Adds the name of a declaration, and records its number of parameters if it's a function.
+    fn add_decl(&mut self, decl: &Decl) -> Result<Id, CompileError> {
+        let id = self.add_ident(&decl.name)?;
+        let mut arities = self.arities.borrow_mut();
+        match decl_arity(decl) {
+            Some(arity) => arities.insert(decl.name.name.clone(), arity),
+            None => arities.remove(&decl.name.name),
+        };
+        Ok(id)
+    }
+
     fn add_sub_ident(&self, id: String) -> Id {
         if self.is_pseudo {
             self.parent.unwrap().add_sub_ident(id)
@@ -195,16 +554,128 @@ impl<'a> CompileContext<'a> {
         }
     }
 
-    fn resolve(&self, id: String) -> Result<Id, CompileError> {
-        if id.starts_with('@') {
-            Ok(id)
-        } else if self.names.borrow().contains(&id) {
-            Ok(id)
+    /// Checks whether an external name (without the `@`) exists.
+    fn external_exists(&self, name: &str) -> bool {
+        match (self.parent, self.resolve_external) {
+            (Some(parent), _) => parent.external_exists(name),
+            (None, Some(resolve_external)) => resolve_external(name),
+            (None, None) => true,
+        }
+    }
+
+    /// Returns true if list literals may be compiled to matrices.
+    fn allows_matrices(&self) -> bool {
+        match self.parent {
+            Some(parent) => parent.allows_matrices(),
+            None => !self.lists_only && !self.strict,
+        }
+    }
+
+    fn is_strict(&self) -> bool {
+        match self.parent {
+            Some(parent) => parent.is_strict(),
+            None => self.strict,
+        }
+    }
+
+    fn checks_types(&self) -> bool {
+        match self.parent {
+            Some(parent) => parent.checks_types(),
+            None => self.check_types,
+        }
+    }
+
+    /// Returns the id a standard library function is compiled to (its alias, if it has one), for
+    /// references the compiler generates itself. This is the id of the name at the top level, so
+    /// it's a declaration instead if one has the same name; parameters and bindings with the same
+    /// id are given private ids instead (see `add_ident`).
+    fn stdlib_id(&self, name: &str) -> Id {
+        match self.parent {
+            Some(parent) => parent.stdlib_id(name),
+            None => self
+                .names
+                .borrow()
+                .get(name)
+                .cloned()
+                .unwrap_or_else(|| name.into()),
+        }
+    }
+
+    /// Returns the alias of a name, if it has one.
+    fn alias(&self, name: &str) -> Option<Id> {
+        match self.parent {
+            Some(parent) => parent.alias(name),
+            None => self.aliases.get(name).cloned(),
+        }
+    }
+
+    /// Returns true if the name is defined in this scope or any parent scope.
+    fn is_defined(&self, name: &str) -> bool {
+        self.names.borrow().contains_key(name)
+            || matches!(self.parent, Some(parent) if parent.is_defined(name))
+    }
+
+    /// Returns the allowed number of arguments if the name refers to a stdlib function.
+    fn stdlib_arity(&self, name: &str) -> Option<RangeInclusive<usize>> {
+        if self.names.borrow().contains_key(name) {
+            if self.parent.is_some() {
+                // shadowed
+                return None;
+            }
+            return STDLIB
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, arity)| arity.clone());
+        }
+        self.parent.and_then(|parent| parent.stdlib_arity(name))
+    }
+
+    /// Returns the number of parameters if the name refers to a declared function.
+    fn fn_arity(&self, name: &str) -> Option<usize> {
+        if self.names.borrow().contains_key(name) {
+            return self.arities.borrow().get(name).copied();
+        }
+        self.parent.and_then(|parent| parent.fn_arity(name))
+    }
+
+    /// Returns the number of parameters if the name refers to a stdlib function or a declared
+    /// function.
+    fn arity(&self, name: &str) -> Option<usize> {
+        match self.stdlib_arity(name) {
+            Some(arity) => Some(*arity.start()),
+            None => self.fn_arity(name),
+        }
+    }
+
+    fn resolve(&self, ident: Ident) -> Result<Id, CompileError> {
+        if ident.name.starts_with('@') {
+            if !self.external_exists(&ident.name[1..]) {
+                self.report(CompileError::CantResolve(ident.name.clone(), ident.span))?;
+            }
+            Ok(ident.name)
+        } else if ident.name.starts_with('_') {
+            // generated ids are in `names` too, but can't be referenced
+            self.report(CompileError::CantResolve(ident.name.clone(), ident.span))?;
+            Ok(ident.name)
+        } else if let Some(id) = self.names.borrow().get(&ident.name) {
+            Ok(id.clone())
         } else {
-            self.parent
-                .map_or(Err(CompileError::CantResolve(id.to_string())), |parent| {
-                    parent.resolve(id)
-                })
+            match self.parent {
+                Some(parent) => parent.resolve(ident),
+                None => {
+                    if let Some(target) = self.aliases.get(&ident.name) {
+                        // the alias has to name a function, like any other reference
+                        if let Some(id) = self.names.borrow().get(target) {
+                            return Ok(id.clone());
+                        }
+                        self.report(CompileError::CantResolve(target.clone(), ident.span))?;
+                        return Ok(target.clone());
+                    }
+                    // keep the name as-is so compilation can continue
+                    self.report(CompileError::CantResolve(ident.name.clone(), ident.span))?;
+                    Ok(ident.name)
+                }
+            }
         }
     }
 
@@ -217,8 +688,8 @@ impl<'a> CompileContext<'a> {
 
         loop {
             let next = format!("_{}{}", priv_counter, suffix);
-            if !names.contains(&next) {
-                names.insert(next.clone());
+            if !names.contains_key(&next) {
+                names.insert(next.clone(), next.clone());
                 break next;
             }
             *priv_counter += 1;
@@ -226,234 +697,2772 @@ impl<'a> CompileContext<'a> {
     }
 }
 
-fn compile_expr<'a>(
-    out: String,
-    expr: Expr,
-    ctx: &mut CompileContext<'a>,
-) -> Result<Defs, CompileError> {
-    let mut defs = HashMap::new();
+/// Lists nested more deeply than this are never compiled to matrices, which keeps the recursion in
+/// `matrix` shallow (and the output readable by JSON parsers with a recursion limit).
+const MAX_MATRIX_DEPTH: usize = 32;
 
-    match expr {
-        Expr::Group(expr) => return compile_expr(out, *expr, ctx),
-        Expr::Ident(ident) => {
-            let name = ctx.resolve(ident.0)?;
-            defs.insert(
-                out,
-                Def::Call {
-                    f: name,
-                    args: Vec::new(),
-                },
-            );
+/// Returns the value of a number literal, which may be in parentheses or negated (if `negates`
+/// is set, i.e. `-` is the stdlib function).
+fn number_value(expr: &Expr, negates: bool) -> Option<f64> {
+    match &expr.kind {
+        ExprKind::Number(n, _) => Some(*n),
+        ExprKind::Group(inner) => number_value(inner, negates),
+        // -x is 0 - x, which is not the same for 0
+        ExprKind::Unary(Op::Infix(op), operand) if negates && op.name == "-" => {
+            number_value(operand, negates).map(|n| 0. - n)
         }
-        Expr::Let(decl, inner) => {
-            let mut sub_ctx = ctx.create_pseudo_child();
-            let ident = sub_ctx.add_ident(decl.name.0.clone())?;
-            defs.extend(compile_decl(ident, *decl, &mut sub_ctx)?);
-            defs.extend(compile_expr(out, *inner, &mut sub_ctx)?);
-        }
-        Expr::Apply(a, op, b) => match op {
-            Op::Apply => {
-                let mut flat_apply = vec![b]; // reversed
-
-                // flatten Apply(Apply(Apply(a b) b) b)
-                let mut cursor = a;
-                let left = loop {
-                    match *cursor {
-                        Expr::Apply(sa, Op::Apply, sb) => {
-                            flat_apply.push(sb);
-                            cursor = sa;
-                        }
-                        _ => break cursor,
-                    }
-                };
-
-                let left_id = match *left {
-                    Expr::Ident(ident) => ctx.resolve(ident.0)?,
-                    expr => {
-                        let out = ctx.next_priv("");
-                        defs.extend(compile_expr(out.clone(), expr, ctx)?);
-                        out
-                    }
-                };
-
-                let mut args = Vec::with_capacity(flat_apply.len());
-                for expr in flat_apply.into_iter().rev() {
-                    args.push(match *expr {
-                        Expr::Ident(ident) => ctx.resolve(ident.0)?,
-                        expr => {
-                            let out = ctx.next_priv("");
-                            defs.extend(compile_expr(out.clone(), expr, ctx)?);
-                            out
-                        }
-                    });
-                }
-
-                defs.insert(out, Def::Call { f: left_id, args });
-            }
-            Op::Infix(o) => {
-                return compile_expr(
-                    out,
-                    Expr::Apply(
-                        Box::new(Expr::Apply(Box::new(Expr::Ident(o)), Op::Apply, a)),
-                        Op::Apply,
-                        b,
-                    ),
-                    ctx,
-                );
-            }
-        },
-        Expr::List(items) => {
-            let mut is_all_bool = true;
-            let mut is_all_num = true;
-
-            for item in &items {
-                match item {
-                    Expr::Number(_) => is_all_bool = false,
-                    Expr::Bool(_) => is_all_num = false,
-                    _ => {
-                        is_all_num = false;
-                        is_all_bool = false;
-                    }
-                }
-            }
-
-            if is_all_num || is_all_bool {
-                let mut values: Vec<Value> = Vec::new();
-                for item in &items {
-                    match item {
-                        Expr::Number(n) => values.push(Value::Number(
-                            serde_json::Number::from_f64(*n).expect("invalid number in ast"),
-                        )),
-                        Expr::Bool(b) => values.push(Value::Bool(*b)),
-                        _ => panic!("invalid state"),
-                    }
-                }
+        _ => None,
+    }
+}
 
-                defs.insert(out, Def::Matrix { value: values });
-            } else {
-                let mut resolved_items = Vec::with_capacity(items.len());
-
-                for item in items {
-                    let resolved = match item {
-                        Expr::Ident(ident) => ctx.resolve(ident.0)?,
-                        expr => {
-                            let out = ctx.next_priv("");
-                            defs.extend(compile_expr(out.clone(), expr, ctx)?);
-                            out
-                        }
-                    };
-                    resolved_items.push(resolved);
-                }
+/// Returns the JSON value of an expression if it can be an item in a matrix. See `number_value`
+/// for `negates`.
+fn matrix_value(expr: &Expr, max_depth: usize, negates: bool) -> Option<Value> {
+    match &expr.kind {
+        // JSON can't represent non-finite numbers, so lists containing those will fall back to
+        // being a regular list
+        ExprKind::Number(..) | ExprKind::Group(_) | ExprKind::Unary(..) => {
+            number_value(expr, negates)
+                .and_then(serde_json::Number::from_f64)
+                .map(Value::Number)
+        }
+        ExprKind::Bool(b) => Some(Value::Bool(*b)),
+        // dates are strings at runtime
+        ExprKind::String(s) | ExprKind::Date(s) => Some(Value::String(s.clone())),
+        ExprKind::Null => Some(Value::Null),
+        ExprKind::List(items) => matrix(items, max_depth, negates).map(Value::Array),
+        _ => None,
+    }
+}
 
-                defs.insert(
-                    out,
-                    Def::List {
-                        items: resolved_items,
-                    },
-                );
+/// Returns the values of a list if it can be a matrix, i.e. if all items are literals of the same
+/// type, or lists of the same shape that can be matrices themselves. Lists nested more deeply than
+/// `max_depth` are not matrices.
+///
+/// Empty lists are never matrices (so `[]` is always an empty `Def::List`), since an empty matrix
+/// has no element type.
+fn matrix(items: &[Expr], max_depth: usize, negates: bool) -> Option<Vec<Value>> {
+    fn same_shape(a: &Value, b: &Value) -> bool {
+        match (a, b) {
+            (Value::Array(a), Value::Array(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same_shape(a, b))
             }
+            (a, b) => mem::discriminant(a) == mem::discriminant(b),
         }
-        Expr::If(c, t, e) => {
-            let mut cases = Vec::new();
-            let cond_out = ctx.next_priv("");
-            let then_out = ctx.next_priv("");
-            let else_out = ctx.next_priv("");
+    }
 
-            defs.extend(compile_expr(cond_out.clone(), *c, ctx)?);
-            defs.extend(compile_expr(then_out.clone(), *t, ctx)?);
-            defs.extend(compile_expr(else_out.clone(), *e, ctx)?);
+    if items.is_empty() {
+        return None;
+    }
+    let max_depth = max_depth.checked_sub(1)?;
+    let values = items
+        .iter()
+        .map(|item| matrix_value(item, max_depth, negates))
+        .collect::<Option<Vec<_>>>()?;
+    if values.windows(2).all(|pair| same_shape(&pair[0], &pair[1])) {
+        Some(values)
+    } else {
+        None
+    }
+}
 
-            cases.push(SwitchCase {
-                cond: Some(cond_out),
-                value: then_out,
-            });
+fn compile_expr<'a>(
+    out: String,
+    mut expr: Expr,
+    defs: &mut Defs,
+    ctx: &mut CompileContext<'a>,
+) -> Result<(), CompileError> {
+    while let ExprKind::Group(inner) = expr.kind {
+        expr = *inner;
+    }
 
-            cases.push(SwitchCase {
-                cond: None,
-                value: else_out,
-            });
+    if !ctx.records_spans {
+        return compile_expr_inner(out, expr, defs, ctx);
+    }
+    // the span is recorded for the definitions of this expression, so they're compiled separately
+    let span = expr.span.clone();
+    let mut expr_defs = Defs::new();
+    compile_expr_inner(out, expr, &mut expr_defs, ctx)?;
+    // spans of subexpressions were recorded first, so this only covers what they don't
+    ctx.record_spans(expr_defs.keys(), &span);
+    defs.append(&mut expr_defs);
+    Ok(())
+}
 
-            defs.insert(out, Def::Switch { cases });
+fn compile_expr_inner<'a>(
+    out: String,
+    expr: Expr,
+    defs: &mut Defs,
+    ctx: &mut CompileContext<'a>,
+) -> Result<(), CompileError> {
+    // each kind of expression is compiled in a separate function to keep stack frames small
+    match expr.kind {
+        ExprKind::Group(_) => unreachable!("groups were unwrapped above"),
+        ExprKind::Ident(ident) => compile_ident(out, ident, defs, ctx),
+        ExprKind::Let(decls, inner) | ExprKind::Where(inner, decls) => {
+            compile_let(out, decls, *inner, defs, ctx)
         }
-        Expr::Number(n) => {
-            defs.insert(out, Def::Number { value: n });
+        ExprKind::Apply(a, Op::Apply, b) => compile_apply(out, a, b, defs, ctx),
+        // `a |> f` is `f a`, unless `|>` has been defined
+        ExprKind::Apply(a, Op::Infix(o), b) if o.name == "|>" && !ctx.is_defined(&o.name) => {
+            compile_apply(out, b, a, defs, ctx)
         }
-        Expr::String(s) => {
-            defs.insert(out, Def::String { value: s });
+        ExprKind::Apply(a, Op::Infix(o), b) if o.name == "??" => {
+            compile_coalesce(out, *a, *b, defs, ctx)
         }
-        Expr::Bool(b) => {
-            defs.insert(out, Def::Bool { value: b });
+        // `a < b < c` is `a < b and b < c`
+        ExprKind::Apply(a, Op::Infix(o), b)
+            if is_chained_comparison(&o, ctx)
+                && matches!(&a.kind, ExprKind::Apply(_, Op::Infix(p), _) if is_chained_comparison(p, ctx)) =>
+        {
+            compile_comparisons(out, *a, o, *b, defs, ctx)
         }
-        Expr::Null => {
-            defs.insert(out, Def::Null);
+        ExprKind::Apply(a, Op::Infix(o), b) => {
+            // `a + b` is `(+) a b`
+            let span = a.span.start..o.span.end;
+            let o = Expr::new(ExprKind::Ident(o.clone()), o.span);
+            let f = Expr::new(ExprKind::Apply(Box::new(o), Op::Apply, a), span);
+            compile_apply(out, Box::new(f), b, defs, ctx)
         }
-        Expr::Lambda(lambda) => {
-            let mut lambda_ctx = ctx.create_child();
-            for param in &lambda.params {
-                lambda_ctx.add_ident(param.0.clone())?;
-            }
-            let body = compile_expr("=".into(), lambda.body, &mut lambda_ctx)?;
-            defs.insert(
-                out,
-                Def::Fn {
-                    params: lambda.params.into_iter().map(|p| p.0).collect(),
-                    body,
-                },
-            );
+        ExprKind::Unary(op, expr) => compile_unary(out, op, expr, defs, ctx),
+        ExprKind::List(items)
+            if items
+                .iter()
+                .any(|item| matches!(item.kind, ExprKind::Spread(_))) =>
+        {
+            compile_spread_list(out, items, defs, ctx)
         }
+        ExprKind::List(items) => compile_list(out, items, defs, ctx),
+        ExprKind::Spread(_) => unreachable!("spreads only appear in lists"),
+        ExprKind::Index(list, index) => compile_index(out, *list, *index, defs, ctx),
+        ExprKind::If(c, t, e) => compile_if(out, *c, *t, *e, expr.span, defs, ctx),
+        ExprKind::Match(arms) => compile_match(out, arms, expr.span, defs, ctx),
+        ExprKind::Lambda(lambda) => compile_lambda(out, *lambda, defs, ctx),
+        ExprKind::Number(n, _) => insert(defs, out, Def::Number { value: n }),
+        ExprKind::String(s) => insert(defs, out, Def::String { value: s }),
+        ExprKind::Bool(b) => insert(defs, out, Def::Bool { value: b }),
+        ExprKind::Null => insert(defs, out, Def::Null),
+        ExprKind::Date(date) => insert(defs, out, Def::String { value: date }),
+        ExprKind::DateTime(date_time) => compile_date_time(out, date_time, defs, ctx),
     }
+}
 
-    Ok(defs)
+/// Adds a definition (of an expression that doesn't need any others).
+fn insert(defs: &mut Defs, out: Id, def: Def) -> Result<(), CompileError> {
+    defs.insert(out, def);
+    Ok(())
 }
 
-fn compile_decl<'a>(
+fn single_def(out: Id, def: Def) -> Defs {
+    let mut defs = Defs::new();
+    defs.insert(out, def);
+    defs
+}
+
+/// Compiles an operand, i.e. resolves it if it's an identifier and compiles it into a new
+/// private definition otherwise. Returns the id of the operand.
+fn compile_operand<'a>(
+    expr: Expr,
+    defs: &mut Defs,
+    ctx: &mut CompileContext<'a>,
+) -> Result<Id, CompileError> {
+    match expr.kind {
+        ExprKind::Ident(ident) if ctx.stdlib_arity(&ident.name) != Some(0..=0) => {
+            ctx.resolve(ident)
+        }
+        kind => {
+            let out = ctx.next_priv("");
+            compile_expr(out.clone(), Expr::new(kind, expr.span), defs, ctx)?;
+            Ok(out)
+        }
+    }
+}
+
+/// Compiles a reference to a name. A call without arguments evaluates to the value of the name,
+/// which is also how stdlib definitions that take no arguments (like `ts_now`) are called.
+///
+/// Functions that take arguments can't be called without them, so a reference to a function is
+/// compiled to a function that passes its parameters on (e.g. `\a -> id a`), which makes it the
+/// same as passing the name as an argument (`map id xs`).
+fn compile_ident<'a>(
     out: Id,
-    decl: Decl,
+    ident: Ident,
+    defs: &mut Defs,
     ctx: &mut CompileContext<'a>,
-) -> Result<Defs, CompileError> {
-    if decl.params.is_empty() {
-        // constant
-        let mut decl_ctx = ctx.create_pseudo_child();
-        compile_expr(out, decl.body, &mut decl_ctx)
-    } else {
-        // function
-        let mut decl_ctx = ctx.create_child();
+) -> Result<(), CompileError> {
+    let arity = ctx.arity(&ident.name);
+    let name = ctx.resolve(ident)?;
+    let def = match arity {
+        Some(arity) if arity > 0 => partial_call(name, Vec::new(), arity, ctx),
+        _ => Def::Call {
+            f: name,
+            args: Vec::new(),
+        },
+    };
+    insert(defs, out, def)
+}
+
+/// Returns a function that takes the arguments missing from a call with fewer than `arity`
+/// arguments and then makes the call, e.g. `\b -> f a b` for `f a`. The given arguments are
+/// evaluated outside of it.
+fn partial_call(f: Id, mut args: Vec<Id>, arity: usize, ctx: &CompileContext) -> Def {
+    // ids from the enclosing scope, so that they can't shadow the function or the arguments
+    let params: Vec<_> = (args.len()..arity).map(|_| ctx.next_priv("")).collect();
+    args.extend(params.iter().cloned());
+    Def::Fn {
+        params,
+        body: single_def("=".into(), Def::Call { f, args }),
+    }
+}
+
+fn compile_let<'a>(
+    out: Id,
+    decls: Vec<Decl>,
+    inner: Expr,
+    defs: &mut Defs,
+    ctx: &mut CompileContext<'a>,
+) -> Result<(), CompileError> {
+    let mut sub_ctx = ctx.create_pseudo_child();
+    // add all names first so that the declarations can reference each other
+    let mut ids = Vec::with_capacity(decls.len());
+    for decl in &decls {
+        ids.push(sub_ctx.add_decl(decl)?);
+    }
+    for (id, decl) in ids.into_iter().zip(decls) {
+        compile_decl(id, decl, defs, &mut sub_ctx)?;
+    }
+    compile_expr(out, inner, defs, &mut sub_ctx)
+}
+
+/// Compiles the application `a b`.
+fn compile_apply<'a>(
+    out: Id,
+    a: Box<Expr>,
+    b: Box<Expr>,
+    defs: &mut Defs,
+    ctx: &mut CompileContext<'a>,
+) -> Result<(), CompileError> {
+    let mut flat_apply = vec![b]; // reversed
 
-        // FIXME: we’re not using the assigned id from these
-        for param in &decl.params {
-            decl_ctx.add_ident(param.0.clone())?;
+    // flatten Apply(Apply(Apply(a b) b) b)
+    let mut cursor = a;
+    let mut left = loop {
+        match cursor.kind {
+            ExprKind::Apply(sa, Op::Apply, sb) => {
+                flat_apply.push(sb);
+                cursor = sa;
+            }
+            kind => break Expr::new(kind, cursor.span),
         }
+    };
 
-        let body = compile_expr("=".into(), decl.body, &mut decl_ctx)?;
+    // an alias of a stdlib function (like `%` for `mod`) is that function, so that its arguments
+    // are checked, unless it has been defined
+    if let ExprKind::Ident(ident) = &mut left.kind {
+        if !ctx.is_defined(&ident.name) {
+            if let Some(name) = ctx.alias(&ident.name).filter(|name| is_stdlib(name)) {
+                ident.name = name;
+            }
+        }
+    }
 
-        let mut defs = HashMap::new();
-        defs.insert(
-            out,
-            Def::Fn {
-                params: decl.params.into_iter().map(|p| p.0).collect(),
-                body,
-            },
-        );
-        Ok(defs)
+    // the number of parameters, if there are fewer arguments
+    let mut partial = None;
+    if let ExprKind::Ident(ident) = &left.kind {
+        if let Some(arity) = ctx.stdlib_arity(&ident.name) {
+            if flat_apply.len() > *arity.end() {
+                ctx.report(CompileError::Arity(
+                    ident.name.clone(),
+                    arity.clone(),
+                    flat_apply.len(),
+                    ident.span.clone(),
+                ))?;
+            } else if ctx.checks_types() {
+                check_arg_types(ident, flat_apply.iter().rev().map(|arg| &**arg), ctx)?;
+            }
+        }
+        partial = ctx
+            .arity(&ident.name)
+            .filter(|arity| flat_apply.len() < *arity);
     }
-}
 
-fn compile_prog<'a>(prog: Program, ctx: &mut CompileContext<'a>) -> Result<Defs, CompileError> {
-    let Program(prog) = prog;
+    let left_id = compile_operand(left, defs, ctx)?;
 
-    for decl in &prog {
-        ctx.add_ident(decl.name.0.clone())?;
+    let mut args = Vec::with_capacity(flat_apply.len());
+    for expr in flat_apply.into_iter().rev() {
+        args.push(compile_operand(*expr, defs, ctx)?);
     }
 
-    let mut defs = HashMap::new();
+    let def = match partial {
+        Some(arity) => partial_call(left_id, args, arity, ctx),
+        None => Def::Call { f: left_id, args },
+    };
+    insert(defs, out, def)
+}
 
-    for decl in prog {
-        defs.extend(compile_decl(decl.name.0.clone(), decl, ctx)?);
+/// Reports arguments of a stdlib call whose type is known and not accepted by the function.
+fn check_arg_types<'e>(
+    f: &Ident,
+    args: impl Iterator<Item = &'e Expr>,
+    ctx: &CompileContext,
+) -> Result<(), CompileError> {
+    let sig = match types::signature(&f.name) {
+        Some(sig) => sig,
+        None => return Ok(()),
+    };
+    let is_stdlib = |name: &str| ctx.stdlib_arity(name).is_some();
+    for (i, (arg, accepted)) in args.zip(sig.params).enumerate() {
+        let actual = match types::expr_type(arg, &is_stdlib) {
+            Some(actual) => actual,
+            None => continue,
+        };
+        if !accepted.is_empty() && !accepted.contains(&actual) {
+            ctx.report(CompileError::TypeMismatch(
+                f.name.clone(),
+                i + 1,
+                accepted.to_vec(),
+                actual,
+                arg.span.clone(),
+            ))?;
+        }
     }
+    Ok(())
+}
 
-    Ok(defs)
+/// Compiles a date-time literal, which is parsed from a string at runtime since timestamps have no
+/// literal representation.
+fn compile_date_time<'a>(
+    out: Id,
+    date_time: String,
+    defs: &mut Defs,
+    ctx: &mut CompileContext<'a>,
+) -> Result<(), CompileError> {
+    let string = ctx.next_priv("");
+    defs.insert(string.clone(), Def::String { value: date_time });
+    insert(
+        defs,
+        out,
+        Def::Call {
+            f: ctx.stdlib_id("ts_parse"),
+            args: vec![string],
+        },
+    )
 }
 
-pub fn compile(prog: Program) -> Result<Defs, CompileError> {
-    compile_prog(prog, &mut CompileContext::global())
+/// Returns true if the operator is a comparison that can be chained, i.e. one of `<`, `<=`, `>`
+/// and `>=` that refers to the stdlib.
+fn is_chained_comparison(op: &Ident, ctx: &CompileContext) -> bool {
+    ["<", "<=", ">", ">="].contains(&&*op.name) && ctx.stdlib_arity(&op.name).is_some()
+}
+
+/// Compiles a chain of comparisons `a op b op c`, which is `a op b and b op c` but with `b` only
+/// compiled once. `a` is the rest of the chain, and `op` is its last operator.
+fn compile_comparisons<'a>(
+    out: Id,
+    a: Expr,
+    op: Ident,
+    c: Expr,
+    defs: &mut Defs,
+    ctx: &mut CompileContext<'a>,
+) -> Result<(), CompileError> {
+    let mut operands = vec![c];
+    let mut ops = vec![op];
+    let mut cursor = a;
+    loop {
+        match cursor.kind {
+            ExprKind::Apply(a, Op::Infix(op), b) if is_chained_comparison(&op, ctx) => {
+                operands.push(*b);
+                ops.push(op);
+                cursor = *a;
+            }
+            kind => {
+                operands.push(Expr::new(kind, cursor.span));
+                break;
+            }
+        }
+    }
+
+    let mut ids = Vec::with_capacity(operands.len());
+    for operand in operands.into_iter().rev() {
+        ids.push(compile_operand(operand, defs, ctx)?);
+    }
+
+    let last = ops.len() - 1;
+    let mut conjunction: Option<Id> = None;
+    for (i, op) in ops.into_iter().rev().enumerate() {
+        let f = ctx.resolve(op)?;
+        let comparison = ctx.next_priv("");
+        defs.insert(
+            comparison.clone(),
+            Def::Call {
+                f,
+                args: vec![ids[i].clone(), ids[i + 1].clone()],
+            },
+        );
+        conjunction = Some(match conjunction {
+            None => comparison,
+            Some(prev) => {
+                // chains have at least two comparisons, so the last conjunction is the output
+                let id = if i == last {
+                    out.clone()
+                } else {
+                    ctx.next_priv("")
+                };
+                defs.insert(
+                    id.clone(),
+                    Def::Call {
+                        f: ctx.stdlib_id("and"),
+                        args: vec![prev, comparison],
+                    },
+                );
+                id
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Compiles `xs[i]`, which is `index xs i` if `i` isn't negative, and `index xs (length xs + i)`
+/// otherwise.
+fn compile_index<'a>(
+    out: Id,
+    list: Expr,
+    index: Expr,
+    defs: &mut Defs,
+    ctx: &mut CompileContext<'a>,
+) -> Result<(), CompileError> {
+    // `list` is used twice, but only compiled once
+    let list = compile_operand(list, defs, ctx)?;
+    let index = match index.kind {
+        ExprKind::Number(n, _) if n < 0. => {
+            let length = ctx.next_priv("");
+            defs.insert(
+                length.clone(),
+                Def::Call {
+                    f: ctx.stdlib_id("length"),
+                    args: vec![list.clone()],
+                },
+            );
+            let offset = ctx.next_priv("");
+            defs.insert(offset.clone(), Def::Number { value: -n });
+            let index = ctx.next_priv("");
+            defs.insert(
+                index.clone(),
+                Def::Call {
+                    f: ctx.stdlib_id("-"),
+                    args: vec![length, offset],
+                },
+            );
+            index
+        }
+        ExprKind::Number(..) => compile_operand(index, defs, ctx)?,
+        // otherwise, whether it counts from the end is only known at runtime
+        _ => {
+            let index = compile_operand(index, defs, ctx)?;
+            let zero = ctx.next_priv("");
+            defs.insert(zero.clone(), Def::Number { value: 0. });
+            let is_negative = ctx.next_priv("");
+            defs.insert(
+                is_negative.clone(),
+                Def::Call {
+                    f: ctx.stdlib_id("<"),
+                    args: vec![index.clone(), zero],
+                },
+            );
+            let length = ctx.next_priv("");
+            defs.insert(
+                length.clone(),
+                Def::Call {
+                    f: ctx.stdlib_id("length"),
+                    args: vec![list.clone()],
+                },
+            );
+            let from_end = ctx.next_priv("");
+            defs.insert(
+                from_end.clone(),
+                Def::Call {
+                    f: ctx.stdlib_id("+"),
+                    args: vec![length, index.clone()],
+                },
+            );
+            let cases = vec![
+                SwitchCase {
+                    cond: Some(is_negative),
+                    value: from_end,
+                },
+                SwitchCase {
+                    cond: None,
+                    value: index,
+                },
+            ];
+            let switch = ctx.next_priv("");
+            defs.insert(switch.clone(), Def::Switch { cases });
+            switch
+        }
+    };
+    insert(
+        defs,
+        out,
+        Def::Call {
+            f: ctx.stdlib_id("index"),
+            args: vec![list, index],
+        },
+    )
+}
+
+/// Compiles `a ?? b`, which is `a` unless it's null, and `b` otherwise.
+fn compile_coalesce<'a>(
+    out: Id,
+    a: Expr,
+    b: Expr,
+    defs: &mut Defs,
+    ctx: &mut CompileContext<'a>,
+) -> Result<(), CompileError> {
+    // `a` is used twice, but only compiled once
+    let a = compile_operand(a, defs, ctx)?;
+    let null = ctx.next_priv("");
+    defs.insert(null.clone(), Def::Null);
+    let is_null = ctx.next_priv("");
+    defs.insert(
+        is_null.clone(),
+        Def::Call {
+            f: ctx.stdlib_id("=="),
+            args: vec![a.clone(), null],
+        },
+    );
+    let b = compile_operand(b, defs, ctx)?;
+
+    let cases = vec![
+        SwitchCase {
+            cond: Some(is_null),
+            value: b,
+        },
+        SwitchCase {
+            cond: None,
+            value: a,
+        },
+    ];
+    insert(defs, out, Def::Switch { cases })
+}
+
+fn compile_unary<'a>(
+    out: Id,
+    op: Op,
+    expr: Box<Expr>,
+    defs: &mut Defs,
+    ctx: &mut CompileContext<'a>,
+) -> Result<(), CompileError> {
+    let ident = |name: &str, span: Range<usize>| {
+        Box::new(Expr::new(
+            ExprKind::Ident(Ident::new(name, span.clone())),
+            span,
+        ))
+    };
+    match op {
+        // -x is 0 - x
+        Op::Infix(Ident { name, span }) if name == "-" => {
+            let zero = Expr::new(ExprKind::Number(0., None), span.clone());
+            let f = ExprKind::Apply(ident("-", span.clone()), Op::Apply, Box::new(zero));
+            compile_apply(out, Box::new(Expr::new(f, span)), expr, defs, ctx)
+        }
+        // the parser only allows - and !, so this is !x, i.e. not x
+        Op::Infix(Ident { span, .. }) => compile_apply(out, ident("not", span), expr, defs, ctx),
+        Op::Apply => unreachable!("application is not a prefix operator"),
+    }
+}
+
+fn compile_list<'a>(
+    out: Id,
+    items: Vec<Expr>,
+    defs: &mut Defs,
+    ctx: &mut CompileContext<'a>,
+) -> Result<(), CompileError> {
+    if ctx.is_strict() {
+        check_item_types(&items, ctx)?;
+    }
+    if ctx.allows_matrices() {
+        let negates = ctx.stdlib_arity("-").is_some();
+        if let Some(values) = matrix(&items, MAX_MATRIX_DEPTH, negates) {
+            return insert(defs, out, Def::Matrix { value: values });
+        }
+    }
+
+    let mut resolved_items = Vec::with_capacity(items.len());
+    for item in items {
+        resolved_items.push(compile_operand(item, defs, ctx)?);
+    }
+
+    insert(
+        defs,
+        out,
+        Def::List {
+            items: resolved_items,
+        },
+    )
+}
+
+/// Reports the first item of a list whose type is known and different from that of the others.
+/// Null can be an item of any list.
+fn check_item_types(items: &[Expr], ctx: &CompileContext) -> Result<(), CompileError> {
+    let is_stdlib = |name: &str| ctx.stdlib_arity(name).is_some();
+    let mut types = items
+        .iter()
+        .filter_map(|item| Some((item, types::expr_type(item, &is_stdlib)?)))
+        .filter(|(_, ty)| *ty != Type::Null);
+    let first = match types.next() {
+        Some((_, first)) => first,
+        None => return Ok(()),
+    };
+    if let Some((item, other)) = types.find(|(_, other)| *other != first) {
+        ctx.report(CompileError::MixedList(first, other, item.span.clone()))?;
+    }
+    Ok(())
+}
+
+/// Returns a list of items from a list with spreads, spanning from the first to the last.
+fn items_list(items: Vec<Expr>) -> Expr {
+    let span = items[0].span.start..items[items.len() - 1].span.end;
+    Expr::new(ExprKind::List(items), span)
+}
+
+/// Compiles a list with spreads (`[...xs, 1, 2, ...ys]`) by concatenating the spread lists and
+/// lists of the items between them (`xs ++ [1, 2] ++ ys`).
+fn compile_spread_list<'a>(
+    out: Id,
+    items: Vec<Expr>,
+    defs: &mut Defs,
+    ctx: &mut CompileContext<'a>,
+) -> Result<(), CompileError> {
+    let mut parts = Vec::new();
+    let mut run = Vec::new();
+    for item in items {
+        match item.kind {
+            ExprKind::Spread(expr) => {
+                if !run.is_empty() {
+                    parts.push(items_list(mem::take(&mut run)));
+                }
+                parts.push(*expr);
+            }
+            kind => run.push(Expr::new(kind, item.span)),
+        }
+    }
+    if !run.is_empty() {
+        parts.push(items_list(run));
+    }
+
+    let mut parts = parts.into_iter();
+    let first = parts.next().expect("lists with spreads are not empty");
+    if parts.len() == 0 {
+        // `[...xs]` is just `xs`
+        return compile_expr(out, first, defs, ctx);
+    }
+    let mut list = compile_operand(first, defs, ctx)?;
+    while let Some(part) = parts.next() {
+        let part = compile_operand(part, defs, ctx)?;
+        let id = if parts.len() == 0 {
+            out.clone()
+        } else {
+            ctx.next_priv("")
+        };
+        defs.insert(
+            id.clone(),
+            Def::Call {
+                f: ctx.stdlib_id("++"),
+                args: vec![list, part],
+            },
+        );
+        list = id;
+    }
+    Ok(())
+}
+
+fn compile_if<'a>(
+    out: Id,
+    c: Expr,
+    t: Expr,
+    e: Expr,
+    span: Range<usize>,
+    defs: &mut Defs,
+    ctx: &mut CompileContext<'a>,
+) -> Result<(), CompileError> {
+    // flatten `else if` chains into a single switch
+    let mut arms = vec![(Some(c), t)];
+    let mut else_expr = e;
+    loop {
+        match else_expr.kind {
+            ExprKind::If(c, t, e) => {
+                arms.push((Some(*c), *t));
+                else_expr = *e;
+            }
+            ExprKind::Group(inner) if matches!(inner.kind, ExprKind::If(..)) => else_expr = *inner,
+            kind => {
+                else_expr = Expr::new(kind, else_expr.span);
+                break;
+            }
+        }
+    }
+    arms.push((None, else_expr));
+    compile_match(out, arms, span, defs, ctx)
+}
+
+/// Compiles the arms of a `cond` (or flattened `if`) expression at `span`.
+fn compile_match<'a>(
+    out: Id,
+    arms: Vec<(Option<Expr>, Expr)>,
+    span: Range<usize>,
+    defs: &mut Defs,
+    ctx: &mut CompileContext<'a>,
+) -> Result<(), CompileError> {
+    // the parser checks this too, but the AST may have been constructed by hand
+    let is_valid = match arms.split_last() {
+        Some(((None, _), rest)) => rest.iter().all(|(cond, _)| cond.is_some()),
+        _ => false,
+    };
+    if !is_valid {
+        let error = SyntaxError::MatchDefault(span);
+        return Err(CompileError::Parse(ParseError::User { error }));
+    }
+
+    let mut cases = Vec::new();
+    for (cond, value) in arms {
+        let cond_out = match cond {
+            Some(cond) => {
+                let cond_out = ctx.next_priv("");
+                compile_expr(cond_out.clone(), cond, defs, ctx)?;
+                Some(cond_out)
+            }
+            None => None,
+        };
+        let value_out = ctx.next_priv("");
+        compile_expr(value_out.clone(), value, defs, ctx)?;
+        cases.push(SwitchCase {
+            cond: cond_out,
+            value: value_out,
+        });
+    }
+
+    insert(defs, out, Def::Switch { cases })
+}
+
+fn compile_lambda<'a>(
+    out: Id,
+    lambda: Lambda,
+    defs: &mut Defs,
+    ctx: &mut CompileContext<'a>,
+) -> Result<(), CompileError> {
+    let mut lambda_ctx = ctx.create_child(out.clone());
+    let params = add_params(&lambda.params, &mut lambda_ctx)?;
+    let mut body = Defs::new();
+    compile_expr("=".into(), lambda.body, &mut body, &mut lambda_ctx)?;
+    insert(defs, out, Def::Fn { params, body })
+}
+
+/// Returns the number of parameters of a declaration, if it has any or its body is a lambda.
+fn decl_arity(decl: &Decl) -> Option<usize> {
+    if !decl.params.is_empty() {
+        return Some(decl.params.len());
+    }
+    let mut body = &decl.body;
+    while let ExprKind::Group(inner) = &body.kind {
+        body = inner;
+    }
+    match &body.kind {
+        ExprKind::Lambda(lambda) => Some(lambda.params.len()),
+        _ => None,
+    }
+}
+
+/// Declares a function's parameters in its (new) context. A parameter with the same name as an
+/// earlier one is a `DupIdent` error at the later one, rather than the two being merged.
+fn add_params(params: &[Ident], ctx: &mut CompileContext) -> Result<Vec<Id>, CompileError> {
+    debug_assert!(
+        ctx.names.borrow().is_empty(),
+        "parameters must be declared first"
+    );
+    let mut ids = Vec::with_capacity(params.len());
+    for param in params {
+        ids.push(ctx.add_ident(param)?);
+    }
+    Ok(ids)
+}
+
+fn compile_decl<'a>(
+    out: Id,
+    decl: Decl,
+    defs: &mut Defs,
+    ctx: &mut CompileContext<'a>,
+) -> Result<(), CompileError> {
+    if !ctx.records_spans {
+        return compile_decl_inner(out, decl, defs, ctx);
+    }
+    let span = decl.span.clone();
+    let mut decl_defs = Defs::new();
+    compile_decl_inner(out, decl, &mut decl_defs, ctx)?;
+    ctx.record_spans(decl_defs.keys(), &span);
+    defs.append(&mut decl_defs);
+    Ok(())
+}
+
+fn compile_decl_inner<'a>(
+    out: Id,
+    decl: Decl,
+    defs: &mut Defs,
+    ctx: &mut CompileContext<'a>,
+) -> Result<(), CompileError> {
+    if decl.params.is_empty() {
+        // constant
+        let mut decl_ctx = ctx.create_pseudo_child();
+        compile_expr(out, decl.body, defs, &mut decl_ctx)
+    } else {
+        // function
+        let mut decl_ctx = ctx.create_child(out.clone());
+        let params = add_params(&decl.params, &mut decl_ctx)?;
+
+        let mut body = Defs::new();
+        compile_expr("=".into(), decl.body, &mut body, &mut decl_ctx)?;
+        insert(defs, out, Def::Fn { params, body })
+    }
+}
+
+fn compile_prog<'a>(prog: Program, ctx: &mut CompileContext<'a>) -> Result<Defs, CompileError> {
+    let Program(prog) = prog;
+
+    for (i, decl) in prog.iter().enumerate() {
+        ctx.add_decl(decl).map_err(|err| ctx.in_program(i, err))?;
+    }
+
+    let mut defs = Defs::new();
+
+    for (i, decl) in prog.into_iter().enumerate() {
+        compile_decl(decl.name.name.clone(), decl, &mut defs, ctx)
+            .map_err(|err| ctx.in_program(i, err))?;
+    }
+
+    Ok(defs)
+}
+
+/// Returns how deeply `compile_expr` will recurse to compile an expression, or `None` if that is
+/// more than `max_depth`.
+///
+/// This uses an explicit stack because the expression itself may be nested too deeply for
+/// recursion, and it's checked before compiling so that nothing deeply nested has to be dropped
+/// while deep in the compiler's recursion either.
+fn expr_depth(expr: &Expr, max_depth: usize) -> Option<usize> {
+    let mut max = 0;
+    let mut stack = vec![(expr, 1)];
+    while let Some((expr, depth)) = stack.pop() {
+        if depth > max_depth {
+            return None;
+        }
+        max = max.max(depth);
+        match &expr.kind {
+            ExprKind::Group(expr) => stack.push((expr, depth)),
+            ExprKind::Let(decls, inner) | ExprKind::Where(inner, decls) => {
+                stack.extend(decls.iter().map(|decl| (&decl.body, depth + 1)));
+                stack.push((inner, depth + 1));
+            }
+            ExprKind::Apply(a, op, b) => {
+                // applications are flattened into a single call
+                let a_depth = match (op, &a.kind) {
+                    (Op::Apply, ExprKind::Apply(_, Op::Apply, _)) => depth,
+                    _ => depth + 1,
+                };
+                stack.push((a, a_depth));
+                stack.push((b, depth + 1));
+            }
+            ExprKind::Unary(_, expr) => stack.push((expr, depth + 1)),
+            ExprKind::List(items) => stack.extend(items.iter().map(|item| (item, depth + 1))),
+            // the spread expression is an operand of `++`
+            ExprKind::Spread(expr) => stack.push((expr, depth)),
+            ExprKind::Index(list, index) => {
+                stack.push((list, depth + 1));
+                stack.push((index, depth + 1));
+            }
+            ExprKind::If(c, t, e) => {
+                // else-if chains are flattened into a single switch
+                let e_depth = match &e.kind {
+                    ExprKind::If(..) => depth,
+                    ExprKind::Group(inner) if matches!(inner.kind, ExprKind::If(..)) => depth,
+                    _ => depth + 1,
+                };
+                stack.push((c, depth + 1));
+                stack.push((t, depth + 1));
+                stack.push((e, e_depth));
+            }
+            ExprKind::Match(arms) => {
+                for (cond, value) in arms {
+                    stack.extend(cond.iter().map(|cond| (cond, depth + 1)));
+                    stack.push((value, depth + 1));
+                }
+            }
+            ExprKind::Lambda(lambda) => stack.push((&lambda.body, depth + 1)),
+            ExprKind::Ident(_)
+            | ExprKind::Number(..)
+            | ExprKind::String(_)
+            | ExprKind::Bool(_)
+            | ExprKind::Null
+            | ExprKind::Date(_)
+            | ExprKind::DateTime(_) => (),
+        }
+    }
+    Some(max)
+}
+
+/// Checks that no declaration in the program is nested more deeply than `max_depth`. If one is,
+/// the program is dropped without recursion, since it may be too deep for that.
+fn check_depth(prog: Program, max_depth: usize) -> Result<Program, CompileError> {
+    if prog
+        .0
+        .iter()
+        .any(|decl| expr_depth(&decl.body, max_depth).is_none())
+    {
+        drop_nested(prog.0.into_iter().map(|decl| decl.body));
+        return Err(CompileError::TooDeep(max_depth));
+    }
+    Ok(prog)
+}
+
+/// Finds a cycle of definitions that depend on each other. Function bodies are checked
+/// separately, since references to the outer scope can't form a cycle with their contents.
+fn find_cycle(defs: &Defs) -> Option<Vec<Id>> {
+    #[derive(PartialEq)]
+    enum State {
+        Visiting,
+        Done,
+    }
+
+    let mut ids: Vec<_> = defs.keys().collect();
+    ids.sort();
+
+    let mut states = HashMap::new();
+    for id in &ids {
+        if states.contains_key(id) {
+            continue;
+        }
+
+        // DFS with an explicit stack, since expression chains can be very deep
+        states.insert(*id, State::Visiting);
+        let mut stack = vec![(*id, defs[*id].refs().into_iter())];
+        while let Some((id, refs)) = stack.last_mut() {
+            let next = match refs.next() {
+                Some(next) => next,
+                None => {
+                    states.insert(*id, State::Done);
+                    stack.pop();
+                    continue;
+                }
+            };
+            if !defs.contains_key(next) {
+                // defined in an outer scope
+                continue;
+            }
+            match states.get(next) {
+                Some(State::Visiting) => {
+                    let start = stack.iter().position(|(id, _)| *id == next).unwrap();
+                    return Some(
+                        stack[start..]
+                            .iter()
+                            .map(|(id, _)| id.to_string())
+                            .collect(),
+                    );
+                }
+                Some(State::Done) => (),
+                None => {
+                    states.insert(next, State::Visiting);
+                    stack.push((next, defs[next].refs().into_iter()));
+                }
+            }
+        }
+    }
+
+    ids.into_iter().find_map(|id| match &defs[id] {
+        Def::Fn { body, .. } => find_cycle(body),
+        _ => None,
+    })
+}
+
+/// Options for `compile_with_options`.
+pub struct CompileOptions<'a> {
+    /// Additional built-in names that can be referenced just like the stdlib.
+    pub extra_stdlib: HashSet<Id>,
+    /// If set, this is called with the name of every external reference (`@name`, passed without
+    /// the `@`) and should return whether it exists. Otherwise, all external references are valid.
+    pub resolve_external: Option<&'a dyn Fn(&str) -> bool>,
+    /// How deeply expressions may be nested before compilation fails with `TooDeep`. If not set,
+    /// this is `DEFAULT_MAX_DEPTH`.
+    pub max_depth: Option<usize>,
+    /// If true, list literals are always compiled to `Def::List`, never `Def::Matrix`, for runtimes
+    /// that don't implement matrices.
+    pub lists_only: bool,
+    /// If true, calls to standard library functions fail with `TypeMismatch` if an argument's type
+    /// is obviously wrong, i.e. the function would ignore its arguments and return null or false
+    /// (e.g. `1 + "a"`). Only literals and the results of other stdlib calls have a known type,
+    /// so this catches few mistakes.
+    pub check_types: bool,
+    /// What happens if a name is declared more than once at the top level.
+    pub redefine: RedefinePolicy,
+    /// If true, the same list literal always compiles to the same kind of definition, and its
+    /// items have to be of the same type: lists are always `Def::List` (as with `lists_only`), and
+    /// a list with items whose types are obviously different (e.g. `[1, "a"]`) fails with
+    /// `MixedList`. Items whose type isn't known (see `check_types`) and null can be anything.
+    /// `compile_with_diagnostics` then also parses with `parse_strict`.
+    pub strict: bool,
+    /// Maps names (or operators) in the source to the ids they're compiled to, for runtimes that
+    /// spell built-in functions differently (e.g. `++` to `concat`). A standard library name is
+    /// always compiled to its alias, including where the compiler uses it itself (e.g. `++` for
+    /// `[...xs]`). Any other name is compiled to its alias unless it's defined, and the alias has
+    /// to be a standard library name or a declaration, which the name then refers to (e.g. `%`,
+    /// which is `mod` by default).
+    pub aliases: HashMap<String, Id>,
+}
+
+impl Default for CompileOptions<'_> {
+    fn default() -> Self {
+        CompileOptions {
+            extra_stdlib: HashSet::new(),
+            resolve_external: None,
+            max_depth: None,
+            lists_only: false,
+            check_types: false,
+            redefine: RedefinePolicy::default(),
+            strict: false,
+            aliases: default_aliases(),
+        }
+    }
+}
+
+/// Returns the aliases of `CompileOptions::default()`: `%` is `mod`.
+pub fn default_aliases() -> HashMap<String, Id> {
+    iter::once(("%".to_string(), "mod".to_string())).collect()
+}
+
+/// How `compile_with_options` handles top-level declarations of the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RedefinePolicy {
+    /// Declaring a name again is a `DupIdent` error.
+    #[default]
+    Error,
+    /// The last declaration of a name is compiled, and earlier ones are ignored (e.g. for generated
+    /// code that overrides earlier definitions). This doesn't apply to `let` and `where` bindings.
+    LastWins,
+}
+
+pub fn compile(prog: Program) -> Result<Defs, CompileError> {
+    compile_with_options(prog, &CompileOptions::default())
+}
+
+/// Compiles several programs (e.g. from different files) together, as if they were one: they can
+/// reference each other's declarations, and declaring the same name in two of them is a
+/// `DupIdent` error. An error in a declaration is wrapped in `InProgram` with the index of the
+/// program it's in, since its span is relative to that program's source.
+pub fn compile_many(progs: Vec<Program>) -> Result<Defs, CompileError> {
+    let options = CompileOptions::default();
+    let mut ctx = CompileContext::global(&options.extra_stdlib, &options.aliases);
+    ctx.decl_programs = Some(
+        progs
+            .iter()
+            .enumerate()
+            .flat_map(|(i, prog)| prog.0.iter().map(move |_| i))
+            .collect(),
+    );
+    let decls = progs.into_iter().flat_map(|prog| prog.0).collect();
+    compile_in(Program(decls), &options, &mut ctx)
+}
+
+/// Like `compile`, but returns the definitions as a JSON tree, as they would be serialized.
+pub fn compile_to_value(prog: Program) -> Result<Value, CompileError> {
+    let defs = compile(prog)?;
+    // ids are strings and non-finite numbers become null, so this can't fail
+    Ok(serde_json::to_value(defs).expect("failed to convert definitions to JSON"))
+}
+
+/// Like `compile`, but also accepts references to the given names, e.g. for built-ins that only
+/// exist in some runtimes.
+pub fn compile_with_stdlib(prog: Program, extra: &HashSet<Id>) -> Result<Defs, CompileError> {
+    let options = CompileOptions {
+        extra_stdlib: extra.clone(),
+        ..CompileOptions::default()
+    };
+    compile_with_options(prog, &options)
+}
+
+/// Compiles a program like `compile`, with the given options.
+pub fn compile_with_options(prog: Program, options: &CompileOptions) -> Result<Defs, CompileError> {
+    let mut ctx = CompileContext::global(&options.extra_stdlib, &options.aliases);
+    compile_in(prog, options, &mut ctx)
+}
+
+/// Like `compile_with_options`, but also returns the source span of every definition (including
+/// those in function bodies): that of the innermost expression or declaration that produced it.
+pub fn compile_with_source_map(
+    prog: Program,
+    options: &CompileOptions,
+) -> Result<(Defs, SourceMap), CompileError> {
+    let mut ctx = CompileContext::global(&options.extra_stdlib, &options.aliases);
+    ctx.spans = Some(RefCell::new(SourceMap::new()));
+    ctx.records_spans = true;
+    let defs = compile_in(prog, options, &mut ctx)?;
+    Ok((defs, ctx.spans.take().unwrap().into_inner()))
+}
+
+/// Removes top-level declarations of names that are declared again later in the program.
+fn keep_last_decls(prog: Program) -> Program {
+    let mut last = HashMap::new();
+    for (i, decl) in prog.0.iter().enumerate() {
+        last.insert(decl.name.name.clone(), i);
+    }
+    let decls = prog.0.into_iter().enumerate();
+    Program(
+        decls
+            .filter(|(i, decl)| last[&decl.name.name] == *i)
+            .map(|(_, decl)| decl)
+            .collect(),
+    )
+}
+
+/// Compiles a program in the given global context.
+fn compile_in<'a>(
+    prog: Program,
+    options: &CompileOptions<'a>,
+    ctx: &mut CompileContext<'a>,
+) -> Result<Defs, CompileError> {
+    let mut prog = check_depth(prog, options.max_depth.unwrap_or(DEFAULT_MAX_DEPTH))?;
+    if options.redefine == RedefinePolicy::LastWins {
+        prog = keep_last_decls(prog);
+    }
+    ctx.resolve_external = options.resolve_external;
+    ctx.lists_only = options.lists_only;
+    ctx.check_types = options.check_types;
+    ctx.strict = options.strict;
+    let defs = compile_prog(prog, ctx)?;
+    if let Some(cycle) = find_cycle(&defs) {
+        return Err(CompileError::Cycle(cycle));
+    }
+    Ok(defs)
+}
+
+/// Compiles a single expression (e.g. one entered in a REPL), whose value is the definition `out`.
+/// Other definitions it needs are private ids. The expression can only reference the standard
+/// library.
+pub fn compile_expression(expr: Expr, out: Id) -> Result<Defs, CompileError> {
+    if expr_depth(&expr, DEFAULT_MAX_DEPTH).is_none() {
+        drop_nested(iter::once(expr));
+        return Err(CompileError::TooDeep(DEFAULT_MAX_DEPTH));
+    }
+    let mut ctx = CompileContext::global(&HashSet::new(), &default_aliases());
+    if is_private(&out) {
+        // so that it isn't generated for anything else
+        ctx.names.borrow_mut().insert(out.clone(), out.clone());
+    }
+    let mut defs = Defs::new();
+    compile_expr(out, expr, &mut defs, &mut ctx)?;
+    if let Some(cycle) = find_cycle(&defs) {
+        return Err(CompileError::Cycle(cycle));
+    }
+    Ok(defs)
+}
+
+/// Like `compile_with_options`, but continues past unresolved and duplicate identifiers and
+/// returns all errors.
+pub fn compile_collecting(
+    prog: Program,
+    options: &CompileOptions,
+) -> Result<Defs, Vec<CompileError>> {
+    let mut ctx = CompileContext::global(&options.extra_stdlib, &options.aliases);
+    ctx.errors = Some(RefCell::new(Vec::new()));
+    let result = compile_in(prog, options, &mut ctx);
+    let mut errors = ctx.errors.take().unwrap().into_inner();
+    match result {
+        Ok(defs) if errors.is_empty() => Ok(defs),
+        Ok(_) => Err(errors),
+        Err(err) => {
+            errors.push(err);
+            Err(errors)
+        }
+    }
+}
+
+/// Compiles declarations one at a time against the ones before them, e.g. for a REPL.
+///
+/// Unlike in `compile`, declaring a name again replaces its earlier declaration (and definitions
+/// that referenced it now reference the new one).
+pub struct Session<'a> {
+    ctx: CompileContext<'a>,
+    max_depth: usize,
+    defs: Defs,
+    /// The ids each declaration was compiled to, by name.
+    decl_ids: HashMap<Id, Vec<Id>>,
+}
+
+impl<'a> Session<'a> {
+    pub fn new(options: &CompileOptions<'a>) -> Session<'a> {
+        let mut ctx = CompileContext::global(&options.extra_stdlib, &options.aliases);
+        ctx.resolve_external = options.resolve_external;
+        ctx.lists_only = options.lists_only;
+        ctx.check_types = options.check_types;
+        ctx.strict = options.strict;
+        Session {
+            ctx,
+            max_depth: options.max_depth.unwrap_or(DEFAULT_MAX_DEPTH),
+            defs: Defs::new(),
+            decl_ids: HashMap::new(),
+        }
+    }
+
+    /// Compiles a declaration and returns the definitions it was compiled to. If this fails, the
+    /// session is left unchanged.
+    pub fn add(&mut self, decl: Decl) -> Result<Defs, CompileError> {
+        if expr_depth(&decl.body, self.max_depth).is_none() {
+            drop_nested(iter::once(decl.body));
+            return Err(CompileError::TooDeep(self.max_depth));
+        }
+        let name = decl.name.name.clone();
+        let names = self.ctx.names.borrow().clone();
+        let arities = self.ctx.arities.borrow().clone();
+        let priv_counter = *self.ctx.priv_counter.borrow();
+        let restore = |ctx: &CompileContext| {
+            *ctx.names.borrow_mut() = names;
+            *ctx.arities.borrow_mut() = arities;
+            *ctx.priv_counter.borrow_mut() = priv_counter;
+        };
+
+        if self.decl_ids.contains_key(&name) {
+            self.ctx.names.borrow_mut().remove(&name);
+        }
+        let result = self.ctx.add_decl(&decl).and_then(|id| {
+            let mut defs = Defs::new();
+            compile_decl(id, decl, &mut defs, &mut self.ctx)?;
+            Ok(defs)
+        });
+        let new_defs = match result {
+            Ok(defs) => defs,
+            Err(err) => {
+                restore(&self.ctx);
+                return Err(err);
+            }
+        };
+
+        let mut old_defs = Vec::new();
+        for id in self.decl_ids.get(&name).into_iter().flatten() {
+            old_defs.extend(self.defs.remove_entry(id));
+        }
+        self.defs.extend(new_defs.clone());
+        if let Some(cycle) = find_cycle(&self.defs) {
+            for id in new_defs.keys() {
+                self.defs.remove(id);
+            }
+            self.defs.extend(old_defs);
+            restore(&self.ctx);
+            return Err(CompileError::Cycle(cycle));
+        }
+        self.decl_ids
+            .insert(name, new_defs.keys().cloned().collect());
+        Ok(new_defs)
+    }
+
+    /// Returns the definitions of all declarations so far.
+    pub fn defs(&self) -> &Defs {
+        &self.defs
+    }
+
+    pub fn into_defs(self) -> Defs {
+        self.defs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar::ProgramParser;
+
+    fn compile_src(src: &str) -> Result<Defs, CompileError> {
+        compile(
+            ProgramParser::new()
+                .parse(&OperatorTable::default(), src)
+                .expect("failed to parse"),
+        )
+    }
+
+    #[test]
+    fn non_finite_list() {
+        let defs = compile_src("a = [1, Infinity, 3]").unwrap();
+        match &defs["a"] {
+            Def::List { items } => {
+                assert_eq!(items.len(), 3);
+                match &defs[&items[1]] {
+                    Def::Number { value } => assert_eq!(*value, f64::INFINITY),
+                    _ => panic!("expected number"),
+                }
+            }
+            _ => panic!("expected list"),
+        }
+
+        let defs = compile_src("a = [1, 2, 3]").unwrap();
+        assert!(matches!(defs["a"], Def::Matrix { .. }));
+    }
+
+    #[test]
+    fn matrix_types() {
+        let defs =
+            compile_src(r#"a = ["a", "b"]; b = [null, null]; c = ["a", null]; d = [1, true]"#)
+                .unwrap();
+        match &defs["a"] {
+            Def::Matrix { value } => assert_eq!(value, &[Value::from("a"), Value::from("b")]),
+            _ => panic!("expected matrix"),
+        }
+        match &defs["b"] {
+            Def::Matrix { value } => assert_eq!(value, &[Value::Null, Value::Null]),
+            _ => panic!("expected matrix"),
+        }
+        assert!(matches!(defs["c"], Def::List { .. }));
+        assert!(matches!(defs["d"], Def::List { .. }));
+    }
+
+    #[test]
+    fn signed_matrix() {
+        let src =
+            "a = [-1, 2, -3]; b = [- 1, -(2), (3), - -4, - 0]; c = [[- 1], [2]]; d = [- 1, \"a\"]";
+        let defs = compile_src(src).unwrap();
+        let matrix = |id: &str| match &defs[id] {
+            Def::Matrix { value } => serde_json::to_string(value).unwrap(),
+            def => panic!("expected matrix, got {:?}", def),
+        };
+        assert_eq!(matrix("a"), "[-1.0,2.0,-3.0]");
+        // `- 0` is 0 - 0, so it's not negative zero
+        assert_eq!(matrix("b"), "[-1.0,-2.0,3.0,4.0,0.0]");
+        assert_eq!(matrix("c"), "[[-1.0],[2.0]]");
+        assert!(matches!(defs["d"], Def::List { .. }));
+
+        // unless `-` has been redefined
+        let defs = compile_src("f `-` = [- 1, 2]").unwrap();
+        match &defs["f"] {
+            Def::Fn { body, .. } => assert!(matches!(body["="], Def::List { .. })),
+            _ => panic!("expected function"),
+        }
+    }
+
+    #[test]
+    fn type_mismatch() {
+        let options = CompileOptions {
+            check_types: true,
+            ..CompileOptions::default()
+        };
+        let check = |src: &str| compile_with_options(crate::parse(src).unwrap(), &options);
+
+        let src = "a = 1 + \"one\"";
+        let err = check(src).unwrap_err();
+        assert!(matches!(
+            &err,
+            CompileError::TypeMismatch(f, 2, expected, Type::String, span)
+                if f == "+" && expected == &[Type::Number] && &src[span.clone()] == "\"one\""
+        ));
+        assert_eq!(err.kind(), "type_mismatch");
+        assert_eq!(
+            err.to_string(),
+            "argument 2 of '+' should be a number, but is a string"
+        );
+
+        let src = "a = length (1 < 2); b = map [1] (\\x -> x)";
+        let err = check(src).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "argument 1 of 'length' should be a list or string, but is a bool"
+        );
+        // the span is that of the argument
+        assert_eq!(&src[err.span().unwrap()], "(1 < 2)");
+        for src in &[
+            "a = sum \"ab\"",
+            "a = avg 1",
+            "a = !1",
+            "a = not (sum [1])",
+            "a = if true then 1 else 2 `and` false",
+            "a = 7 % \"2\"",
+        ] {
+            assert!(
+                matches!(check(src), Err(CompileError::TypeMismatch(..))),
+                "{}",
+                src
+            );
+        }
+
+        // unknown types, shadowed names and functions without a signature aren't checked
+        for src in &[
+            "x = \"a\"; a = x + 1",
+            "a = let y = \"a\" in y + 1",
+            "f `+` = 1 + \"a\"",
+            "a = length \"abc\" + sum [1, 2] * -(3)",
+            "a = date_add \"days\" @2024-01-15 \"x\"",
+            "a = [1] ++ \"a\" == null",
+        ] {
+            assert!(check(src).is_ok(), "{}", src);
+        }
+        // functions that work on lists work on strings too, and may return strings
+        for src in &[
+            "a = [1] ++ 2",
+            "a = map (\\x -> x) \"abc\"",
+            "a = length (sort \"cba\")",
+            "a = fold (\\a b -> a) 0 \"ab\"",
+            "a = (min [\"b\", \"a\"]) ++ \"x\"",
+            "a = length (max [\"b\", \"a\"]) + sum 1",
+            "a = \"a\" < \"b\"",
+        ] {
+            assert!(check(src).is_ok(), "{}", src);
+        }
+        // without the option, nothing is checked
+        assert!(compile(crate::parse("a = 1 + \"one\"").unwrap()).is_ok());
+    }
+
+    #[test]
+    fn lists_only() {
+        let options = CompileOptions {
+            lists_only: true,
+            ..CompileOptions::default()
+        };
+        let prog = crate::parse("a = [1, 2, 3]; b = \\x -> [[x], [\"a\"]]").unwrap();
+        let defs = compile_with_options(prog, &options).unwrap();
+        let items = match &defs["a"] {
+            Def::List { items } => items,
+            def => panic!("expected list, got {:?}", def),
+        };
+        let values: Vec<_> = items
+            .iter()
+            .map(|item| match defs[item] {
+                Def::Number { value } => value,
+                ref def => panic!("expected number, got {:?}", def),
+            })
+            .collect();
+        assert_eq!(values, [1., 2., 3.]);
+        // in function bodies too
+        match &defs["b"] {
+            Def::Fn { body, .. } => {
+                assert!(body.values().all(|def| !matches!(def, Def::Matrix { .. })))
+            }
+            def => panic!("expected function, got {:?}", def),
+        }
+    }
+
+    #[test]
+    fn strict_lists() {
+        let strict = CompileOptions {
+            strict: true,
+            ..CompileOptions::default()
+        };
+        let compile_strict = |src| compile_with_options(crate::parse(src).unwrap(), &strict);
+
+        // lists that would be matrices are lists too
+        let defs = compile_strict("a = [1, 2, 3]; b = [[1], [2]]").unwrap();
+        assert!(defs.values().all(|def| !matches!(def, Def::Matrix { .. })));
+        assert!(matches!(&defs["a"], Def::List { items } if items.len() == 3));
+
+        let src = "a = [1, \"a\"]";
+        assert!(matches!(
+            compile_src(src).unwrap()["a"],
+            Def::List { ref items } if items.len() == 2
+        ));
+        let err = compile_strict(src).unwrap_err();
+        assert!(matches!(
+            &err,
+            CompileError::MixedList(Type::Number, Type::String, span) if span == &(8..11)
+        ));
+        assert_eq!(
+            err.to_string(),
+            "items of a list should all have the same type, but this is a string and an earlier \
+             item is a number"
+        );
+        // null is compatible with any type
+        assert!(compile_strict("a = [1, null]; b = [null, \"a\"]; c = [null, null]").is_ok());
+        assert!(matches!(
+            compile_strict("a = [\"a\", null, 3]"),
+            Err(CompileError::MixedList(Type::String, Type::Number, span)) if span == (16..17)
+        ));
+        assert!(matches!(
+            compile_strict("f x = [x, [], 1, 2.5]"),
+            Err(CompileError::MixedList(Type::List, Type::Number, span)) if span == (14..15)
+        ));
+        // items of unknown type, nested lists, and lists in spreads
+        assert!(compile_strict("f x = [x, 1, x + 1]; g = [[1], [\"a\"]]").is_ok());
+        assert!(compile_strict("xs = [1]; a = [...xs, true]").is_ok());
+        assert!(compile_strict("xs = [1]; a = [...xs, true, 1]").is_err());
+    }
+
+    #[test]
+    fn spread() {
+        let src = "xs = [1, 2]; ys = [3]; a = [...xs, 1]; b = [1, ...xs]; c = [...xs, ...ys]; \
+                   d = [...xs, 1, 2, ...ys, 3]; e = [...xs]; f = [1, 2]";
+        let defs = compile_src(src).unwrap();
+        let call = |id: &str| match &defs[id] {
+            Def::Call { f, args } if f == "++" => args.clone(),
+            def => panic!("expected concatenation, got {:?}", def),
+        };
+        let numbers = |id: &str| match &defs[id] {
+            Def::Matrix { value } => value
+                .iter()
+                .map(|n| n.as_f64().unwrap())
+                .collect::<Vec<_>>(),
+            def => panic!("expected matrix, got {:?}", def),
+        };
+
+        let args = call("a");
+        assert_eq!(args[0], "xs");
+        assert_eq!(numbers(&args[1]), [1.]);
+        let args = call("b");
+        assert_eq!(numbers(&args[0]), [1.]);
+        assert_eq!(args[1], "xs");
+        assert_eq!(call("c"), ["xs", "ys"]);
+        // (xs ++ [1, 2]) ++ ys) ++ [3]
+        let args = call("d");
+        assert_eq!(numbers(&args[1]), [3.]);
+        let args = call(&args[0]);
+        assert_eq!(args[1], "ys");
+        let args = call(&args[0]);
+        assert_eq!(args[0], "xs");
+        assert_eq!(numbers(&args[1]), [1., 2.]);
+        assert!(matches!(&defs["e"], Def::Call { f, args } if f == "xs" && args.is_empty()));
+        assert_eq!(numbers("f"), [1., 2.]);
+
+        // a parameter named `++` doesn't capture the concatenation
+        let defs = compile_src("xs = [1]; f `++` = [...xs, 2]").unwrap();
+        match &defs["f"] {
+            Def::Fn { params, body } => {
+                assert_ne!(params[0], "++");
+                assert!(matches!(&body["="], Def::Call { f, .. } if f == "++"));
+            }
+            def => panic!("expected function, got {:?}", def),
+        }
+    }
+
+    #[test]
+    fn session() {
+        let decl = |src: &str| {
+            let Program(mut decls) = crate::parse(src).unwrap();
+            decls.remove(0)
+        };
+        let mut session = Session::new(&CompileOptions::default());
+        let err = session.add(decl("a = [1, b]")).map(|_| ()).unwrap_err();
+        assert!(matches!(err, CompileError::CantResolve(id, _) if id == "b"));
+        assert!(session.defs().is_empty());
+
+        session.add(decl("b = 2")).unwrap();
+        let defs = session.add(decl("a = [1, b]")).unwrap();
+        assert!(matches!(&defs["a"], Def::List { items } if items.len() == 2));
+        assert_eq!(defs.len(), 2);
+        assert_eq!(session.defs().len(), 3);
+
+        // redefining replaces the old definitions, including generated ones
+        let defs = session.add(decl("a = b * 2")).unwrap();
+        assert!(matches!(&defs["a"], Def::Call { f, .. } if f == "*"));
+        assert_eq!(session.defs().len(), 3);
+        let defs = session.add(decl("b = 3")).unwrap();
+        assert_eq!(defs.len(), 1);
+        assert!(matches!(session.defs()["b"], Def::Number { value } if value == 3.));
+
+        // failures leave the session as it was
+        let before = session.defs().clone();
+        assert!(matches!(
+            session.add(decl("b = a + 1")),
+            Err(CompileError::Cycle(_))
+        ));
+        assert!(matches!(
+            session.add(decl("map = 1")),
+            Err(CompileError::DupIdent(..))
+        ));
+        assert!(matches!(
+            session.add(decl("c = let x = 1; x = 2 in x")),
+            Err(CompileError::DupIdent(..))
+        ));
+        assert_eq!(session.defs(), &before);
+        session.add(decl("c = a")).unwrap();
+        let defs = session.into_defs();
+        // a, its literal 2, b and c
+        assert_eq!(defs.len(), 4);
+        assert!(matches!(&defs["c"], Def::Call { f, .. } if f == "a"));
+
+        // but batch compilation doesn't allow redefinitions
+        assert!(matches!(
+            compile_src("a = 1; a = 2"),
+            Err(CompileError::DupIdent(..))
+        ));
+    }
+
+    #[test]
+    fn percent_is_mod() {
+        let defs = compile_src("a = 5 % 3").unwrap();
+        assert!(matches!(&defs["a"], Def::Call { f, .. } if f == "mod"));
+
+        let defs = compile_src("`%` = \\a b -> a; a = 5 % 3").unwrap();
+        assert!(matches!(&defs["a"], Def::Call { f, .. } if f == "%"));
+    }
+
+    #[test]
+    fn aliases() {
+        let mut options = CompileOptions::default();
+        options.aliases.insert("++".into(), "concat".into());
+        options.aliases.insert("map".into(), "list_map".into());
+        options.aliases.insert("mod".into(), "modulo".into());
+        options.aliases.insert("cat".into(), "++".into());
+        let compile = |src| compile_with_options(crate::parse(src).unwrap(), &options);
+        let call = |def: &Def| match def {
+            Def::Call { f, .. } => f.clone(),
+            def => panic!("expected call, got {:?}", def),
+        };
+
+        let defs = compile(
+            "xs = [1]; a = xs ++ [2]; b = [...xs, 2]; c = map (\\x -> x) xs; d = 5 % 3; \
+             e = cat xs xs; f = xs `cat` xs",
+        )
+        .unwrap();
+        assert_eq!(call(&defs["a"]), "concat");
+        // also where the compiler calls stdlib functions itself
+        assert_eq!(call(&defs["b"]), "concat");
+        assert_eq!(call(&defs["c"]), "list_map");
+        // `%` is `mod`, which is `modulo`
+        assert_eq!(call(&defs["d"]), "modulo");
+        // `cat` is `++`, which is `concat`
+        assert_eq!(call(&defs["e"]), "concat");
+        assert_eq!(call(&defs["f"]), "concat");
+        // references to functions are aliased too
+        for (src, f) in &[("a = map floor", "list_map"), ("a = (++)", "concat")] {
+            match &compile(src).unwrap()["a"] {
+                Def::Fn { body, .. } => assert_eq!(&call(&body["="]), f),
+                def => panic!("expected function, got {:?}", def),
+            }
+        }
+
+        // names that are defined aren't aliased
+        let defs = compile("`%` = \\a b -> a; cat = 1; a = 5 % 3; f map = map 1; g = cat").unwrap();
+        assert_eq!(call(&defs["a"]), "%");
+        match &defs["f"] {
+            Def::Fn { params, body } => assert_eq!(call(&body["="]), params[0]),
+            def => panic!("expected function, got {:?}", def),
+        }
+        assert_eq!(call(&defs["g"]), "cat");
+
+        // an aliased stdlib function is still checked like one
+        assert!(matches!(
+            compile("a = cat [1] [2] [3]"),
+            Err(CompileError::Arity(name, ..)) if name == "++"
+        ));
+        match &compile("a = cat [1]").unwrap()["a"] {
+            Def::Fn { body, .. } => assert_eq!(call(&body["="]), "concat"),
+            def => panic!("expected function, got {:?}", def),
+        }
+
+        // an alias of a name that isn't defined can't be resolved
+        let mut cat_foo = CompileOptions::default();
+        cat_foo.aliases.insert("cat".into(), "foo".into());
+        let compile = |src| compile_with_options(crate::parse(src).unwrap(), &cat_foo);
+        assert!(matches!(
+            compile("a = cat [1] [2]"),
+            Err(CompileError::CantResolve(name, _)) if name == "foo"
+        ));
+        assert_eq!(
+            call(&compile("foo = \\a b -> a; a = cat 1 2").unwrap()["a"]),
+            "foo"
+        );
+
+        // without the default aliases, `%` isn't defined
+        let no_aliases = CompileOptions {
+            aliases: HashMap::new(),
+            ..CompileOptions::default()
+        };
+        assert!(matches!(
+            compile_with_options(crate::parse("a = 5 % 3").unwrap(), &no_aliases),
+            Err(CompileError::CantResolve(name, _)) if name == "%"
+        ));
+        assert_eq!(
+            call(&compile_src("a = 5 % 3").unwrap()["a"]),
+            call(
+                &compile_with_options(
+                    crate::parse("a = 5 % 3").unwrap(),
+                    &CompileOptions::default()
+                )
+                .unwrap()["a"]
+            )
+        );
+    }
+
+    #[test]
+    fn pipe() {
+        let defs = compile_src("a = 1 |> f 2 |> g; f a b = a + b; g = \\x -> x").unwrap();
+        let call = |id: &str| match &defs[id] {
+            Def::Call { f, args } => (f.clone(), args.clone()),
+            def => panic!("expected call, got {:?}", def),
+        };
+        // g (f 2 1)
+        let (f, args) = call("a");
+        assert_eq!(f, "g");
+        let (f, args) = call(&args[0]);
+        assert_eq!(f, "f");
+        assert_eq!(args.len(), 2);
+        assert!(matches!(defs[&args[0]], Def::Number { value } if value == 2.));
+        assert!(matches!(defs[&args[1]], Def::Number { value } if value == 1.));
+
+        // binds more loosely than anything else
+        let defs = compile_src("a = 1 + 2 |> f; f = \\x -> x").unwrap();
+        assert!(matches!(&defs["a"], Def::Call { f, args } if f == "f" && args.len() == 1));
+    }
+
+    #[test]
+    fn coalesce() {
+        let defs = compile_src("a = f 1 ?? 2 == 3; f = \\x -> x").unwrap();
+        let cases = match &defs["a"] {
+            Def::Switch { cases } => cases,
+            def => panic!("expected switch, got {:?}", def),
+        };
+        assert_eq!(cases.len(), 2);
+        let lhs = &cases[1].value;
+        assert!(cases[1].cond.is_none());
+        assert!(matches!(&defs[lhs], Def::Call { f, .. } if f == "f"));
+        match &defs[cases[0].cond.as_ref().unwrap()] {
+            Def::Call { f, args } => {
+                assert_eq!(f, "==");
+                assert_eq!(&args[0], lhs);
+                assert!(matches!(defs[&args[1]], Def::Null));
+            }
+            def => panic!("expected call, got {:?}", def),
+        }
+        // binds more loosely than comparisons
+        assert!(matches!(&defs[&cases[0].value], Def::Call { f, .. } if f == "=="));
+
+        // identifiers don't need a temporary
+        let defs = compile_src("a = null; b = a ?? 1").unwrap();
+        assert!(matches!(&defs["b"], Def::Switch { cases } if cases[1].value == "a"));
+
+        // a parameter named `==` doesn't capture the comparison with null
+        let defs = compile_src("f `==` x = x ?? 1").unwrap();
+        match &defs["f"] {
+            Def::Fn { params, body } => {
+                assert_ne!(params[0], "==");
+                let cases = match &body["="] {
+                    Def::Switch { cases } => cases,
+                    def => panic!("expected switch, got {:?}", def),
+                };
+                let cond = cases[0].cond.as_ref().unwrap();
+                assert!(matches!(&body[cond], Def::Call { f, .. } if f == "=="));
+            }
+            def => panic!("expected function, got {:?}", def),
+        }
+    }
+
+    #[test]
+    fn empty_lists() {
+        let defs = compile_src("a = []; b = [[], []]; c = [[]]").unwrap();
+        for id in &["a", "b", "c"] {
+            assert!(matches!(&defs[*id], Def::List { .. }), "{}", id);
+        }
+        assert!(matches!(&defs["a"], Def::List { items } if items.is_empty()));
+    }
+
+    #[test]
+    fn nested_matrices() {
+        let defs =
+            compile_src("a = [[1, 2], [3, 4]]; b = [[1, 2], [3]]; c = [[1, 2], [true, false]]")
+                .unwrap();
+        match &defs["a"] {
+            Def::Matrix { value } => {
+                assert_eq!(
+                    Value::Array(value.clone()),
+                    serde_json::json!([[1., 2.], [3., 4.]])
+                )
+            }
+            _ => panic!("expected matrix"),
+        }
+        // ragged and mixed nestings are lists of matrices
+        for id in &["b", "c"] {
+            match &defs[*id] {
+                Def::List { items } => {
+                    assert!(items
+                        .iter()
+                        .all(|item| matches!(defs[item], Def::Matrix { .. })))
+                }
+                _ => panic!("expected list"),
+            }
+        }
+    }
+
+    #[test]
+    fn right_assoc_pow() {
+        let defs = compile_src("a = 2 ^ 3 ^ 2").unwrap();
+        let call = |id: &str| match &defs[id] {
+            Def::Call { f, args } => (f.clone(), args.clone()),
+            _ => panic!("expected call"),
+        };
+        let number = |id: &str| match &defs[id] {
+            Def::Number { value } => *value,
+            _ => panic!("expected number"),
+        };
+
+        // 2 ^ (3 ^ 2)
+        let (f, args) = call("a");
+        assert_eq!(f, "^");
+        assert_eq!(number(&args[0]), 2.);
+        let (f, args) = call(&args[1]);
+        assert_eq!(f, "^");
+        assert_eq!(number(&args[0]), 3.);
+        assert_eq!(number(&args[1]), 2.);
+
+        // (8 - 3) - 2
+        let defs = compile_src("a = 8 - 3 - 2").unwrap();
+        match &defs["a"] {
+            Def::Call { f, args } => {
+                assert_eq!(f, "-");
+                assert!(matches!(&defs[&args[0]], Def::Call { .. }));
+                assert!(matches!(&defs[&args[1]], Def::Number { value } if *value == 2.));
+            }
+            _ => panic!("expected call"),
+        }
+    }
+
+    #[test]
+    fn ternary() {
+        let compile_json = |src| serde_json::to_value(compile_src(src).unwrap()).unwrap();
+        assert_eq!(
+            compile_json("x = 2; a = x > 1 ? 1 : x == 0 ? 2 : 3"),
+            compile_json("x = 2; a = if x > 1 then 1 else if x == 0 then 2 else 3")
+        );
+        assert_eq!(
+            compile_json("x = true; y = false; a = x ? (y ? 1 : 2) : 3"),
+            compile_json("x = true; y = false; a = if x then if y then 1 else 2 else 3")
+        );
+    }
+
+    #[test]
+    fn else_if_chain() {
+        let defs = compile_src(
+            "x = 2; a = if x == 1 then 1 else if x == 2 then 2 else (if x == 3 then 3 else 4)",
+        )
+        .unwrap();
+        match &defs["a"] {
+            Def::Switch { cases } => {
+                assert_eq!(cases.len(), 4);
+                assert!(cases[..3].iter().all(|case| case.cond.is_some()));
+                assert!(cases[3].cond.is_none());
+                assert!(matches!(&defs[&cases[3].value], Def::Number { value } if *value == 4.));
+            }
+            _ => panic!("expected switch"),
+        }
+    }
+
+    #[test]
+    fn cond() {
+        let src = "x = 2; a = cond { x == 1 -> \"one\", x == 2 -> \"two\", else -> x + 1, }";
+        let defs = compile_src(src).unwrap();
+        let cases = match &defs["a"] {
+            Def::Switch { cases } => cases,
+            _ => panic!("expected switch"),
+        };
+        assert_eq!(cases.len(), 3);
+        assert!(cases[2].cond.is_none());
+        // every condition and value has its own definition
+        let mut ids: Vec<_> = cases.iter().flat_map(|case| &case.cond).collect();
+        ids.extend(cases.iter().map(|case| &case.value));
+        assert!(ids
+            .iter()
+            .all(|id| id.starts_with('_') && defs.contains_key(*id)));
+        assert_eq!(ids.iter().collect::<HashSet<_>>().len(), 5);
+        assert!(matches!(&defs[&cases[1].value], Def::String { value } if value == "two"));
+
+        // same as the equivalent `if` chain
+        let compile_json = |src| serde_json::to_value(compile_src(src).unwrap()).unwrap();
+        assert_eq!(
+            compile_json("x = 2; a = cond { x == 1 -> 1, x > 1 -> 2, else -> 3 }"),
+            compile_json("x = 2; a = if x == 1 then 1 else if x > 1 then 2 else 3")
+        );
+
+        // the default has to come last, exactly once
+        for src in &[
+            "a = cond { true -> 1 }",
+            "a = cond { else -> 1, true -> 2 }",
+            "a = cond { true -> 1, else -> 2, else -> 3 }",
+        ] {
+            let err = crate::compile_str(src).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "`cond` must end with exactly one `else` arm"
+            );
+            assert_eq!(err.span(), Some(4..src.len()));
+        }
+        let arms = vec![(
+            Some(Expr::new(ExprKind::Bool(true), 4..8)),
+            Expr::new(ExprKind::Null, 12..16),
+        )];
+        let decl = Decl {
+            name: Ident::new("a", 0..1),
+            params: Vec::new(),
+            body: Expr::new(ExprKind::Match(arms), 4..16),
+            span: 0..1,
+            doc: None,
+            attrs: Vec::new(),
+        };
+        assert!(matches!(
+            compile(Program(vec![decl])),
+            Err(CompileError::Parse(ParseError::User {
+                error: SyntaxError::MatchDefault(_)
+            }))
+        ));
+    }
+
+    #[test]
+    fn error_messages() {
+        let err = compile_src("a = b").err().unwrap();
+        assert_eq!(err.to_string(), "cannot resolve 'b'");
+        let err = compile_src("a = 1; a = 2").err().unwrap();
+        assert_eq!(err.to_string(), "duplicate identifier 'a'");
+        let err: Box<dyn error::Error> = Box::new(err);
+        assert_eq!(err.to_string(), "duplicate identifier 'a'");
+    }
+
+    #[test]
+    fn error_spans() {
+        let src = "a = 1;\nb = a + c";
+        let span = compile_src(src).err().unwrap().span().unwrap();
+        assert_eq!(&src[span], "c");
+
+        let src = "a = 1;\nb x x = x";
+        let err = compile_src(src).err().unwrap();
+        assert!(matches!(err, CompileError::DupIdent(..)));
+        assert_eq!(err.span(), Some(11..12));
+    }
+
+    #[test]
+    fn duplicate_params() {
+        // the error is at the second parameter
+        for (src, span) in &[
+            ("f a a = a", 4..5),
+            ("f = \\(a, a) -> a", 9..10),
+            ("f = \\a b a -> a", 9..10),
+            ("f = let g a a = a in g", 12..13),
+            ("f x = [\\a `a` -> a]", 10..13),
+        ] {
+            let err = crate::compile_str(src).unwrap_err();
+            assert!(
+                matches!(&err, CompileError::DupIdent(name, _) if name == "a"),
+                "{}",
+                src
+            );
+            assert_eq!(err.span(), Some(span.clone()), "{}", src);
+        }
+        // parameters of different functions don't conflict
+        assert!(crate::compile_str("f a = \\a -> a; g a = a").is_ok());
+    }
+
+    #[test]
+    fn param_shadowing() {
+        // parameters named like a stdlib function get a private id, since the compiler may
+        // generate references to that function
+        let defs = compile_src("f map = map 1; g = \\map -> map").unwrap();
+        for name in &["f", "g"] {
+            match &defs[*name] {
+                Def::Fn { params, body } => {
+                    assert_eq!(params.len(), 1);
+                    assert_ne!(params[0], "map");
+                    assert!(matches!(&body["="], Def::Call { f, .. } if f == &params[0]));
+                }
+                _ => panic!("expected function"),
+            }
+        }
+
+        // let bindings inside the function resolve to their assigned ids
+        let defs = compile_src("f map = let map2 = map in map2").unwrap();
+        match &defs["f"] {
+            Def::Fn { body, .. } => match &body["="] {
+                Def::Call { f, .. } => {
+                    assert_ne!(f, "map2");
+                    assert!(matches!(&body[f], Def::Call { f, .. } if f.ends_with("map")));
+                }
+                _ => panic!("expected call"),
+            },
+            _ => panic!("expected function"),
+        }
+    }
+
+    #[test]
+    fn collect_errors() {
+        let src = "a = b + 1; c x x = d; a = 2; e = 1";
+        let prog = ProgramParser::new()
+            .parse(&OperatorTable::default(), src)
+            .unwrap();
+        let errors = compile_collecting(prog, &CompileOptions::default())
+            .err()
+            .unwrap();
+        let spans: Vec<_> = errors.iter().map(|err| &src[err.span().unwrap()]).collect();
+        assert_eq!(spans, ["a", "b", "x", "d"]);
+        assert!(matches!(&errors[0], CompileError::DupIdent(..)));
+        assert!(matches!(&errors[1], CompileError::CantResolve(..)));
+
+        let prog = ProgramParser::new()
+            .parse(&OperatorTable::default(), "a = 1")
+            .unwrap();
+        assert!(compile_collecting(prog, &CompileOptions::default()).is_ok());
+
+        // options apply as with `compile_with_options`
+        let prog = ProgramParser::new()
+            .parse(&OperatorTable::default(), "a = 1; a = b; c = e")
+            .unwrap();
+        let options = CompileOptions {
+            redefine: RedefinePolicy::LastWins,
+            ..CompileOptions::default()
+        };
+        let errors = compile_collecting(prog, &options).err().unwrap();
+        let names: Vec<_> = errors
+            .iter()
+            .map(|err| match err {
+                CompileError::CantResolve(name, _) => name.as_str(),
+                err => panic!("expected unresolved name, got {:?}", err),
+            })
+            .collect();
+        assert_eq!(names, ["b", "e"]);
+    }
+
+    #[test]
+    fn cycles() {
+        let cycle = |src| match compile_src(src) {
+            Err(CompileError::Cycle(ids)) => Some(ids),
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(_) => None,
+        };
+        assert_eq!(cycle("a = b; b = a"), Some(vec!["a".into(), "b".into()]));
+        assert_eq!(cycle("x = 1; a = [x, a]"), Some(vec!["a".into()]));
+        assert_eq!(
+            cycle("a = if b then 1 else 2; b = a == 1"),
+            Some(vec!["_0".into(), "b".into(), "a".into()])
+        );
+        assert!(cycle("f x = let a = [x, a] in a").is_some());
+        assert_eq!(cycle("f x = f (x - 1); g = f 1"), None);
+        assert_eq!(cycle("a = 1; b = a + a"), None);
+
+        let err = compile_src("a = b; b = a").err().unwrap();
+        assert_eq!(err.to_string(), "cyclic definition: a -> b -> a");
+    }
+
+    #[test]
+    fn deterministic_output() {
+        let src =
+            "a = [x, y, z, 1 + 2 * 3]; x = 1; y = \\a b -> a + b * 2; z = if x > 1 then 2 else 3";
+        let compile_json = || serde_json::to_string(&compile_src(src).unwrap()).unwrap();
+        let first = compile_json();
+        for _ in 0..10 {
+            assert_eq!(compile_json(), first);
+        }
+    }
+
+    #[test]
+    fn many() {
+        let parse = |src| crate::parse(src).unwrap();
+        let defs = compile_many(vec![
+            parse("a = b + 1; c = [1, 2] ++ [d]"),
+            parse("b = 2; d = a * 3"),
+            Program(Vec::new()),
+        ])
+        .unwrap();
+        assert_eq!(
+            defs,
+            compile(parse("a = b + 1; c = [1, 2] ++ [d]; b = 2; d = a * 3")).unwrap()
+        );
+        // private ids don't collide
+        let private = defs.keys().filter(|id| id.starts_with('_')).count();
+        assert_eq!(private, 4);
+
+        // errors say which program they're in
+        let err = compile_many(vec![parse("a = 1"), parse("b = 2;\na = 3")]).unwrap_err();
+        assert!(matches!(
+            &err,
+            CompileError::InProgram(1, err)
+                if matches!(&**err, CompileError::DupIdent(id, span) if id == "a" && span == &(7..8))
+        ));
+        assert_eq!(err.kind(), "duplicate_identifier");
+        assert_eq!(err.span(), Some(7..8));
+        assert_eq!(err.to_string(), "in program 1: duplicate identifier 'a'");
+        let err = compile_many(vec![parse("a = 1"), parse("b = 2"), parse("c = [a, d]")]);
+        assert!(matches!(
+            err,
+            Err(CompileError::InProgram(2, err)) if matches!(*err, CompileError::CantResolve(..))
+        ));
+        // a cycle isn't in one program
+        assert!(matches!(
+            compile_many(vec![parse("a = b"), parse("b = a")]),
+            Err(CompileError::Cycle(_))
+        ));
+        assert!(compile_many(Vec::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn redefine() {
+        let parse = |src| crate::parse(src).unwrap();
+        let src = "a = 1; b = a + 1; a = \"x\"";
+        let err = compile_with_options(parse(src), &CompileOptions::default()).unwrap_err();
+        assert!(matches!(err, CompileError::DupIdent(id, span) if id == "a" && span == (18..19)));
+
+        let options = CompileOptions {
+            redefine: RedefinePolicy::LastWins,
+            ..Default::default()
+        };
+        let defs = compile_with_options(parse(src), &options).unwrap();
+        assert_eq!(defs, compile(parse("b = a + 1; a = \"x\"")).unwrap());
+        // a declaration that was replaced isn't checked
+        assert!(compile_with_options(parse("a = b; a = 1"), &options).is_ok());
+        // bindings still can't be duplicated
+        assert!(matches!(
+            compile_with_options(parse("a = let b = 1; b = 2 in b"), &options),
+            Err(CompileError::DupIdent(..))
+        ));
+    }
+
+    #[test]
+    fn to_value() {
+        let src = "a = [1, 2]; f x = x + a; b = [NaN, \"s\"]";
+        let parse = || {
+            ProgramParser::new()
+                .parse(&OperatorTable::default(), src)
+                .unwrap()
+        };
+        let value = compile_to_value(parse()).unwrap();
+        let json = serde_json::to_string(&compile(parse()).unwrap()).unwrap();
+        assert_eq!(value, serde_json::from_str::<Value>(&json).unwrap());
+        assert_eq!(value["a"], serde_json::json!({ "t": "m", "v": [1., 2.] }));
+        assert_eq!(value["f"]["t"], "f");
+
+        let err = compile_to_value(
+            ProgramParser::new()
+                .parse(&OperatorTable::default(), "a = b")
+                .unwrap(),
+        );
+        assert!(matches!(err, Err(CompileError::CantResolve(..))));
+    }
+
+    #[test]
+    fn deserialize() {
+        let src = r#"
+            a = [x, y, z, "s", null, true, [1, 2]];
+            x = 1;
+            y = \a b -> let c = a * 2 in c + b;
+            z = if x > 1 then 2 else 3
+        "#;
+        let defs = compile_src(src).unwrap();
+        let json = serde_json::to_string(&defs).unwrap();
+        let deserialized: Defs = serde_json::from_str(&json).unwrap();
+        assert_eq!(deserialized, defs);
+    }
+
+    #[test]
+    fn extra_stdlib() {
+        let src = "a = str_upper \"hello\"";
+        let prog = || {
+            ProgramParser::new()
+                .parse(&OperatorTable::default(), src)
+                .unwrap()
+        };
+        assert!(matches!(
+            compile(prog()),
+            Err(CompileError::CantResolve(..))
+        ));
+
+        let extra = vec!["str_upper".to_string()].into_iter().collect();
+        let defs = compile_with_stdlib(prog(), &extra).unwrap();
+        assert!(matches!(&defs["a"], Def::Call { f, .. } if f == "str_upper"));
+
+        // extra names can't be redefined, just like the stdlib
+        let prog = ProgramParser::new()
+            .parse(&OperatorTable::default(), "str_upper = 1")
+            .unwrap();
+        assert!(matches!(
+            compile_with_stdlib(prog, &extra),
+            Err(CompileError::DupIdent(..))
+        ));
+    }
+
+    #[test]
+    fn arity() {
+        let src = "a = floor 1 2 3";
+        match compile_src(src) {
+            Err(CompileError::Arity(name, expected, got, span)) => {
+                assert_eq!(name, "floor");
+                assert_eq!(expected, 1..=1);
+                assert_eq!(got, 3);
+                assert_eq!(&src[span], "floor");
+            }
+            res => panic!("expected arity error, got {:?}", res),
+        }
+        assert!(matches!(
+            compile_src("a = [1] |> map id 1"),
+            Err(CompileError::Arity(..))
+        ));
+        assert!(matches!(
+            compile_src("a = tz_utc 1"),
+            Err(CompileError::Arity(..))
+        ));
+
+        // bare references and shadowed names aren't calls to the stdlib
+        assert!(compile_src("a = map floor [1.5]; b = ts_now").is_ok());
+        assert!(compile_src("f floor = floor 1 2 3").is_ok());
+        assert!(compile_src("a = let floor x y = x in floor 1 2").is_ok());
+    }
+
+    #[test]
+    fn partial_application() {
+        let defs = compile_src(
+            "a = map ((+) 1) [1]; f x y z = x; b = f 1; c = 1 `f` 2; \
+             d = let g = \\x y -> x in g 1; e = [1] |> map; h = (-) 1 2",
+        )
+        .unwrap();
+        // returns the function and its body's call
+        let partial = |def: &Def| match def {
+            Def::Fn { params, body } => match &body["="] {
+                Def::Call { f, args } => {
+                    assert_eq!(args[args.len() - params.len()..], params[..]);
+                    (
+                        params.len(),
+                        f.clone(),
+                        args[..args.len() - params.len()].to_vec(),
+                    )
+                }
+                def => panic!("expected a call, got {:?}", def),
+            },
+            def => panic!("expected a function, got {:?}", def),
+        };
+
+        let arg = match &defs["a"] {
+            Def::Call { f, args } if f == "map" => &args[0],
+            def => panic!("expected a call to map, got {:?}", def),
+        };
+        let (missing, f, args) = partial(&defs[arg]);
+        assert_eq!((missing, &*f), (1, "+"));
+        assert_eq!(defs[&args[0]], Def::Number { value: 1. });
+
+        let (missing, f, args) = partial(&defs["b"]);
+        assert_eq!((missing, &*f, args.len()), (2, "f", 1));
+        let (missing, f, args) = partial(&defs["c"]);
+        assert_eq!((missing, &*f, args.len()), (1, "f", 2));
+        let (missing, f, args) = partial(&defs["d"]);
+        assert_eq!((missing, args.len()), (1, 1));
+        assert!(crate::passes::is_private(&f) && f.ends_with('g'));
+        // `map [1]`
+        let (missing, f, args) = partial(&defs["e"]);
+        assert_eq!((missing, &*f, args.len()), (1, "map", 1));
+
+        // parameters don't shadow the function or the arguments
+        for (id, def) in &defs {
+            if let Def::Fn { params, .. } = def {
+                assert!(
+                    params.iter().all(|param| !defs.contains_key(param)),
+                    "{}",
+                    id
+                );
+            }
+        }
+
+        // applications with all arguments are still calls
+        assert!(matches!(&defs["h"], Def::Call { f, args } if f == "-" && args.len() == 2));
+        let defs = compile_src("f x y = x; a = f 1 2; b = (\\x -> \\y -> x) 1").unwrap();
+        assert!(matches!(&defs["a"], Def::Call { args, .. } if args.len() == 2));
+        assert!(matches!(&defs["b"], Def::Call { args, .. } if args.len() == 1));
+        // parameters aren't known to be functions
+        let defs = compile_src("f x y = x; g f = f 1").unwrap();
+        let body = match &defs["g"] {
+            Def::Fn { body, .. } => body,
+            def => panic!("expected a function, got {:?}", def),
+        };
+        assert!(matches!(&body["="], Def::Call { f, .. } if f == "f"));
+    }
+
+    #[test]
+    fn stdlib_references() {
+        let defs = compile_src(
+            "a = map id [1]; b = map (`+`) [1]; c = id; d = if true then floor else (`mod`); \
+             e = ts_now; f = [ts_now, tz_utc]; g = id 1; h = let id = 2 in id",
+        )
+        .unwrap();
+        // passed as arguments, they're references
+        assert!(matches!(&defs["a"], Def::Call { f, args } if f == "map" && args[0] == "id"));
+        assert!(matches!(&defs["b"], Def::Call { args, .. } if args[0] == "+"));
+
+        // on their own, functions are wrapped so they aren't called without arguments
+        let wrapper = |id: &str, f: &str, arity: usize| match &defs[id] {
+            Def::Fn { params, body } => {
+                assert_eq!(params.len(), arity);
+                assert_eq!(
+                    body["="],
+                    Def::Call {
+                        f: f.into(),
+                        args: params.clone()
+                    }
+                );
+            }
+            def => panic!("expected a function, got {:?}", def),
+        };
+        wrapper("c", "id", 1);
+        let cases = match &defs["d"] {
+            Def::Switch { cases } => cases,
+            def => panic!("expected a switch, got {:?}", def),
+        };
+        wrapper(&cases[0].value, "floor", 1);
+        wrapper(&cases[1].value, "mod", 2);
+
+        // definitions without arguments are called everywhere
+        assert_eq!(
+            defs["e"],
+            Def::Call {
+                f: "ts_now".into(),
+                args: Vec::new()
+            }
+        );
+        let items = match &defs["f"] {
+            Def::List { items } => items,
+            def => panic!("expected a list, got {:?}", def),
+        };
+        for (item, f) in items.iter().zip(&["ts_now", "tz_utc"]) {
+            assert_eq!(
+                defs[item],
+                Def::Call {
+                    f: f.to_string(),
+                    args: Vec::new()
+                }
+            );
+        }
+
+        // applications are calls, and shadowed names are plain references
+        assert!(matches!(&defs["g"], Def::Call { f, args } if f == "id" && args.len() == 1));
+        assert!(matches!(&defs["h"], Def::Call { args, .. } if args.is_empty()));
+    }
+
+    #[test]
+    fn reserved_names() {
+        let src = "`if` = 1; f _x = 2";
+        let prog = ProgramParser::new()
+            .parse(&OperatorTable::default(), src)
+            .unwrap();
+        let errors = match compile_collecting(prog, &CompileOptions::default()) {
+            Err(errors) => errors,
+            Ok(_) => panic!("expected errors"),
+        };
+        let names: Vec<_> = errors
+            .iter()
+            .map(|err| match err {
+                CompileError::ReservedIdent(name, span) => {
+                    assert!(src[span.clone()].contains(name.as_str()));
+                    name.as_str()
+                }
+                err => panic!("expected reserved identifier, got {:?}", err),
+            })
+            .collect();
+        assert_eq!(names, ["if", "_x"]);
+
+        for src in &[
+            "@a = 1",
+            "a = let `null` = 1 in 2",
+            "f = \\_0 -> 1",
+            "`NaN` = 1",
+        ] {
+            assert!(
+                matches!(compile_src(src), Err(CompileError::ReservedIdent(..))),
+                "{}",
+                src
+            );
+        }
+        for src in &["` a` = 1", "`a\tb` = 1"] {
+            assert!(
+                matches!(compile_src(src), Err(CompileError::InvalidIdent(..))),
+                "{}",
+                src
+            );
+        }
+        assert!(compile_src("a' = 1; b_2 = a'; `<+>` = \\a b -> a; iffy = 1").is_ok());
+        assert!(compile_src("`1a` = 1; `+a` = 2; `my field` = (`1a`) + (`+a`)").is_ok());
+    }
+
+    #[test]
+    fn private_ids() {
+        assert!(matches!(
+            compile_src("_0 = 1; a = [1 + 2]"),
+            Err(CompileError::ReservedIdent(..))
+        ));
+        assert!(matches!(
+            compile_src("a = [1 + 2]; b = _0"),
+            Err(CompileError::CantResolve(name, _)) if name == "_0"
+        ));
+
+        // even if a reserved name is declared anyway, generated ids don't clash with it, including
+        // those generated in nested scopes
+        let mut ctx = CompileContext::global(&HashSet::new(), &default_aliases());
+        ctx.errors = Some(RefCell::new(Vec::new()));
+        let user = ctx.add_ident(&Ident::new("_0", 0..2)).unwrap();
+        let mut ids = vec![user, ctx.next_priv("")];
+        {
+            let mut sub_ctx = ctx.create_pseudo_child();
+            ids.push(sub_ctx.add_ident(&Ident::new("_1", 0..2)).unwrap());
+            ids.push(sub_ctx.next_priv(""));
+        }
+        ids.push(ctx.next_priv(""));
+        let unique: HashSet<_> = ids.iter().collect();
+        assert_eq!(unique.len(), ids.len(), "{:?}", ids);
+        assert_eq!(ctx.errors.unwrap().into_inner().len(), 2);
+    }
+
+    #[test]
+    fn date_literals() {
+        let defs =
+            compile_src("a = @2024-01-15; b = @2024-01-15T10:30:00Z; c = [@2024-01-15]").unwrap();
+        assert!(matches!(&defs["a"], Def::String { value } if value == "2024-01-15"));
+        match &defs["b"] {
+            Def::Call { f, args } => {
+                assert_eq!(f, "ts_parse");
+                assert!(matches!(
+                    &defs[&args[0]],
+                    Def::String { value } if value == "2024-01-15T10:30:00Z"
+                ));
+            }
+            def => panic!("expected call, got {:?}", def),
+        }
+        assert!(matches!(&defs["c"], Def::Matrix { .. }));
+
+        // a parameter named `ts_parse` doesn't capture the call
+        let defs = compile_src("f ts_parse = @2024-01-15T10:30Z").unwrap();
+        match &defs["f"] {
+            Def::Fn { params, body } => {
+                assert_ne!(params[0], "ts_parse");
+                assert!(matches!(&body["="], Def::Call { f, .. } if f == "ts_parse"));
+            }
+            def => panic!("expected function, got {:?}", def),
+        }
+    }
+
+    #[test]
+    fn chained_comparisons() {
+        let defs = compile_src("a x = 0 <= f x < 10 <= x; f = \\x -> x").unwrap();
+        let body = match &defs["a"] {
+            Def::Fn { body, .. } => body,
+            def => panic!("expected function, got {:?}", def),
+        };
+        let call = |id: &str| match &body[id] {
+            Def::Call { f, args } => (f.as_str(), args.clone()),
+            def => panic!("expected call, got {:?}", def),
+        };
+
+        // (0 <= f x and f x < 10) and 10 <= x
+        let (f, args) = call("=");
+        assert_eq!(f, "and");
+        let (last_op, last_args) = call(&args[1]);
+        assert_eq!(last_op, "<=");
+        assert_eq!(last_args[1], "x");
+        let (f, args) = call(&args[0]);
+        assert_eq!(f, "and");
+        let (first_op, first_args) = call(&args[0]);
+        let (second_op, second_args) = call(&args[1]);
+        assert_eq!((first_op, second_op), ("<=", "<"));
+        // middle operands are shared
+        assert_eq!(first_args[1], second_args[0]);
+        assert_eq!(second_args[1], last_args[0]);
+        assert_eq!(call(&first_args[1]).0, "f");
+
+        // parentheses and other operators break the chain
+        let defs = compile_src("a = (1 < 2) < 3; b = 1 < 2 == true").unwrap();
+        assert!(matches!(&defs["a"], Def::Call { f, .. } if f == "<"));
+        assert!(matches!(&defs["b"], Def::Call { f, .. } if f == "=="));
+        // and so do user-defined comparisons
+        let defs = compile_src("a = let `<` = \\a b -> a in 1 < 2 < 3").unwrap();
+        assert!(matches!(&defs["a"], Def::Call { f, .. } if f.ends_with('<')));
+
+        // a parameter named `and` doesn't capture the call that joins the comparisons
+        let defs = compile_src("f and = 0 < 1 < 2").unwrap();
+        match &defs["f"] {
+            Def::Fn { params, body } => {
+                assert_ne!(params[0], "and");
+                assert!(matches!(&body["="], Def::Call { f, .. } if f == "and"));
+            }
+            def => panic!("expected function, got {:?}", def),
+        }
+    }
+
+    #[test]
+    fn resolve_external() {
+        let src = "a = @known + @unknown";
+        let prog = || {
+            ProgramParser::new()
+                .parse(&OperatorTable::default(), src)
+                .unwrap()
+        };
+        assert!(compile(prog()).is_ok());
+
+        let is_known = |name: &str| name == "known";
+        let options = CompileOptions {
+            resolve_external: Some(&is_known),
+            ..CompileOptions::default()
+        };
+        match compile_with_options(prog(), &options) {
+            Err(CompileError::CantResolve(name, span)) => {
+                assert_eq!(name, "@unknown");
+                assert_eq!(&src[span], "@unknown");
+            }
+            _ => panic!("expected unresolved external"),
+        }
+    }
+
+    #[test]
+    fn prefix_ops() {
+        let defs = compile_src("a = -(1 + 2); b = !true").unwrap();
+        match &defs["a"] {
+            Def::Call { f, args } => {
+                assert_eq!(f, "-");
+                assert!(matches!(&defs[&args[0]], Def::Number { value } if *value == 0.));
+                assert!(matches!(&defs[&args[1]], Def::Call { f, .. } if f == "+"));
+            }
+            _ => panic!("expected call"),
+        }
+        match &defs["b"] {
+            Def::Call { f, args } => {
+                assert_eq!(f, "not");
+                assert_eq!(args.len(), 1);
+            }
+            _ => panic!("expected call"),
+        }
+    }
+
+    #[test]
+    fn deep_nesting() {
+        let nested = |depth: usize, open: &str, close: &str| {
+            format!("x = 1; a = {}1{}", open.repeat(depth), close.repeat(depth))
+        };
+        // parentheses and else-if chains don't add to the depth
+        assert!(compile_src(&nested(10_000, "(", ")")).is_ok());
+        assert!(compile_src(&nested(10_000, "if x then 1 else ", "")).is_ok());
+
+        let kinds = [
+            ("[", "]"),
+            ("[x, ", "]"),
+            ("1 + (", ")"),
+            ("-(", ")"),
+            ("\\y -> ", ""),
+            ("let y = 1 in ", ""),
+            ("if (", ") then 1 else 2"),
+        ];
+        for (open, close) in &kinds {
+            assert!(matches!(
+                compile_src(&nested(10_000, open, close)),
+                Err(CompileError::TooDeep(DEFAULT_MAX_DEPTH))
+            ));
+            // anything within the limit must not overflow the stack
+            assert!(compile_src(&nested(DEFAULT_MAX_DEPTH - 10, open, close)).is_ok());
+        }
+
+        let prog = ProgramParser::new()
+            .parse(&OperatorTable::default(), &nested(100, "[x, ", "]"))
+            .unwrap();
+        let options = CompileOptions {
+            max_depth: Some(50),
+            ..CompileOptions::default()
+        };
+        assert!(matches!(
+            compile_with_options(prog, &options),
+            Err(CompileError::TooDeep(50))
+        ));
+    }
+
+    #[test]
+    fn let_block() {
+        // bindings can reference each other regardless of order
+        let src = "f x = let b = a * 2; a = x + 1; in b; g = let a = 1; b = a; in b";
+        assert!(compile_src(src).is_ok());
+        // a single binding may end with a `;` too
+        let body = |src: &str| crate::parse(src).unwrap().0.remove(0).body.to_string();
+        assert_eq!(body("f = let a = 1; in a"), body("f = let a = 1 in a"));
+        assert!(compile_src("f x = let a = x; in a").is_ok());
+        let src = "f n = let even n = n == 0 `or` odd (n - 1); odd n = n != 0 `and` even (n - 1) in even n";
+        assert!(compile_src(src).is_ok());
+
+        assert!(matches!(
+            compile_src("a = let b = 1; b = 2 in b"),
+            Err(CompileError::DupIdent(id, _)) if id == "b"
+        ));
+        assert!(matches!(
+            compile_src("a = let b = c; c = b in b"),
+            Err(CompileError::Cycle(_))
+        ));
+        // `where` bindings are in the same scope too
+        let src = "f x = b where b = a * 2, a = x + 1; g = f 1 where h = 2";
+        assert!(compile_src(src).is_ok());
+        assert!(matches!(
+            compile_src("a = b where b = 1, b = 2"),
+            Err(CompileError::DupIdent(id, _)) if id == "b"
+        ));
+        assert!(matches!(
+            compile_src("a = b where b = c, c = b"),
+            Err(CompileError::Cycle(_))
+        ));
+        // the comma form is still a sequence of nested scopes
+        assert!(compile_src("a = let b = 1, c = b + 1 in c").is_ok());
+        assert!(matches!(
+            compile_src("a = let b = c, c = 1 in b"),
+            Err(CompileError::CantResolve(id, _)) if id == "c"
+        ));
+    }
+
+    #[test]
+    fn blocks() {
+        let block = compile_src("f x = { b = a * 2; a = x + 1; a + b }").unwrap();
+        let let_in = compile_src("f x = let b = a * 2; a = x + 1 in a + b").unwrap();
+        assert_eq!(block, let_in);
+        // blocks nest, and can be used anywhere an expression can
+        let src = "a = map (\\x -> { y = x * 2; { z = y + 1; [y, z] } }) [1, 2]; b = { c = 1; c }";
+        assert!(compile_src(src).is_ok());
+        assert!(matches!(
+            compile_src("a = { b = 1; b = 2; b }"),
+            Err(CompileError::DupIdent(id, _)) if id == "b"
+        ));
+        assert!(matches!(
+            compile_src("a = { b = 1; c }"),
+            Err(CompileError::CantResolve(id, _)) if id == "c"
+        ));
+
+        // a block needs a value at the end
+        assert!(crate::parse("a = { b = 1; }").is_err());
+        assert!(crate::parse("a = { b = 1 }").is_err());
+        assert!(crate::parse("a = { b = 1; c = 2 }").is_err());
+        // without bindings, it's just a group
+        assert!(crate::parse("a = { 1 }").is_ok());
+    }
+
+    #[test]
+    fn subscripts() {
+        let call = |def: &Def| match def {
+            Def::Call { f, args } => (f.clone(), args.clone()),
+            def => panic!("expected call, got {:?}", def),
+        };
+
+        let defs = compile_src("xs = [1, 2]; a = xs[0]").unwrap();
+        let (f, args) = call(&defs["a"]);
+        assert_eq!(f, "index");
+        assert_eq!(args[0], "xs");
+        assert_eq!(defs[&args[1]], Def::Number { value: 0. });
+
+        // `xs[-2]` is `index xs (length xs - 2)`
+        let defs = compile_src("xs = [1, 2]; a = xs[-2]").unwrap();
+        let (f, args) = call(&defs["a"]);
+        assert_eq!((f.as_str(), args[0].as_str()), ("index", "xs"));
+        let (f, index_args) = call(&defs[&args[1]]);
+        assert_eq!(f, "-");
+        assert_eq!(
+            call(&defs[&index_args[0]]),
+            ("length".into(), vec!["xs".into()])
+        );
+        assert_eq!(defs[&index_args[1]], Def::Number { value: 2. });
+
+        // otherwise, it's only known at runtime whether the index counts from the end
+        let defs = compile_src("xs = [1, 2]; f i = xs[i]").unwrap();
+        let body = match &defs["f"] {
+            Def::Fn { body, .. } => body,
+            def => panic!("expected function, got {:?}", def),
+        };
+        let (f, args) = call(&body["="]);
+        assert_eq!((f.as_str(), args[0].as_str()), ("index", "xs"));
+        let cases = match &body[&args[1]] {
+            Def::Switch { cases } => cases,
+            def => panic!("expected switch, got {:?}", def),
+        };
+        let (f, cond_args) = call(&body[cases[0].cond.as_ref().unwrap()]);
+        assert_eq!(f, "<");
+        assert_eq!(cond_args[0], "i");
+        let (f, from_end_args) = call(&body[&cases[0].value]);
+        assert_eq!(f, "+");
+        assert_eq!(from_end_args[1], "i");
+        assert_eq!(
+            (cases[1].cond.as_ref(), cases[1].value.as_str()),
+            (None, "i")
+        );
+
+        assert!(compile_src("a = \\i -> @items[i * 2] ?? \"\"").is_ok());
+        assert!(matches!(
+            compile_src("a = xs[0]"),
+            Err(CompileError::CantResolve(id, span)) if id == "xs" && span == (4..6)
+        ));
+        // with a space, it's an application to a list
+        assert!(matches!(
+            compile_src("f = 1; a = f [0]").unwrap()["a"],
+            Def::Call { ref f, .. } if f == "f"
+        ));
+
+        // so is a list of any other length, and a list after a keyword
+        let body = |src: &str| crate::parse(src).unwrap().0.remove(0).body.to_string();
+        assert_eq!(body("a = sum[1, 2]"), "(apply sum (list 1 2))");
+        assert_eq!(body("a = sum[]"), "(apply sum (list))");
+        assert_eq!(
+            body("a = let b = 1 in[b, 2]"),
+            "(let ((decl b () 1)) (list b 2))"
+        );
+        assert_eq!(
+            body("a = if true then[1] else[2]"),
+            "(if true (list 1) (list 2))"
+        );
+        // anything can be subscripted, including another subscript
+        assert_eq!(body("a = m[0][1]"), "(index (index m 0) 1)");
+        assert_eq!(body("a = [1, 2][-1]"), "(index (list 1 2) -1)");
+        assert_eq!(body("a = (f x)[0]"), "(index (group (apply f x)) 0)");
+        // and it binds tighter than application and operators
+        assert_eq!(
+            body("a = f xs[0] + -ys[1]"),
+            "(apply + (apply f (index xs 0)) (unary - (index ys 1)))"
+        );
+
+        let defs = compile_src("m = [[1, 2]]; a = m[0][-1]").unwrap();
+        let (f, args) = call(&defs["a"]);
+        assert_eq!(f, "index");
+        assert_eq!(call(&defs[&args[0]]).1[0], "m");
+        let defs = compile_src("f x = [x]; a = (f 1)[0]").unwrap();
+        let (_, args) = call(&defs["a"]);
+        assert_eq!(call(&defs[&args[0]]).0, "f");
+
+        // parameters don't capture the functions a subscript is compiled to
+        let defs = compile_src("f length xs = xs[-1]").unwrap();
+        let (params, body) = match &defs["f"] {
+            Def::Fn { params, body } => (params, body),
+            def => panic!("expected function, got {:?}", def),
+        };
+        assert_ne!(params[0], "length");
+        let (_, args) = call(&body["="]);
+        let (_, index_args) = call(&body[&args[1]]);
+        assert_eq!(call(&body[&index_args[0]]).0, "length");
+    }
+
+    #[test]
+    fn source_map() {
+        fn check_all(defs: &Defs, path: &[Id], spans: &SourceMap) {
+            for (id, def) in defs {
+                let mut path = path.to_vec();
+                path.push(id.clone());
+                assert!(spans.contains_key(&path), "{:?} has no span", path);
+                if let Def::Fn { body, .. } = def {
+                    check_all(body, &path, spans);
+                }
+            }
+        }
+
+        let src = "a = 1; b = a + 2 * a; f x = \\y -> x y; c = [1, a]";
+        let prog = crate::parse(src).unwrap();
+        let (defs, spans) =
+            compile_with_source_map(prog.clone(), &CompileOptions::default()).unwrap();
+        assert_eq!(defs, compile(prog).unwrap());
+        check_all(&defs, &[], &spans);
+        let span = |path: &[&str]| {
+            let path: Vec<_> = path.iter().map(|id| id.to_string()).collect();
+            &src[spans[&path].clone()]
+        };
+
+        assert_eq!(span(&["a"]), "1");
+        assert_eq!(span(&["b"]), "a + 2 * a");
+        // `2 * a`
+        let mul = defs.iter().find_map(|(id, def)| match def {
+            Def::Call { f, .. } if f == "*" => Some(id.as_str()),
+            _ => None,
+        });
+        assert_eq!(span(&[mul.unwrap()]), "2 * a");
+        assert_eq!(span(&["f"]), "f x = \\y -> x y");
+        assert_eq!(span(&["f", "="]), "\\y -> x y");
+        assert_eq!(span(&["f", "=", "="]), "x y");
+        assert_eq!(span(&["c"]), "[1, a]");
+        // literals have their own span
+        let one = match &defs["c"] {
+            Def::List { items } => items[0].as_str(),
+            def => panic!("expected list, got {:?}", def),
+        };
+        assert_eq!(span(&[one]), "1");
+    }
 }
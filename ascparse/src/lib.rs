@@ -0,0 +1,271 @@
+//! Parses a loosely Haskell-like language into AKSO script definitions.
+
+use lalrpop_util::lalrpop_mod;
+use std::iter;
+
+pub mod ast;
+pub mod completion;
+pub mod decompile;
+pub mod diagnostic;
+pub mod format;
+pub mod ir;
+pub mod lint;
+pub mod parse_error;
+pub mod passes;
+pub mod types;
+pub mod typescript;
+pub mod visit;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+lalrpop_mod!(pub grammar);
+
+pub use ast::{Associativity, Expr, OperatorTable, Program, SyntaxError};
+pub use diagnostic::Diagnostic;
+pub use grammar::{ExpressionParser, ProgramParser};
+pub use ir::{CompileError, CompileOptions, Defs};
+pub use parse_error::ParseError;
+
+/// Parses source code using the built-in operator table. Groups, lists, subscripts and lambdas may
+/// be nested at most `ir::DEFAULT_MAX_DEPTH` levels deep, the same limit as in the compiler.
+pub fn parse(src: &str) -> Result<Program, ParseError> {
+    parse_with_max_depth(src, ir::DEFAULT_MAX_DEPTH)
+}
+
+/// Like `parse`, but fails with `SyntaxError::TooDeep` if groups, lists, subscripts or lambdas are
+/// nested more deeply than `max_depth`. Chains of operators and applications (e.g. `a + b + c`)
+/// don't add to the depth.
+pub fn parse_with_max_depth(src: &str, max_depth: usize) -> Result<Program, ParseError> {
+    let prog = ProgramParser::new()
+        .parse(&OperatorTable::default(), src)
+        .map_err(|err| ParseError::from(err.map_token(|token| token.1.to_string())))?;
+    if let Err(error) = ast::check_nesting(prog.0.iter().map(|decl| &decl.body), max_depth) {
+        ast::drop_nested(prog.0.into_iter().map(|decl| decl.body));
+        return Err(ParseError::User { error });
+    }
+    Ok(prog)
+}
+
+/// Like `parse`, but a backslash in a string literal that isn't followed by a known escape is an
+/// error (`ast::StringParseError::UnknownEscape`) rather than being left out.
+pub fn parse_strict(src: &str) -> Result<Program, ParseError> {
+    let prog = parse(src)?;
+    let mut errors = Vec::new();
+    lint::for_each_expr(&prog, |expr| {
+        // raw strings have no escapes
+        let literal = src.get(expr.span.clone()).filter(|s| s.starts_with('"'));
+        if let (ast::ExprKind::String(_), Some(literal)) = (&expr.kind, literal) {
+            if let Err(err) = ast::parse_string(literal.into(), expr.span.start, true) {
+                errors.push((expr.span.start, err));
+            }
+        }
+    });
+    match errors.into_iter().min_by_key(|(start, _)| *start) {
+        Some((_, err)) => Err(ParseError::User { error: err.into() }),
+        None => Ok(prog),
+    }
+}
+
+/// Parses a single expression (rather than declarations) using the built-in operator table, with
+/// the same nesting limit as `parse`.
+pub fn parse_expr(src: &str) -> Result<Expr, ParseError> {
+    let expr = ExpressionParser::new()
+        .parse(&OperatorTable::default(), src)
+        .map_err(|err| ParseError::from(err.map_token(|token| token.1.to_string())))?;
+    if let Err(error) = ast::check_nesting(iter::once(&expr), ir::DEFAULT_MAX_DEPTH) {
+        ast::drop_nested(iter::once(expr));
+        return Err(ParseError::User { error });
+    }
+    Ok(expr)
+}
+
+/// Parses and compiles source code to AKSO script definitions.
+pub fn compile_str(src: &str) -> Result<Defs, CompileError> {
+    ir::compile(parse(src).map_err(CompileError::Parse)?)
+}
+
+/// Parses and compiles a single expression to definitions, where `out` is the id of its value (see
+/// `ir::compile_expression`).
+pub fn compile_expr_str(src: &str, out: &str) -> Result<Defs, CompileError> {
+    ir::compile_expression(parse_expr(src).map_err(CompileError::Parse)?, out.into())
+}
+
+/// Like `compile_str`, but with options, and also runs the lints in `lint`. Everything found is
+/// returned as diagnostics: on success, the warnings (ordered by position) along with the
+/// definitions, and on failure, the warnings followed by the error.
+pub fn compile_with_diagnostics(
+    src: &str,
+    options: &CompileOptions,
+) -> Result<(Defs, Vec<Diagnostic>), Vec<Diagnostic>> {
+    let parsed = if options.strict {
+        parse_strict(src)
+    } else {
+        parse(src)
+    };
+    let prog = match parsed {
+        Ok(prog) => prog,
+        Err(err) => return Err(vec![CompileError::Parse(err).diagnostic(src)]),
+    };
+    let mut warnings = lint::shadowed_names(&prog);
+    warnings.extend(lint::imprecise_numbers(&prog));
+    warnings.extend(lint::unknown_escapes(&prog, src));
+    warnings.sort_by_key(|warning| warning.span().start);
+    let mut diagnostics: Vec<_> = warnings
+        .iter()
+        .map(|warning| warning.diagnostic(src))
+        .collect();
+    match ir::compile_with_options(prog, options) {
+        Ok(defs) => Ok((defs, diagnostics)),
+        Err(err) => {
+            diagnostics.push(err.diagnostic(src));
+            Err(diagnostics)
+        }
+    }
+}
+
+/// The same as `compile_str`; the entry point for fuzzing (see `fuzz/`). Deeply nested input is
+/// rejected rather than overflowing the stack, but nothing else guarantees that compiling doesn't
+/// panic: a panic found by fuzzing is a bug.
+pub fn try_compile(src: &str) -> Result<Defs, CompileError> {
+    compile_str(src)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compile_str_errors() {
+        assert!(compile_str("a = 1 + 2").unwrap().contains_key("a"));
+        assert!(matches!(
+            compile_str("a = 1 +"),
+            Err(CompileError::Parse(_))
+        ));
+        assert!(matches!(
+            compile_str("a = b"),
+            Err(CompileError::CantResolve(id, span)) if id == "b" && span == (4..5)
+        ));
+    }
+
+    #[test]
+    fn compile_expressions() {
+        let src = "map (\\x -> x * 2) [1, 2 + length \"ab\"]";
+        let defs = compile_expr_str(src, "result").unwrap();
+        assert!(matches!(&defs["result"], ir::Def::Call { f, .. } if f == "map"));
+        assert!(defs
+            .keys()
+            .all(|id| id == "result" || passes::is_private(id)));
+        // the same as the body of a declaration
+        assert_eq!(defs, compile_str(&format!("result = {}", src)).unwrap());
+
+        // the output id is never generated for anything else
+        let err = compile_expr_str("[1, x]", "_0").unwrap_err();
+        assert!(matches!(err, CompileError::CantResolve(id, _) if id == "x"));
+        let defs = compile_expr_str("1 + 2 * 3", "_1").unwrap();
+        assert_eq!(defs.len(), 5);
+        assert!(matches!(&defs["_1"], ir::Def::Call { f, .. } if f == "+"));
+
+        assert!(matches!(
+            compile_expr_str("1 +", "a"),
+            Err(CompileError::Parse(_))
+        ));
+        assert!(matches!(
+            compile_expr_str("a = 1", "a"),
+            Err(CompileError::Parse(_))
+        ));
+        assert!(matches!(
+            compile_expr_str(&format!("{}1{}", "[".repeat(300), "]".repeat(300)), "a"),
+            Err(CompileError::Parse(ParseError::User {
+                error: SyntaxError::TooDeep(_)
+            }))
+        ));
+    }
+
+    #[test]
+    fn comments() {
+        let src = "// leading
+            a = 1 + /* inline */ 2; // trailing
+            /* between
+               declarations */
+            f x = [x, /* * / */ x] // no semicolon
+                `mod` 2;
+            s = \"// not /* a comment\"
+            // at the end";
+        // replacing comments with spaces keeps the spans the same
+        let comments = [
+            "// leading",
+            "/* inline */",
+            "// trailing",
+            "/* between\n               declarations */",
+            "/* * / */",
+            "// no semicolon",
+            "// at the end",
+        ];
+        let mut without = src.to_string();
+        for comment in &comments {
+            assert!(without.contains(comment));
+            without = without.replace(comment, &" ".repeat(comment.len()));
+        }
+        assert_eq!(parse(src).unwrap(), parse(&without).unwrap());
+        // an unterminated block comment is lexed as an operator instead
+        assert!(compile_str("a = 1 /* unterminated").is_err());
+    }
+
+    #[test]
+    fn parse_depth() {
+        let too_deep = |result| {
+            matches!(
+                result,
+                Err(ParseError::User {
+                    error: SyntaxError::TooDeep(_)
+                })
+            )
+        };
+        let nested = |depth: usize, open: &str, close: &str| {
+            format!("a = {}1{}", open.repeat(depth), close.repeat(depth))
+        };
+        for (open, close) in &[
+            ("(", ")"),
+            ("[", "]"),
+            ("f (", ")"),
+            ("\\x -> ", ""),
+            ("xs[", "]"),
+        ] {
+            assert!(too_deep(parse(&nested(10_000, open, close))), "{}", open);
+            assert!(parse(&nested(ir::DEFAULT_MAX_DEPTH, open, close)).is_ok());
+        }
+        // chains aren't nested in the source, though they may be too deep to compile
+        let chain = format!("a = 1{}", " + 1".repeat(1000));
+        assert!(parse(&chain).is_ok());
+        assert!(parse(&format!("a = f{}", " x".repeat(1000))).is_ok());
+        assert!(parse(&format!("a = xs{}", "[0]".repeat(1000))).is_ok());
+        assert!(matches!(compile_str(&chain), Err(CompileError::TooDeep(_))));
+
+        let src = nested(20, "[", "]");
+        assert!(parse_with_max_depth(&src, 20).is_ok());
+        assert!(matches!(
+            parse_with_max_depth(&src, 19),
+            Err(ParseError::User {
+                error: SyntaxError::TooDeep(19)
+            })
+        ));
+        assert!(matches!(
+            compile_str(&nested(1000, "[", "]")),
+            Err(CompileError::Parse(ParseError::User {
+                error: SyntaxError::TooDeep(ir::DEFAULT_MAX_DEPTH)
+            }))
+        ));
+    }
+
+    #[test]
+    fn try_compile_deep() {
+        // these aren't limited by the parser, and are too deep to drop recursively
+        for prefix in &["- ", "! ", "let x = 1 in ", "if x then 1 else "] {
+            let src = format!("a = {}1", prefix.repeat(20_000));
+            assert!(try_compile(&src).is_err(), "{}", prefix);
+        }
+        for (open, close) in &[("(", ")"), ("[", "]"), ("\\x -> ", "")] {
+            let src = format!("a = {}1{}", open.repeat(20_000), close.repeat(20_000));
+            assert!(try_compile(&src).is_err(), "{}", open);
+        }
+    }
+}
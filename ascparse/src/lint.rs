@@ -0,0 +1,383 @@
+//! Warnings about code that compiles, but might not do what was intended.
+//!
+//! Lints are opt-in: compiling never runs them, and it's up to the caller whether a warning should
+//! be treated as an error.
+
+use crate::ast::{loses_precision, Decl, Expr, ExprKind, Ident, Program};
+use crate::ir::is_stdlib;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warning {
+    /// A `let` or `where` binding or a parameter has the same name as a definition in an enclosing
+    /// scope, which can then no longer be referenced (name, span, span of the outer definition or
+    /// `None` if it's in the standard library).
+    Shadow(String, Range<usize>, Option<Range<usize>>),
+    /// An integer literal that can't be represented exactly as a number, and is rounded (literal,
+    /// the number it's rounded to, span).
+    ImpreciseNumber(String, f64, Range<usize>),
+    /// A backslash in a string literal followed by a character that isn't a known escape, which is
+    /// left out (the character, span of the escape).
+    UnknownEscape(char, Range<usize>),
+}
+
+impl Warning {
+    /// Returns a short, stable identifier for the kind of warning.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Warning::Shadow(..) => "shadow",
+            Warning::ImpreciseNumber(..) => "imprecise_number",
+            Warning::UnknownEscape(..) => "unknown_escape",
+        }
+    }
+
+    /// Returns the byte offsets in the source that caused this warning.
+    pub fn span(&self) -> Range<usize> {
+        match self {
+            Warning::Shadow(_, span, _)
+            | Warning::ImpreciseNumber(_, _, span)
+            | Warning::UnknownEscape(_, span) => span.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::Shadow(name, _, Some(_)) => {
+                write!(f, "'{}' shadows a definition in an enclosing scope", name)
+            }
+            Warning::Shadow(name, _, None) => {
+                write!(f, "'{}' shadows a standard library definition", name)
+            }
+            Warning::ImpreciseNumber(literal, value, _) if value.is_infinite() => {
+                write!(f, "{} is too large to be represented as a number", literal)
+            }
+            Warning::ImpreciseNumber(literal, value, _) => write!(
+                f,
+                "{} can't be represented exactly as a number, and is rounded to {:.0}",
+                literal, value
+            ),
+            Warning::UnknownEscape(c, _) => write!(
+                f,
+                "unknown escape sequence \\{} in string literal, which is just {:?}",
+                c, c
+            ),
+        }
+    }
+}
+
+enum Task<'a> {
+    Expr(&'a Expr),
+    /// A declaration's parameters (if any) and body.
+    Decl(&'a Decl),
+    PopScope,
+}
+
+/// Names in scope and where they were declared.
+struct Scopes<'a> {
+    scopes: Vec<HashMap<&'a str, Range<usize>>>,
+    warnings: Vec<Warning>,
+}
+
+impl<'a> Scopes<'a> {
+    /// Declares names in a new scope, with a warning for each name that shadows another.
+    fn push(&mut self, names: impl Iterator<Item = &'a Ident>) {
+        let mut scope = HashMap::new();
+        for ident in names {
+            let name = &*ident.name;
+            let outer = self.scopes.iter().rev().find_map(|scope| scope.get(name));
+            if let Some(outer) = outer {
+                self.warn(ident, Some(outer.clone()));
+            } else if is_stdlib(name) {
+                self.warn(ident, None);
+            }
+            scope.insert(name, ident.span.clone());
+        }
+        self.scopes.push(scope);
+    }
+
+    fn warn(&mut self, ident: &Ident, outer: Option<Range<usize>>) {
+        self.warnings.push(Warning::Shadow(
+            ident.name.clone(),
+            ident.span.clone(),
+            outer,
+        ));
+    }
+}
+
+/// Finds `let` and `where` bindings and parameters that shadow names from an enclosing scope,
+/// including the standard library. Warnings are ordered by their position in the source.
+pub fn shadowed_names(prog: &Program) -> Vec<Warning> {
+    let mut scopes = Scopes {
+        scopes: Vec::new(),
+        warnings: Vec::new(),
+    };
+    // top-level declarations can't have stdlib names at all
+    let top_level = prog
+        .0
+        .iter()
+        .map(|decl| (&*decl.name.name, decl.name.span.clone()));
+    scopes.scopes.push(top_level.collect());
+
+    // an explicit stack, since expressions can be nested very deeply
+    let mut stack: Vec<_> = prog.0.iter().rev().map(Task::Decl).collect();
+    while let Some(task) = stack.pop() {
+        match task {
+            Task::Decl(decl) => {
+                if decl.params.is_empty() {
+                    stack.push(Task::Expr(&decl.body));
+                } else {
+                    scopes.push(decl.params.iter());
+                    stack.push(Task::PopScope);
+                    stack.push(Task::Expr(&decl.body));
+                }
+            }
+            Task::PopScope => {
+                scopes.scopes.pop();
+            }
+            Task::Expr(expr) => match &expr.kind {
+                ExprKind::Let(decls, inner) | ExprKind::Where(inner, decls) => {
+                    scopes.push(decls.iter().map(|decl| &decl.name));
+                    stack.push(Task::PopScope);
+                    stack.push(Task::Expr(inner));
+                    stack.extend(decls.iter().rev().map(Task::Decl));
+                }
+                ExprKind::Lambda(lambda) => {
+                    scopes.push(lambda.params.iter());
+                    stack.push(Task::PopScope);
+                    stack.push(Task::Expr(&lambda.body));
+                }
+                ExprKind::Group(expr) | ExprKind::Unary(_, expr) | ExprKind::Spread(expr) => {
+                    stack.push(Task::Expr(expr))
+                }
+                ExprKind::Apply(a, _, b) | ExprKind::Index(a, b) => {
+                    stack.push(Task::Expr(b));
+                    stack.push(Task::Expr(a));
+                }
+                ExprKind::List(items) => stack.extend(items.iter().rev().map(Task::Expr)),
+                ExprKind::If(c, t, e) => {
+                    stack.push(Task::Expr(e));
+                    stack.push(Task::Expr(t));
+                    stack.push(Task::Expr(c));
+                }
+                ExprKind::Match(arms) => {
+                    for (cond, value) in arms.iter().rev() {
+                        stack.push(Task::Expr(value));
+                        stack.extend(cond.as_ref().map(Task::Expr));
+                    }
+                }
+                ExprKind::Ident(_)
+                | ExprKind::Number(..)
+                | ExprKind::String(_)
+                | ExprKind::Bool(_)
+                | ExprKind::Null
+                | ExprKind::Date(_)
+                | ExprKind::DateTime(_) => (),
+            },
+        }
+    }
+
+    let mut warnings = scopes.warnings;
+    warnings.sort_by_key(|warning| warning.span().start);
+    warnings
+}
+
+/// Finds integer literals that are rounded because they can't be represented exactly as a number
+/// (a 64-bit float), such as integers larger than 2^53. The compiled value is still the rounded
+/// one. Warnings are ordered by their position in the source.
+pub fn imprecise_numbers(prog: &Program) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    for_each_expr(prog, |expr| {
+        if let ExprKind::Number(value, Some(literal)) = &expr.kind {
+            if loses_precision(&literal.text, *value) {
+                warnings.push(Warning::ImpreciseNumber(
+                    literal.text.clone(),
+                    *value,
+                    literal.span.clone(),
+                ));
+            }
+        }
+    });
+    warnings.sort_by_key(|warning| warning.span().start);
+    warnings
+}
+
+/// Finds backslashes in string literals that aren't followed by a known escape (e.g. `"\\q"`), which
+/// are left out, in the source the program was parsed from. Raw strings have no escapes. Warnings
+/// are ordered by their position in the source.
+pub fn unknown_escapes(prog: &Program, src: &str) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    for_each_expr(prog, |expr| {
+        if let ExprKind::String(_) = expr.kind {
+            // a literal, unless it's a raw string
+            let literal = src.get(expr.span.clone()).filter(|s| s.starts_with('"'));
+            for (c, span) in literal.map_or_else(Vec::new, crate::ast::unknown_escapes) {
+                let start = expr.span.start;
+                warnings.push(Warning::UnknownEscape(
+                    c,
+                    start + span.start..start + span.end,
+                ));
+            }
+        }
+    });
+    warnings.sort_by_key(|warning| warning.span().start);
+    warnings
+}
+
+/// Calls `f` with every expression in the program.
+pub(crate) fn for_each_expr<'a>(prog: &'a Program, mut f: impl FnMut(&'a Expr)) {
+    // an explicit stack, since expressions can be nested very deeply
+    let mut stack: Vec<_> = prog.0.iter().map(|decl| &decl.body).collect();
+    while let Some(expr) = stack.pop() {
+        f(expr);
+        match &expr.kind {
+            ExprKind::Let(decls, inner) | ExprKind::Where(inner, decls) => {
+                stack.extend(decls.iter().map(|decl| &decl.body));
+                stack.push(inner);
+            }
+            ExprKind::Lambda(lambda) => stack.push(&lambda.body),
+            ExprKind::Group(expr) | ExprKind::Unary(_, expr) | ExprKind::Spread(expr) => {
+                stack.push(expr)
+            }
+            ExprKind::Apply(a, _, b) | ExprKind::Index(a, b) => {
+                stack.push(a);
+                stack.push(b);
+            }
+            ExprKind::List(items) => stack.extend(items),
+            ExprKind::If(c, t, e) => {
+                stack.push(c);
+                stack.push(t);
+                stack.push(e);
+            }
+            ExprKind::Match(arms) => {
+                for (cond, value) in arms {
+                    stack.extend(cond);
+                    stack.push(value);
+                }
+            }
+            ExprKind::Ident(_)
+            | ExprKind::Number(..)
+            | ExprKind::String(_)
+            | ExprKind::Bool(_)
+            | ExprKind::Null
+            | ExprKind::Date(_)
+            | ExprKind::DateTime(_) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    fn shadows(src: &str) -> Vec<String> {
+        let warnings = shadowed_names(&parse(src).unwrap());
+        warnings
+            .iter()
+            .map(|warning| src[warning.span()].to_string())
+            .collect()
+    }
+
+    #[test]
+    fn shadow() {
+        let src = "a = 1; f a = let b = a, a = b in \\b -> a; g = a where a = \\map -> map";
+        let warnings = shadowed_names(&parse(src).unwrap());
+        let found: Vec<_> = warnings
+            .iter()
+            .map(|warning| match warning {
+                Warning::Shadow(name, span, outer) => {
+                    assert_eq!(&src[span.clone()], name);
+                    (span.start, outer.as_ref().map(|outer| outer.start))
+                }
+                warning => panic!("unexpected warning {:?}", warning),
+            })
+            .collect();
+        let at = |needle: &str, n: usize| src.match_indices(needle).nth(n).unwrap().0;
+        assert_eq!(
+            found,
+            [
+                // parameter `a` shadows the top-level `a`
+                (at("a", 1), Some(at("a", 0))),
+                // the second `let` binding shadows the parameter
+                (at("a = b", 0), Some(at("a", 1))),
+                // the lambda parameter shadows the first `let` binding
+                (at("\\b", 0) + 1, Some(at("b = a", 0))),
+                // the `where` binding shadows the top-level `a`
+                (at("a where", 0) + 8, Some(at("a", 0))),
+                (at("map", 0), None),
+            ]
+        );
+        assert_eq!(
+            warnings[4].to_string(),
+            "'map' shadows a standard library definition"
+        );
+        assert_eq!(
+            warnings[0].to_string(),
+            "'a' shadows a definition in an enclosing scope"
+        );
+
+        // bindings in the same scope don't shadow each other, and siblings don't either
+        assert!(
+            shadows("f x = x; g x = x; h = let a = 1; b = a in \\c -> c; i = \\c -> c").is_empty()
+        );
+        // top-level names are in scope everywhere, regardless of order
+        assert_eq!(
+            shadows("f = \\x -> x; x = 1; g = [\\x -> 1, \\sum -> 2]"),
+            ["x", "x", "sum"]
+        );
+    }
+
+    #[test]
+    fn imprecise_numbers() {
+        let src = "a = [9007199254740992, 9007199254740993, -9_007_199_254_740_993]; \
+                   b = 0x20000000000001 + 0b11111111111111111111111111111111111111111111111111111; \
+                   c = [1.1, 1e23, 0x1.8p3, 36rzzzzzzzzzz, 0xFFFFFFFFFFFFF800, 36rzzzzzzzzzzzzz]";
+        let warnings = super::imprecise_numbers(&parse(src).unwrap());
+        let found: Vec<_> = warnings
+            .iter()
+            .map(|warning| &src[warning.span()])
+            .collect();
+        assert_eq!(
+            found,
+            [
+                "9007199254740993",
+                "-9_007_199_254_740_993",
+                "0x20000000000001",
+                "36rzzzzzzzzzzzzz",
+            ]
+        );
+        assert_eq!(warnings[0].kind(), "imprecise_number");
+        assert_eq!(
+            warnings[1].to_string(),
+            "-9_007_199_254_740_993 can't be represented exactly as a number, and is rounded to \
+             -9007199254740992"
+        );
+
+        // hexadecimal literals can be too large
+        let prog = parse(&format!("a = 0x{}", "F".repeat(300))).unwrap();
+        assert!(matches!(
+            super::imprecise_numbers(&prog)[..],
+            [Warning::ImpreciseNumber(_, value, _)] if value.is_infinite()
+        ));
+    }
+
+    #[test]
+    fn unknown_escapes() {
+        let src = "a = \"\\q\\n\"; b = [r\"\\q\", (\"\\\\ \\ \")]; c = \\x -> \"\\u{41}\\%\"";
+        let warnings = super::unknown_escapes(&parse(src).unwrap(), src);
+        let escapes: Vec<_> = warnings
+            .iter()
+            .map(|warning| &src[warning.span()])
+            .collect();
+        assert_eq!(escapes, ["\\q", "\\ ", "\\%"]);
+        assert_eq!(warnings[0].kind(), "unknown_escape");
+        assert_eq!(
+            warnings[0].to_string(),
+            "unknown escape sequence \\q in string literal, which is just 'q'"
+        );
+    }
+}
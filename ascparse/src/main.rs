@@ -1,22 +1,144 @@
-use lalrpop_util::lalrpop_mod;
-use std::io::{stdin, Read};
+use serde_json::json;
+use std::env;
+use std::fs::File;
+use std::io::{self, stdin, Read};
+use std::process::exit;
 
-mod ast;
-mod ir;
-lalrpop_mod!(grammar);
+/// Inputs larger than this are rejected, since they'd have to be parsed all at once.
+const MAX_INPUT_SIZE: u64 = 32 * 1024 * 1024;
 
 fn main() {
-    let mut input = String::new();
-    stdin()
-        .lock()
-        .read_to_string(&mut input)
-        .expect("failed to read stdin");
-    match grammar::ProgramParser::new().parse(&input) {
-        Ok(decl) => println!(
-            "{}",
-            serde_json::to_string(&ir::compile(decl).expect("failed to compile"))
-                .expect("failed to serialize")
+    let args: Vec<_> = env::args().skip(1).collect();
+    let input = match &args[..] {
+        [] => read_stdin(),
+        [path] if path == "-" => read_stdin(),
+        [flag] if flag == "--typescript" => {
+            print!("{}", ascparse::typescript::definitions());
+            return;
+        }
+        [path] => read_file(path),
+        _ => fail(
+            "usage",
+            "usage: ascparse [FILE | - | --typescript]".into(),
+            None,
         ),
-        Err(err) => println!("{}", err),
+    };
+    match ascparse::compile_str(&input) {
+        Ok(defs) => println!("{}", json!({ "ok": true, "defs": defs })),
+        Err(err) => {
+            let diagnostic = err.diagnostic(&input);
+            let position = diagnostic
+                .position
+                .map(|position| json!({ "line": position.line, "column": position.column }));
+            let error = json!({
+                "kind": err.kind(),
+                "message": diagnostic.message,
+                "span": span_json(diagnostic.span.clone()),
+                "position": position,
+                "rendered": diagnostic.to_string(),
+            });
+            eprintln!("{}", json!({ "ok": false, "error": error }));
+            exit(1);
+        }
+    }
+}
+
+fn read_stdin() -> String {
+    read_limited(stdin().lock(), MAX_INPUT_SIZE, None).unwrap_or_else(|err| fail_read("stdin", err))
+}
+
+fn read_file(path: &str) -> String {
+    let name = path.to_string();
+    let file = File::open(path).unwrap_or_else(|err| fail_read(&name, ReadError::Io(err)));
+    // files can be rejected before reading them
+    let size = file.metadata().ok().map(|metadata| metadata.len());
+    if matches!(size, Some(size) if size > MAX_INPUT_SIZE) {
+        fail_read(&name, ReadError::TooLarge);
+    }
+    read_limited(file, MAX_INPUT_SIZE, size).unwrap_or_else(|err| fail_read(&name, err))
+}
+
+#[derive(Debug)]
+enum ReadError {
+    TooLarge,
+    OutOfMemory(u64),
+    Io(io::Error),
+}
+
+/// Reads UTF-8 text, failing once more than `limit` bytes have been read. If the size is known, the
+/// buffer is allocated up front so that running out of memory is reported instead of aborting.
+fn read_limited(reader: impl Read, limit: u64, size: Option<u64>) -> Result<String, ReadError> {
+    let mut buf = Vec::new();
+    if let Some(size) = size {
+        let size = size.min(limit);
+        buf.try_reserve_exact(size as usize)
+            .map_err(|_| ReadError::OutOfMemory(size))?;
+    }
+    reader
+        .take(limit + 1)
+        .read_to_end(&mut buf)
+        .map_err(ReadError::Io)?;
+    if buf.len() as u64 > limit {
+        return Err(ReadError::TooLarge);
+    }
+    String::from_utf8(buf)
+        .map_err(|err| ReadError::Io(io::Error::new(io::ErrorKind::InvalidData, err)))
+}
+
+/// Fails with an error from reading the input (`name` is the path or `stdin`).
+fn fail_read(name: &str, err: ReadError) -> ! {
+    match err {
+        ReadError::TooLarge => fail(
+            "too_large",
+            format!(
+                "{} is larger than the limit of {} MiB",
+                name,
+                MAX_INPUT_SIZE / 1024 / 1024
+            ),
+            None,
+        ),
+        ReadError::OutOfMemory(size) => fail(
+            "out_of_memory",
+            format!("not enough memory to read {} ({} bytes)", name, size),
+            None,
+        ),
+        ReadError::Io(err) if err.kind() == io::ErrorKind::InvalidData => {
+            fail("io", format!("{} is not valid UTF-8", name), None)
+        }
+        ReadError::Io(err) => fail("io", format!("failed to read {}: {}", name, err), None),
+    }
+}
+
+fn span_json(span: Option<std::ops::Range<usize>>) -> serde_json::Value {
+    json!(span.map(|span| json!({ "start": span.start, "end": span.end })))
+}
+
+/// Writes an error envelope to stderr and exits.
+fn fail(kind: &str, message: String, span: Option<std::ops::Range<usize>>) -> ! {
+    let error = json!({ "kind": kind, "message": message, "span": span_json(span) });
+    eprintln!("{}", json!({ "ok": false, "error": error }));
+    exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_limit() {
+        let read = |input: &[u8], size| read_limited(input, 4, size);
+        assert_eq!(read(b"abcd", None).unwrap(), "abcd");
+        assert_eq!(read(b"abcd", Some(4)).unwrap(), "abcd");
+        assert!(matches!(read(b"abcde", None), Err(ReadError::TooLarge)));
+        // the size is only a hint
+        assert!(matches!(read(b"abcde", Some(2)), Err(ReadError::TooLarge)));
+        assert!(matches!(
+            read(b"\xff", None),
+            Err(ReadError::Io(err)) if err.kind() == io::ErrorKind::InvalidData
+        ));
+        assert!(matches!(
+            read_limited(&b""[..], u64::MAX - 1, Some(u64::MAX - 1)),
+            Err(ReadError::OutOfMemory(_))
+        ));
     }
 }
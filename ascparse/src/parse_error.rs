@@ -0,0 +1,236 @@
+//! Errors found while parsing, and the kinds of tokens the parser expected instead.
+
+use crate::ast::SyntaxError;
+use std::error;
+use std::fmt;
+
+/// A parse error. Offsets are bytes in the source.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// Source that isn't any token.
+    InvalidToken { location: usize },
+    /// The source ended where the parser expected more.
+    UnrecognizedEOF {
+        location: usize,
+        expected: Vec<Expected>,
+    },
+    /// A token where the parser expected something else (the start, the token's text, the end).
+    UnrecognizedToken {
+        token: (usize, String, usize),
+        expected: Vec<Expected>,
+    },
+    /// A token after the end of what was parsed.
+    ExtraToken { token: (usize, String, usize) },
+    /// An error found by the parser actions rather than by the grammar itself.
+    User { error: SyntaxError },
+}
+
+/// A token the parser expected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expected {
+    /// A keyword or punctuation, as written (e.g. `let` or `(`).
+    Token(String),
+    Name,
+    /// A name in backticks.
+    QuotedName,
+    Operator,
+    Number,
+    String,
+    RawString,
+    Date,
+    DateTime,
+    DocComment,
+    Attribute,
+}
+
+/// The grammar's regex terminals, as lalrpop names them in lists of expected tokens, and the kind
+/// of token each of them matches.
+const REGEX_TERMINALS: &[(&str, Expected)] = &[
+    (
+        r##"r#"[_a-zA-Z@][a-zA-Z0-9!@#$%^&*_+\\-/'<>=|]*"#"##,
+        Expected::Name,
+    ),
+    (
+        r##"r#"`([^`\\\\\\n\\r]|\\\\[`\\\\])+`"#"##,
+        Expected::QuotedName,
+    ),
+    (r##"r#"[!#$%^&*+\\-/'<>=|]+"#"##, Expected::Operator),
+    (
+        r##"r#"[+\\-]?(0[bB][01_]+(\\.[01_]+)?|0[oO][0-7_]+(\\.[0-7_]+)?|0[xX][0-9a-fA-F_]+(\\.[0-9a-fA-F_]+)?([pP][+\\-]?[0-9_]+)?|[0-9]+r[0-9a-zA-Z_]+(\\.[0-9a-zA-Z_]+)?|((0|[1-9][0-9_]*)(\\.[0-9_]*)?|\\.[0-9_]+)(e[+\\-]?[0-9_]+)?)"#"##,
+        Expected::Number,
+    ),
+    (r##"r#"\"([^\\\\\"]|\\\\(.|\\n))*\""#"##, Expected::String),
+    (r##"r#"r\"[^\"]*\""#"##, Expected::RawString),
+    (r##"r#"@[0-9]{4}-[0-9]{2}-[0-9]{2}"#"##, Expected::Date),
+    (
+        r##"r#"@[0-9]{4}-[0-9]{2}-[0-9]{2}T[0-9]{2}:[0-9]{2}(:[0-9]{2})?(Z|[+\\-][0-9]{2}:[0-9]{2})"#"##,
+        Expected::DateTime,
+    ),
+    (r##"r#"///[^\\n\\r]*"#"##, Expected::DocComment),
+    (r##"r#"@\\[[^\\]\\n\\r]*\\]"#"##, Expected::Attribute),
+];
+
+impl Expected {
+    /// Returns the kind of token a terminal of the grammar (a quoted literal or a regex, as named
+    /// by lalrpop) matches. A regex that isn't in `REGEX_TERMINALS` is kept as-is.
+    fn from_terminal(terminal: &str) -> Expected {
+        // names are escaped like string literals
+        if let Some(literal) = terminal.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+            return Expected::Token(literal.replace("\\\"", "\"").replace("\\\\", "\\"));
+        }
+        REGEX_TERMINALS
+            .iter()
+            .find(|(regex, _)| *regex == terminal)
+            .map_or_else(
+                || Expected::Token(terminal.into()),
+                |(_, kind)| kind.clone(),
+            )
+    }
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            Expected::Token(token) => return write!(f, "`{}`", token),
+            Expected::Name => "a name",
+            Expected::QuotedName => "a quoted name",
+            Expected::Operator => "an operator",
+            Expected::Number => "a number",
+            Expected::String => "a string",
+            Expected::RawString => "a raw string",
+            Expected::Date => "a date",
+            Expected::DateTime => "a date-time",
+            Expected::DocComment => "a doc comment",
+            Expected::Attribute => "an attribute",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+impl From<lalrpop_util::ParseError<usize, String, SyntaxError>> for ParseError {
+    fn from(err: lalrpop_util::ParseError<usize, String, SyntaxError>) -> Self {
+        use lalrpop_util::ParseError as E;
+        let expected = |expected: Vec<String>| {
+            expected
+                .iter()
+                .map(|terminal| Expected::from_terminal(terminal))
+                .collect()
+        };
+        match err {
+            E::InvalidToken { location } => ParseError::InvalidToken { location },
+            E::UnrecognizedEOF {
+                location,
+                expected: e,
+            } => ParseError::UnrecognizedEOF {
+                location,
+                expected: expected(e),
+            },
+            E::UnrecognizedToken { token, expected: e } => ParseError::UnrecognizedToken {
+                token,
+                expected: expected(e),
+            },
+            E::ExtraToken { token } => ParseError::ExtraToken { token },
+            E::User { error } => ParseError::User { error },
+        }
+    }
+}
+
+/// Writes a list of expected tokens, e.g. ", expected `(` or a name".
+fn write_expected(f: &mut fmt::Formatter, expected: &[Expected]) -> fmt::Result {
+    let expected: Vec<_> = expected.iter().map(Expected::to_string).collect();
+    match expected.split_last() {
+        None => Ok(()),
+        Some((last, [])) => write!(f, ", expected {}", last),
+        Some((last, rest)) => write!(f, ", expected {} or {}", rest.join(", "), last),
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::InvalidToken { .. } => write!(f, "invalid token"),
+            ParseError::UnrecognizedEOF { expected, .. } => {
+                write!(f, "unexpected end of input")?;
+                write_expected(f, expected)
+            }
+            ParseError::UnrecognizedToken {
+                token: (_, token, _),
+                expected,
+            } => {
+                write!(f, "unexpected `{}`", token)?;
+                write_expected(f, expected)
+            }
+            ParseError::ExtraToken {
+                token: (_, token, _),
+            } => write!(f, "unexpected `{}` after the end", token),
+            ParseError::User { error } => write!(f, "{}", error),
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    #[test]
+    fn terminals() {
+        // every regex terminal of the generated parser is in the table
+        let grammar = include_str!(concat!(env!("OUT_DIR"), "/grammar.rs"));
+        let start = grammar.find("const __TERMINAL").unwrap();
+        let end = start + grammar[start..].find("];").unwrap();
+        let terminals: Vec<_> = grammar[start..end]
+            .lines()
+            .skip(1)
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                line.trim()
+                    .trim_start_matches("r###\"")
+                    .trim_end_matches("\"###,")
+            })
+            .collect();
+        let regexes: Vec<_> = terminals.iter().filter(|t| !t.starts_with('"')).collect();
+        assert_eq!(regexes.len(), REGEX_TERMINALS.len(), "{:?}", regexes);
+        for regex in regexes {
+            assert!(
+                REGEX_TERMINALS.iter().any(|(r, _)| r == regex),
+                "{} is missing",
+                regex
+            );
+        }
+        assert!(terminals.contains(&"\"let\""));
+        assert_eq!(
+            Expected::from_terminal("\"let\""),
+            Expected::Token("let".into())
+        );
+    }
+
+    #[test]
+    fn messages() {
+        let err = parse("a = ;").unwrap_err();
+        match &err {
+            ParseError::UnrecognizedToken { token, expected } => {
+                assert_eq!(token, &(4, ";".to_string(), 5));
+                for kind in &[Expected::Name, Expected::Number, Expected::DateTime] {
+                    assert!(expected.contains(kind), "{:?}", expected);
+                }
+                assert!(expected.contains(&Expected::Token("(".into())));
+            }
+            err => panic!("expected unrecognized token, got {:?}", err),
+        }
+        assert!(err.to_string().starts_with("unexpected `;`, expected `"));
+        assert!(err.to_string().contains("a date-time"));
+
+        let err = parse("a = (1").unwrap_err();
+        assert!(matches!(
+            &err,
+            ParseError::UnrecognizedEOF { location: 6, .. }
+        ));
+        assert!(err.to_string().ends_with("`)`"), "{}", err);
+        let err = parse("a = 1 )").unwrap_err().to_string();
+        assert!(err.starts_with("unexpected `)`, expected "), "{}", err);
+        assert!(err.contains("`;`") && err.contains("`where`"), "{}", err);
+    }
+}
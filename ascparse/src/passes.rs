@@ -0,0 +1,970 @@
+//! Analyses and transformations over compiled definitions.
+
+use crate::ir::{is_stdlib, Def, Defs, Id};
+use crate::visit::{walk_defs, walk_fn, DefVisitor};
+use std::collections::{HashMap, HashSet};
+
+/// Returns the ids in `defs` that are reachable from `roots`.
+///
+/// References that aren't defined in `defs` (i.e. those to an outer scope) are added to `escaped`.
+/// Function bodies of reachable functions are traversed as well, starting from their `=`
+/// definition.
+fn reachable<'a>(
+    defs: &'a Defs,
+    roots: impl IntoIterator<Item = &'a Id>,
+    escaped: &mut HashSet<&'a Id>,
+) -> HashSet<&'a Id> {
+    let mut reached = HashSet::new();
+    let mut stack: Vec<_> = roots.into_iter().collect();
+
+    while let Some(id) = stack.pop() {
+        let def = match defs.get(id) {
+            Some(def) => def,
+            None => {
+                escaped.insert(id);
+                continue;
+            }
+        };
+        if !reached.insert(id) {
+            continue;
+        }
+        stack.extend(def.refs());
+
+        if let Def::Fn { params, body } = def {
+            let mut body_escaped = HashSet::new();
+            let body_root = body.get_key_value("=").map(|(id, _)| id);
+            reachable(body, body_root, &mut body_escaped);
+            stack.extend(body_escaped.into_iter().filter(|id| !params.contains(id)));
+        }
+    }
+
+    reached
+}
+
+/// External (`@`-prefixed) definitions, which are always considered to be used.
+fn external_ids(defs: &Defs) -> impl Iterator<Item = &Id> {
+    defs.keys().filter(|id| id.starts_with('@'))
+}
+
+/// Returns top-level definitions that are not transitively referenced by any of the roots,
+/// in sorted order.
+pub fn unused_defs(defs: &Defs, roots: &[Id]) -> Vec<Id> {
+    let roots = roots.iter().chain(external_ids(defs));
+    let reached = reachable(defs, roots, &mut HashSet::new());
+    let mut unused: Vec<_> = defs
+        .keys()
+        .filter(|id| !reached.contains(id))
+        .cloned()
+        .collect();
+    unused.sort();
+    unused
+}
+
+/// Removes definitions that are not reachable from the given roots, including unused definitions
+/// inside function bodies. External (`@`-prefixed) definitions are always kept.
+pub fn prune(defs: Defs, roots: &[Id]) -> Defs {
+    let reached: HashSet<Id> = {
+        let roots = roots.iter().chain(external_ids(&defs));
+        reachable(&defs, roots, &mut HashSet::new())
+            .into_iter()
+            .cloned()
+            .collect()
+    };
+
+    defs.into_iter()
+        .filter(|(id, _)| reached.contains(id))
+        .map(|(id, def)| match def {
+            Def::Fn { params, body } => {
+                let body = prune(body, &["=".into()]);
+                (id, Def::Fn { params, body })
+            }
+            def => (id, def),
+        })
+        .collect()
+}
+
+/// Returns all ids referenced by definitions in `defs`, including references from function
+/// bodies to this scope.
+pub(crate) fn referenced_ids(defs: &Defs) -> HashSet<&Id> {
+    let mut refs = escaped_ids(defs);
+    for def in defs.values() {
+        refs.extend(def.refs());
+    }
+    refs
+}
+
+/// Returns ids referenced from function bodies in `defs` that aren't defined in those bodies.
+pub(crate) fn escaped_ids(defs: &Defs) -> HashSet<&Id> {
+    let mut refs = HashSet::new();
+    for def in defs.values() {
+        if let Def::Fn { params, body } = def {
+            refs.extend(
+                referenced_ids(body)
+                    .into_iter()
+                    .filter(|id| !params.contains(id) && !body.contains_key(*id)),
+            );
+        }
+    }
+    refs
+}
+
+/// Returns true for ids generated by the compiler, like `_0` or `_1name`.
+pub(crate) fn is_private(id: &str) -> bool {
+    let mut chars = id.chars();
+    chars.next() == Some('_') && matches!(chars.next(), Some(c) if c.is_ascii_digit())
+}
+
+/// Returns the ids a function closes over, i.e. those referenced in its body (including nested
+/// functions) that are neither parameters, defined in the body, stdlib names, nor external
+/// (`@`-prefixed). Returns an empty set for definitions that aren't functions.
+///
+/// The function is assumed to be defined at the top level; use `all_free_vars` for nested
+/// functions, whose parameters may shadow stdlib names.
+pub fn free_vars(def: &Def) -> HashSet<Id> {
+    match def {
+        Def::Fn { params, body } => fn_free_vars(params, body, &HashSet::new()),
+        _ => HashSet::new(),
+    }
+}
+
+/// Returns the free variables (see `free_vars`) of every function in `defs`, including nested
+/// ones. Functions are identified by the path of ids leading to them from the top level.
+pub fn all_free_vars(defs: &Defs) -> HashMap<Vec<Id>, HashSet<Id>> {
+    let mut out = HashMap::new();
+    collect_free_vars(defs, &mut Vec::new(), &HashSet::new(), &mut out);
+    out
+}
+
+fn collect_free_vars(
+    defs: &Defs,
+    path: &mut Vec<Id>,
+    bound: &HashSet<&Id>,
+    out: &mut HashMap<Vec<Id>, HashSet<Id>>,
+) {
+    for (id, def) in defs {
+        if let Def::Fn { params, body } = def {
+            path.push(id.clone());
+            out.insert(path.clone(), fn_free_vars(params, body, bound));
+            let mut bound = bound.clone();
+            bound.extend(params.iter().chain(body.keys()));
+            collect_free_vars(body, path, &bound, out);
+            path.pop();
+        }
+    }
+}
+
+/// Returns the free variables of a function. `bound` holds the ids bound by enclosing functions,
+/// which shadow stdlib names.
+fn fn_free_vars(params: &[Id], body: &Defs, bound: &HashSet<&Id>) -> HashSet<Id> {
+    let mut inner_bound = bound.clone();
+    inner_bound.extend(params.iter().chain(body.keys()));
+
+    let mut refs = HashSet::new();
+    for def in body.values() {
+        match def {
+            Def::Fn { params, body } => refs.extend(fn_free_vars(params, body, &inner_bound)),
+            def => refs.extend(def.refs().into_iter().cloned()),
+        }
+    }
+    refs.retain(|id| {
+        !params.contains(id)
+            && !body.contains_key(id)
+            && !id.starts_with('@')
+            && (bound.contains(id) || !is_stdlib(id))
+    });
+    refs
+}
+
+#[derive(Clone, Copy)]
+enum Literal {
+    Number(f64),
+    Bool(bool),
+}
+
+impl Literal {
+    fn from_def(def: &Def) -> Option<Self> {
+        match def {
+            Def::Number { value } => Some(Literal::Number(*value)),
+            Def::Bool { value } => Some(Literal::Bool(*value)),
+            _ => None,
+        }
+    }
+}
+
+/// Evaluates a call to a stdlib function the same way the runtime would, if possible.
+fn fold_call(f: &str, args: &[Literal]) -> Option<Def> {
+    use Literal::*;
+
+    let value = match (f, args) {
+        ("+", [Number(a), Number(b)]) => Number(a + b),
+        ("-", [Number(a), Number(b)]) => Number(a - b),
+        ("*", [Number(a), Number(b)]) => Number(a * b),
+        ("/", [Number(a), Number(b)]) => Number(a / b),
+        ("^", [Number(a), Number(b)]) => Number(a.powf(*b)),
+        ("mod", [Number(_), Number(b)]) if *b == 0. => Number(0.),
+        ("mod", [Number(a), Number(b)]) => {
+            let pa = b.signum() * a;
+            let pb = b.abs();
+            Number(((pa % pb) + pb) % pb)
+        }
+        ("==", [Number(a), Number(b)]) => Bool(a == b),
+        ("==", [Bool(a), Bool(b)]) => Bool(a == b),
+        ("==", [_, _]) => Bool(false),
+        ("!=", [Number(a), Number(b)]) => Bool(a != b),
+        ("!=", [Bool(a), Bool(b)]) => Bool(a != b),
+        ("!=", [_, _]) => Bool(true),
+        // comparisons compare the difference to 0, like the runtime
+        (">", [Number(a), Number(b)]) => Bool(a - b > 0.),
+        ("<", [Number(a), Number(b)]) => Bool(a - b < 0.),
+        (">=", [Number(a), Number(b)]) => Bool(a - b >= 0.),
+        ("<=", [Number(a), Number(b)]) => Bool(a - b <= 0.),
+        ("and", [Bool(a), Bool(b)]) => Bool(*a && *b),
+        ("or", [Bool(a), Bool(b)]) => Bool(*a || *b),
+        ("not", [Bool(a)]) => Bool(!a),
+        _ => return None,
+    };
+
+    match value {
+        // e.g. division by zero; leave those to the runtime
+        Number(value) if !value.is_finite() => None,
+        Number(value) => Some(Def::Number { value }),
+        Bool(value) => Some(Def::Bool { value }),
+    }
+}
+
+/// Replaces calls to arithmetic, comparison and logic stdlib functions whose arguments are all
+/// number or bool literals with the result, and removes argument definitions that are no longer
+/// used.
+pub fn fold_constants(defs: Defs) -> Defs {
+    fold_scope(defs, &HashSet::new())
+}
+
+/// Folds constants in one scope. `shadowed` contains parameters of enclosing functions.
+fn fold_scope(defs: Defs, shadowed: &HashSet<Id>) -> Defs {
+    let mut defs: Defs = defs
+        .into_iter()
+        .map(|(id, def)| match def {
+            Def::Fn { params, body } => {
+                let mut shadowed = shadowed.clone();
+                shadowed.extend(params.iter().cloned());
+                let body = fold_scope(body, &shadowed);
+                (id, Def::Fn { params, body })
+            }
+            def => (id, def),
+        })
+        .collect();
+
+    // fold until nothing changes, since folding one call may make its users foldable
+    let mut folded_args = Vec::new();
+    loop {
+        let folded: Vec<_> = defs
+            .iter()
+            .filter_map(|(id, def)| match def {
+                Def::Call { f, args } if !shadowed.contains(f) && !defs.contains_key(f) => {
+                    let args = args
+                        .iter()
+                        .map(|arg| defs.get(arg).and_then(Literal::from_def))
+                        .collect::<Option<Vec<_>>>()?;
+                    Some((id.clone(), fold_call(f, &args)?))
+                }
+                _ => None,
+            })
+            .collect();
+        if folded.is_empty() {
+            break;
+        }
+        for (id, value) in folded {
+            if let Some(Def::Call { args, .. }) = defs.insert(id, value) {
+                folded_args.extend(args);
+            }
+        }
+    }
+
+    let referenced: HashSet<Id> = referenced_ids(&defs).into_iter().cloned().collect();
+    for id in folded_args {
+        if is_private(&id) && !referenced.contains(&id) {
+            defs.remove(&id);
+        }
+    }
+    defs
+}
+
+/// Merges compiler-generated definitions that are structurally identical, rewriting references
+/// to point at a single surviving definition.
+pub fn dedupe(defs: Defs) -> Defs {
+    let mut defs: Defs = defs
+        .into_iter()
+        .map(|(id, def)| match def {
+            Def::Fn { params, body } => {
+                let body = dedupe(body);
+                (id, Def::Fn { params, body })
+            }
+            def => (id, def),
+        })
+        .collect();
+
+    // ids referenced from function bodies are never removed, so those don't need rewriting
+    let pinned: HashSet<Id> = escaped_ids(&defs).into_iter().cloned().collect();
+
+    // each round merges definitions whose references are already merged, so repeat until
+    // nothing changes
+    loop {
+        let mut ids: Vec<_> = defs.keys().cloned().collect();
+        // prefer keeping named definitions, then the lowest id
+        ids.sort_by_key(|id| (is_private(id), id.clone()));
+
+        let mut survivors = HashMap::new();
+        let mut renames = HashMap::new();
+        for id in ids {
+            let key = match &defs[&id] {
+                Def::Fn { .. } => continue,
+                def => format!("{:?}", def),
+            };
+            match survivors.get(&key) {
+                Some(survivor) if is_private(&id) && !pinned.contains(&id) => {
+                    renames.insert(id, Id::clone(survivor));
+                }
+                Some(_) => (),
+                None => {
+                    survivors.insert(key, id);
+                }
+            }
+        }
+
+        if renames.is_empty() {
+            break;
+        }
+        for id in renames.keys() {
+            defs.remove(id);
+        }
+        for def in defs.values_mut() {
+            for id in def.refs_mut() {
+                if let Some(survivor) = renames.get(id) {
+                    *id = survivor.clone();
+                }
+            }
+        }
+    }
+
+    defs
+}
+
+/// Inlines compiler-generated leaf definitions (literals and plain references) that are used
+/// exactly once in the same scope, and removes them.
+///
+/// A reference is inlined by referring to its target directly. Since a call without arguments
+/// calls its target if that's a function (e.g. `ts_now`), it's only a reference if its target
+/// isn't a function, or if its user is a call without arguments too. A literal can only be inlined
+/// into a definition that is a plain reference to it.
+pub fn inline_trivial(defs: Defs) -> Defs {
+    inline_scope(defs, &HashMap::new())
+}
+
+/// Inlines definitions in one scope, where `outer` tells whether the ids of enclosing scopes are
+/// functions. Parameters are assumed not to be.
+fn inline_scope(defs: Defs, outer: &HashMap<Id, bool>) -> Defs {
+    let mut functions = outer.clone();
+    for (id, def) in &defs {
+        functions.insert(id.clone(), matches!(def, Def::Fn { .. }));
+    }
+    let is_function = |id: &Id| functions.get(id).copied().unwrap_or_else(|| is_stdlib(id));
+
+    let mut defs: Defs = defs
+        .into_iter()
+        .map(|(id, def)| match def {
+            Def::Fn { params, body } => {
+                let mut inner = functions.clone();
+                inner.extend(params.iter().map(|param| (param.clone(), false)));
+                let body = inline_scope(body, &inner);
+                (id, Def::Fn { params, body })
+            }
+            def => (id, def),
+        })
+        .collect();
+
+    // ids referenced from function bodies may be shadowed there, so they're left alone
+    let pinned: HashSet<Id> = escaped_ids(&defs).into_iter().cloned().collect();
+
+    // inlining one definition may make its user inlinable, so repeat until nothing changes
+    loop {
+        let mut uses: HashMap<&Id, (usize, &Id)> = HashMap::new();
+        for (user, def) in &defs {
+            for id in def.refs() {
+                uses.entry(id).or_insert((0, user)).0 += 1;
+            }
+        }
+        let is_candidate = |id: &Id| {
+            is_private(id)
+                && !pinned.contains(id)
+                && matches!(uses.get(id), Some((1, _)))
+                && match &defs[id] {
+                    Def::Number { .. } | Def::String { .. } | Def::Bool { .. } | Def::Null => true,
+                    Def::Call { args, .. } => args.is_empty(),
+                    _ => false,
+                }
+        };
+
+        // candidates that refer to other candidates wait for the next round
+        let inlined: Vec<(Id, Id)> = defs
+            .iter()
+            .filter(|(id, def)| is_candidate(id) && !def.refs().into_iter().any(is_candidate))
+            .map(|(id, def)| (id, def, uses[id].1))
+            .filter(|(id, def, user)| match (def, &defs[*user]) {
+                (Def::Call { f: target, .. }, Def::Call { f, args }) if f == *id => {
+                    args.is_empty() || !is_function(target)
+                }
+                (Def::Call { f: target, .. }, _) => !is_function(target),
+                (_, Def::Call { f, args }) => f == *id && args.is_empty(),
+                _ => false,
+            })
+            .map(|(id, _, user)| (id.clone(), user.clone()))
+            .collect();
+
+        if inlined.is_empty() {
+            break;
+        }
+        for (id, user) in inlined {
+            match defs.remove(&id) {
+                Some(Def::Call { f: target, .. }) => {
+                    for r in defs.get_mut(&user).unwrap().refs_mut() {
+                        if *r == id {
+                            *r = target.clone();
+                        }
+                    }
+                }
+                Some(literal) => {
+                    defs.insert(user, literal);
+                }
+                None => unreachable!(),
+            }
+        }
+    }
+
+    defs
+}
+
+/// Renames all definitions and parameters to short generated names, except for external
+/// (`@`-prefixed) definitions and the `=` definition of function bodies. References to names that
+/// aren't defined (like stdlib functions) are kept, and those names are never generated.
+pub fn minify_ids(defs: Defs) -> Defs {
+    let mut unbound = UnboundIds {
+        scopes: Vec::new(),
+        ids: HashSet::new(),
+    };
+    unbound.visit_defs(&defs);
+    let mut names = NameGenerator {
+        next: 0,
+        reserved: unbound.ids,
+    };
+    minify_scope(defs, &mut Vec::new(), &mut names)
+}
+
+/// Generates names `a`, `b`, …, `Z`, `aa`, `ab`, … that aren't reserved.
+struct NameGenerator {
+    next: usize,
+    reserved: HashSet<Id>,
+}
+
+impl NameGenerator {
+    const CHARS: &'static [u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+    fn next(&mut self) -> Id {
+        loop {
+            let mut n = self.next;
+            self.next += 1;
+            let mut name = Vec::new();
+            loop {
+                name.push(Self::CHARS[n % Self::CHARS.len()]);
+                n /= Self::CHARS.len();
+                if n == 0 {
+                    break;
+                }
+                n -= 1;
+            }
+            name.reverse();
+            let name = String::from_utf8(name).unwrap();
+            if !is_stdlib(&name) && !self.reserved.contains(&name) {
+                return name;
+            }
+        }
+    }
+}
+
+fn keeps_id(id: &str) -> bool {
+    id == "=" || id.starts_with('@')
+}
+
+/// Collects ids referenced in definitions that aren't defined in any enclosing scope.
+struct UnboundIds<'a> {
+    scopes: Vec<HashSet<&'a Id>>,
+    ids: HashSet<Id>,
+}
+
+impl<'a> DefVisitor<'a> for UnboundIds<'a> {
+    fn visit_defs(&mut self, defs: &'a Defs) {
+        self.scopes.push(defs.keys().collect());
+        walk_defs(self, defs);
+        self.scopes.pop();
+    }
+
+    fn visit_fn(&mut self, params: &'a [Id], body: &'a Defs) {
+        self.scopes.push(params.iter().collect());
+        walk_fn(self, params, body);
+        self.scopes.pop();
+    }
+
+    fn visit_ref(&mut self, id: &'a Id) {
+        if !self.scopes.iter().any(|scope| scope.contains(id)) {
+            self.ids.insert(id.clone());
+        }
+    }
+}
+
+fn minify_scope(defs: Defs, scopes: &mut Vec<HashMap<Id, Id>>, names: &mut NameGenerator) -> Defs {
+    let renames = defs
+        .keys()
+        .map(|id| {
+            let name = if keeps_id(id) {
+                id.clone()
+            } else {
+                names.next()
+            };
+            (id.clone(), name)
+        })
+        .collect();
+    scopes.push(renames);
+
+    let defs = defs
+        .into_iter()
+        .map(|(id, def)| {
+            let def = match def {
+                Def::Fn { params, body } => {
+                    let renames: HashMap<_, _> =
+                        params.iter().map(|id| (id.clone(), names.next())).collect();
+                    let params = params.iter().map(|id| renames[id].clone()).collect();
+                    scopes.push(renames);
+                    let body = minify_scope(body, scopes, names);
+                    scopes.pop();
+                    Def::Fn { params, body }
+                }
+                mut def => {
+                    for id in def.refs_mut() {
+                        if let Some(name) = scopes.iter().rev().find_map(|scope| scope.get(id)) {
+                            *id = name.clone();
+                        }
+                    }
+                    def
+                }
+            };
+            (scopes.last().unwrap()[&id].clone(), def)
+        })
+        .collect();
+
+    scopes.pop();
+    defs
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// Returns a hash of the definitions that doesn't depend on how private ids (`_0`, `_1name`, …)
+/// are numbered, e.g. as a cache key for compiled output: definitions that only differ in the
+/// names of their private ids hash equal.
+///
+/// This is FNV-1a over the definitions serialized as JSON, so it's the same on every platform and
+/// with any version of Rust (but may change with the IR).
+pub fn program_hash(defs: &Defs) -> u64 {
+    let defs = canonical_private_ids(defs, &mut Vec::new(), &mut 0);
+    // ids are strings and non-finite numbers become null, so this can't fail
+    let json = serde_json::to_vec(&defs).expect("failed to serialize definitions");
+    json.iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// Renames private ids to `_0`, `_1`, … in the order they're reached from the other definitions
+/// (and then in the order of their names, for those that aren't reached at all), followed by
+/// those in function bodies.
+fn canonical_private_ids(defs: &Defs, scopes: &mut Vec<HashMap<Id, Id>>, next: &mut usize) -> Defs {
+    let mut renames = HashMap::new();
+    let mut order = Vec::with_capacity(defs.len());
+    let mut visited = HashSet::new();
+    let mut stack: Vec<_> = defs.keys().filter(|id| !is_private(id)).rev().collect();
+    let unreached = defs.keys().filter(|id| is_private(id));
+    while let Some(id) = stack
+        .pop()
+        .or_else(|| unreached.clone().find(|id| !visited.contains(id)))
+    {
+        if !defs.contains_key(id) || !visited.insert(id) {
+            continue;
+        }
+        if is_private(id) {
+            renames.insert(id.clone(), format!("_{}", next));
+            *next += 1;
+        }
+        order.push(id);
+        stack.extend(defs[id].refs().into_iter().rev());
+    }
+    scopes.push(renames);
+
+    let mut canonical = Defs::new();
+    for id in order {
+        let def = match &defs[id] {
+            Def::Fn { params, body } => {
+                let mut renames = HashMap::new();
+                for param in params.iter().filter(|id| is_private(id)) {
+                    renames.insert(param.clone(), format!("_{}", next));
+                    *next += 1;
+                }
+                let params = params
+                    .iter()
+                    .map(|id| renames.get(id).unwrap_or(id).clone())
+                    .collect();
+                scopes.push(renames);
+                let body = canonical_private_ids(body, scopes, next);
+                scopes.pop();
+                Def::Fn { params, body }
+            }
+            def => {
+                let mut def = def.clone();
+                for id in def.refs_mut() {
+                    if let Some(name) = scopes.iter().rev().find_map(|scope| scope.get(id)) {
+                        *id = name.clone();
+                    }
+                }
+                def
+            }
+        };
+        let id = scopes.last().unwrap().get(id).unwrap_or(id).clone();
+        canonical.insert(id, def);
+    }
+
+    scopes.pop();
+    canonical
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile_str;
+    use crate::ir::compile_many;
+
+    #[test]
+    fn unused() {
+        let defs = compile_str(
+            "helper x = x + 1; other_helper = 2; y = 3;
+            main = map helper [y]",
+        )
+        .unwrap();
+        assert_eq!(
+            unused_defs(&defs, &["main".into()]),
+            ["other_helper".to_string()]
+        );
+        assert!(unused_defs(&defs, &["main".into(), "other_helper".into()]).is_empty());
+
+        // references from function bodies count, but not if they refer to a parameter
+        let defs = compile_str("y = 1; x = 2; f x = x + y").unwrap();
+        assert_eq!(unused_defs(&defs, &["f".into()]), ["x".to_string()]);
+    }
+
+    #[test]
+    fn prune_unreachable() {
+        let defs = compile_str(
+            "helper = 1; other = helper + 1;
+            f x = let unused = other, used = x * 2 in used;
+            main = f 2",
+        )
+        .unwrap();
+        let defs = prune(defs, &["main".into()]);
+
+        let mut ids: Vec<_> = defs.keys().cloned().collect();
+        ids.sort();
+        // `other` is only used by `unused`, so it's gone too
+        assert_eq!(ids, ["_1", "f", "main"]);
+        match &defs["f"] {
+            Def::Fn { body, .. } => {
+                let ids: Vec<_> = body.keys().collect();
+                // `=`, `used` and `2` are kept, but not `unused`
+                assert_eq!(ids.len(), 3);
+                assert!(ids.iter().all(|id| !id.contains("unused")));
+            }
+            _ => panic!("expected function"),
+        }
+    }
+
+    #[test]
+    fn closures() {
+        let defs = compile_str(
+            "y = 1;
+            f x = let z = x + y in \\w -> w + z + y;
+            g = \\map -> \\a -> map a;
+            h a = map (\\b -> b + a) [@ext]",
+        )
+        .unwrap();
+        let set = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<HashSet<_>>();
+
+        assert_eq!(free_vars(&defs["f"]), set(&["y"]));
+        assert_eq!(free_vars(&defs["g"]), set(&[]));
+        assert_eq!(free_vars(&defs["h"]), set(&[]));
+        assert!(free_vars(&defs["y"]).is_empty());
+
+        let all = all_free_vars(&defs);
+        let inner = |f: &str| {
+            let mut inner: Vec<_> = all
+                .iter()
+                .filter(|(path, _)| path.len() == 2 && path[0] == f)
+                .map(|(_, free)| free.clone())
+                .collect();
+            assert_eq!(inner.len(), 1);
+            inner.pop().unwrap()
+        };
+        assert_eq!(all[&vec!["f".to_string()]], set(&["y"]));
+        // the lambda in `f` closes over the let binding `z`, whose id is private
+        let free = inner("f");
+        assert_eq!(free.len(), 2);
+        assert!(free.contains("y"));
+        assert!(free.iter().any(|id| is_private(id) && id.ends_with('z')));
+        // `map` is a parameter here (with a private id), not the stdlib function
+        assert_eq!(inner("g"), set(&["_0map"]));
+        assert_eq!(inner("h"), set(&["a"]));
+    }
+
+    #[test]
+    fn fold() {
+        let number = |def: &Def| match def {
+            Def::Number { value } => Some(*value),
+            _ => None,
+        };
+
+        let defs = fold_constants(compile_str("a = 1 + 2 * 3 - -1").unwrap());
+        assert_eq!(defs.len(), 1);
+        assert_eq!(number(&defs["a"]), Some(8.));
+
+        let defs = fold_constants(compile_str("x = 2; a = (x ^ 3) `mod` -3").unwrap());
+        assert_eq!(defs.len(), 2);
+        assert_eq!(number(&defs["a"]), Some(1.));
+
+        let defs = fold_constants(compile_str("a = !(1 < 2) `or` (2 >= 2)").unwrap());
+        assert!(matches!(defs["a"], Def::Bool { value: true }));
+
+        // division by zero is left to the runtime
+        let defs = fold_constants(compile_str("a = 1 / 0").unwrap());
+        assert!(matches!(&defs["a"], Def::Call { f, .. } if f == "/"));
+        assert_eq!(defs.len(), 3);
+
+        // parameters shadow stdlib functions
+        let defs = fold_constants(compile_str("f mod = mod 5 3; g x = 2 * 3 + x").unwrap());
+        match &defs["f"] {
+            Def::Fn { body, .. } => assert!(matches!(&body["="], Def::Call { .. })),
+            _ => panic!("expected function"),
+        }
+        match &defs["g"] {
+            Def::Fn { body, .. } => {
+                assert_eq!(body.len(), 2);
+                assert!(body.values().any(|def| number(def) == Some(6.)));
+            }
+            _ => panic!("expected function"),
+        }
+    }
+
+    #[test]
+    fn dedupe_subexprs() {
+        let defs = compile_str("x = 1; y = 2; a = (x + y) * (x + y)").unwrap();
+        assert_eq!(defs.len(), 5);
+        let defs = dedupe(defs);
+        assert_eq!(defs.len(), 4);
+        match &defs["a"] {
+            Def::Call { args, .. } => assert_eq!(args[0], args[1]),
+            _ => panic!("expected call"),
+        }
+
+        // literals are merged first, which then makes the calls identical
+        let defs = compile_str("a = (1 + 2) * (1 + 2)").unwrap();
+        assert_eq!(defs.len(), 7);
+        assert_eq!(dedupe(defs).len(), 4);
+
+        // named definitions are kept, and private ones can be merged into them
+        let defs = dedupe(compile_str("a = 1; b = 1; c = [a, 1, 1]").unwrap());
+        assert_eq!(defs.len(), 3);
+        match &defs["c"] {
+            Def::List { items } => assert_eq!(items, &["a", "a", "a"]),
+            _ => panic!("expected list"),
+        }
+    }
+
+    /// Where an id refers to, for comparing definitions up to renaming.
+    enum Target<'a> {
+        Def(&'a Def, usize),
+        Param(usize, usize),
+        Free(&'a Id),
+    }
+
+    fn lookup<'a>(scopes: &[(&'a Defs, &'a [Id])], id: &'a Id) -> Target<'a> {
+        for (depth, (defs, params)) in scopes.iter().enumerate().rev() {
+            if let Some(def) = defs.get(id) {
+                return Target::Def(def, depth);
+            }
+            if let Some(i) = params.iter().position(|param| param == id) {
+                return Target::Param(i, depth);
+            }
+        }
+        Target::Free(id)
+    }
+
+    /// Returns true if the ids refer to the same definitions up to renaming.
+    fn equivalent<'a>(
+        a: &[(&'a Defs, &'a [Id])],
+        a_id: &'a Id,
+        b: &[(&'a Defs, &'a [Id])],
+        b_id: &'a Id,
+    ) -> bool {
+        let (a_def, b_def, depth) = match (lookup(a, a_id), lookup(b, b_id)) {
+            (Target::Def(a, da), Target::Def(b, db)) if da == db => (a, b, da),
+            (Target::Param(a, da), Target::Param(b, db)) => return a == b && da == db,
+            (Target::Free(a), Target::Free(b)) => return a == b,
+            _ => return false,
+        };
+        let (a, b) = (&a[..=depth], &b[..=depth]);
+        match (a_def, b_def) {
+            (
+                Def::Fn {
+                    params: pa,
+                    body: ba,
+                },
+                Def::Fn {
+                    params: pb,
+                    body: bb,
+                },
+            ) => {
+                let a = [a, &[(ba, &pa[..])]].concat();
+                let b = [b, &[(bb, &pb[..])]].concat();
+                let ret = "=".to_string();
+                pa.len() == pb.len() && equivalent(&a, &ret, &b, &ret)
+            }
+            (Def::Call { f: fa, args: aa }, Def::Call { f: fb, args: ab }) => {
+                aa.len() == ab.len()
+                    && equivalent(a, fa, b, fb)
+                    && aa.iter().zip(ab).all(|(x, y)| equivalent(a, x, b, y))
+            }
+            (Def::List { items: ia }, Def::List { items: ib }) => {
+                ia.len() == ib.len() && ia.iter().zip(ib).all(|(x, y)| equivalent(a, x, b, y))
+            }
+            (Def::Switch { cases: ca }, Def::Switch { cases: cb }) => {
+                ca.len() == cb.len()
+                    && ca.iter().zip(cb).all(|(x, y)| {
+                        equivalent(a, &x.value, b, &y.value)
+                            && match (&x.cond, &y.cond) {
+                                (Some(x), Some(y)) => equivalent(a, x, b, y),
+                                (x, y) => x.is_none() && y.is_none(),
+                            }
+                    })
+            }
+            (a, b) => a == b,
+        }
+    }
+
+    #[test]
+    fn minify() {
+        let src = "numbers = [1, 2, 3]; offset = 2;
+            shifted = map (\\x -> x + offset) numbers;
+            f map x = let y = map x, z = @ext in \\w -> [y, z, w, x];
+            main = [f floor 1, shifted, numbers ?? []]";
+        let defs = compile_str(src).unwrap();
+        let minified = minify_ids(defs.clone());
+        assert_eq!(minified, minify_ids(defs.clone()));
+        assert_eq!(minified.len(), defs.len());
+
+        let longest = |defs: &Defs| defs.keys().map(|id| id.len()).max().unwrap();
+        assert!(longest(&minified) < longest(&defs));
+        assert!(minified.keys().all(|id| !is_stdlib(id)));
+
+        // every definition still means the same thing
+        let scope = |defs| vec![(defs, &[][..])];
+        for id in defs.keys() {
+            assert!(
+                minified.keys().any(|new_id| equivalent(
+                    &scope(&defs),
+                    id,
+                    &scope(&minified),
+                    new_id
+                )),
+                "{} changed",
+                id
+            );
+        }
+    }
+
+    #[test]
+    fn inline() {
+        let body = |defs: &Defs, id: &str| match &defs[id] {
+            Def::Fn { body, .. } => body.clone(),
+            _ => panic!("expected function"),
+        };
+
+        let defs = compile_str(
+            "a = let x = 5 in x;
+            b = let y = a in [y, 1];
+            f w = let z = w in z + 1;
+            g w = let z = 1 in \\v -> z",
+        )
+        .unwrap();
+        assert_eq!(defs.len(), 7);
+        assert_eq!(body(&defs, "f").len(), 3);
+        assert_eq!(body(&defs, "g").len(), 2);
+
+        let defs = inline_trivial(defs);
+        assert_eq!(defs.len(), 5);
+        assert!(matches!(defs["a"], Def::Number { value } if value == 5.));
+        assert!(matches!(&defs["b"], Def::List { items } if items[0] == "a"));
+        // the let binding in `f` is replaced by the parameter
+        let f = body(&defs, "f");
+        assert_eq!(f.len(), 2);
+        assert!(matches!(&f["="], Def::Call { args, .. } if args[0] == "w"));
+        // `z` is referenced from a function body, so it's not touched
+        assert_eq!(body(&defs, "g").len(), 2);
+
+        // a function without parameters is called, not referenced
+        let defs =
+            compile_str("f = [ts_now, 1]; g x = let y = ts_now in [y, x]; h = ts_now").unwrap();
+        let inlined = inline_trivial(defs.clone());
+        assert_eq!(inlined["f"], defs["f"]);
+        assert_eq!(body(&inlined, "g"), body(&defs, "g"));
+        assert!(matches!(&inlined["h"], Def::Call { f, args } if f == "ts_now" && args.is_empty()));
+    }
+
+    #[test]
+    fn hash() {
+        let parse = |src| crate::parse(src).unwrap();
+        let a = "a = [x, 1 + 2]; f x = map (\\y -> y * x + 1) [x, 2]";
+        let b = "x = 3 + 4; g = f x; h = (+) 1";
+        let ab = compile_many(vec![parse(a), parse(b)]).unwrap();
+        let ba = compile_many(vec![parse(b), parse(a)]).unwrap();
+        // the private ids are numbered differently
+        assert_ne!(ab, ba);
+        assert_eq!(program_hash(&ab), program_hash(&ba));
+        assert_eq!(program_hash(&ab), program_hash(&ab.clone()));
+
+        assert_ne!(
+            program_hash(&compile_str("a = 1 + 2").unwrap()),
+            program_hash(&compile_str("a = 2 + 1").unwrap())
+        );
+        assert_ne!(
+            program_hash(&compile_str("a = 1").unwrap()),
+            program_hash(&compile_str("b = 1").unwrap())
+        );
+        // the numbering of private ids is all that's ignored
+        let defs = compile_str("a = 1 + 2").unwrap();
+        let renamed = defs
+            .iter()
+            .map(|(id, def)| {
+                let mut def = def.clone();
+                for id in def.refs_mut() {
+                    *id = id.replace('_', "_1");
+                }
+                (id.replace('_', "_1"), def)
+            })
+            .collect();
+        assert_ne!(defs, renamed);
+        assert_eq!(program_hash(&defs), program_hash(&renamed));
+    }
+}
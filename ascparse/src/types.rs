@@ -0,0 +1,209 @@
+//! Types of expressions, as far as they're obvious without inference across definitions, and the
+//! parameter types of standard library functions. Used by `CompileOptions::check_types`.
+
+use crate::ast::{Expr, ExprKind, Op};
+use std::cmp::Ordering;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    Number,
+    String,
+    Bool,
+    Null,
+    /// A list or matrix.
+    List,
+    Fn,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Type::Number => "number",
+            Type::String => "string",
+            Type::Bool => "bool",
+            Type::Null => "null",
+            Type::List => "list",
+            Type::Fn => "function",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The types a standard library function accepts for each parameter (where an empty slice means
+/// any type), and the type it returns, if known.
+pub(crate) struct Signature {
+    pub params: &'static [&'static [Type]],
+    pub returns: Option<Type>,
+}
+
+const ANY: &[Type] = &[];
+const NUMBER: &[Type] = &[Type::Number];
+const BOOL: &[Type] = &[Type::Bool];
+const LIST: &[Type] = &[Type::List];
+const SEQUENCE: &[Type] = &[Type::List, Type::String];
+const COMPARABLE: &[Type] = &[Type::Number, Type::String];
+
+/// Returns the signature of a standard library function. Functions that aren't listed aren't
+/// checked at all.
+///
+/// A parameter only excludes the types for which the function (in `src/stdlib.js`) ignores its
+/// arguments and returns null or false. Many functions also take strings, or wrap other values in
+/// a list, and return the same kind of value, so their return type isn't known.
+pub(crate) fn signature(name: &str) -> Option<Signature> {
+    let (params, returns): (&[&[Type]], _) = match name {
+        "+" | "-" | "*" | "/" | "^" | "mod" => (&[NUMBER, NUMBER], Some(Type::Number)),
+        "floor" | "ceil" | "round" | "trunc" | "sign" | "abs" => (&[NUMBER], Some(Type::Number)),
+        "==" | "!=" => (&[ANY, ANY], Some(Type::Bool)),
+        ">" | "<" | ">=" | "<=" => (&[COMPARABLE, COMPARABLE], Some(Type::Bool)),
+        "and" | "or" | "xor" => (&[BOOL, BOOL], Some(Type::Bool)),
+        "not" => (&[BOOL], Some(Type::Bool)),
+        // anything that isn't a function is mapified to a constant function, and anything that
+        // isn't a list or string is treated like a list of one item
+        "++" => (&[ANY, ANY], None),
+        "map" | "flat_map" => (&[ANY, ANY], None),
+        "fold" => (&[ANY, ANY, ANY], None),
+        "fold1" => (&[ANY, SEQUENCE], None),
+        "index" => (&[SEQUENCE, NUMBER], None),
+        "length" => (&[SEQUENCE], Some(Type::Number)),
+        "contains" => (&[SEQUENCE, ANY], Some(Type::Bool)),
+        "head" | "tail" => (&[SEQUENCE, NUMBER], None),
+        // `sum` is a fold with `+`, so a number is its own sum
+        "sum" => (&[&[Type::Number, Type::List]], Some(Type::Number)),
+        "avg" => (&[LIST], Some(Type::Number)),
+        // these return an item (or a string, if sorting a string)
+        "min" | "max" | "med" | "sort" => (&[SEQUENCE], None),
+        _ => return None,
+    };
+    Some(Signature { params, returns })
+}
+
+/// Returns the type of an expression if it's obvious, i.e. for literals and calls to standard
+/// library functions with a known return type. `is_stdlib` returns true if a name refers to the
+/// standard library (i.e. it isn't shadowed).
+///
+/// Names aren't resolved, and `let` and `where` aren't looked into (since they may shadow the
+/// standard library), so their type is never known.
+pub(crate) fn expr_type(expr: &Expr, is_stdlib: &dyn Fn(&str) -> bool) -> Option<Type> {
+    match &expr.kind {
+        ExprKind::Number(..) => Some(Type::Number),
+        // dates are strings at runtime
+        ExprKind::String(_) | ExprKind::Date(_) => Some(Type::String),
+        ExprKind::Bool(_) => Some(Type::Bool),
+        ExprKind::Null => Some(Type::Null),
+        ExprKind::List(_) => Some(Type::List),
+        ExprKind::Lambda(_) => Some(Type::Fn),
+        // a reference to a stdlib function that takes arguments is the function itself
+        ExprKind::Ident(ident) if is_stdlib(&ident.name) => signature(&ident.name)
+            .filter(|sig| !sig.params.is_empty())
+            .map(|_| Type::Fn),
+        ExprKind::Group(inner) => expr_type(inner, is_stdlib),
+        ExprKind::If(_, t, e) => same_type([&**t, &**e].iter().copied(), is_stdlib),
+        ExprKind::Match(arms) => same_type(arms.iter().map(|(_, value)| value), is_stdlib),
+        ExprKind::Unary(Op::Infix(op), _) => match &*op.name {
+            "-" if is_stdlib("-") => Some(Type::Number),
+            "!" if is_stdlib("not") => Some(Type::Bool),
+            _ => None,
+        },
+        ExprKind::Apply(_, Op::Infix(op), _) => {
+            let name = match &*op.name {
+                "%" if !is_stdlib("%") => "mod",
+                // `??` and `|>` aren't functions (unless defined, in which case they're unknown)
+                "??" | "|>" => return None,
+                name => name,
+            };
+            return_type(name, 2, is_stdlib)
+        }
+        ExprKind::Apply(a, Op::Apply, _) => {
+            let mut head = &**a;
+            let mut args = 1;
+            while let ExprKind::Apply(a, Op::Apply, _) = &head.kind {
+                head = a;
+                args += 1;
+            }
+            match &head.kind {
+                ExprKind::Ident(ident) => return_type(&ident.name, args, is_stdlib),
+                _ => None,
+            }
+        }
+        ExprKind::Ident(_)
+        | ExprKind::Let(..)
+        | ExprKind::Where(..)
+        | ExprKind::DateTime(_)
+        | ExprKind::Spread(_)
+        | ExprKind::Index(..)
+        | ExprKind::Unary(Op::Apply, _) => None,
+    }
+}
+
+/// Returns the type of a call to a standard library function, which is a function if there are
+/// fewer arguments than parameters.
+fn return_type(name: &str, args: usize, is_stdlib: &dyn Fn(&str) -> bool) -> Option<Type> {
+    if !is_stdlib(name) {
+        return None;
+    }
+    let sig = signature(name)?;
+    match args.cmp(&sig.params.len()) {
+        Ordering::Less => Some(Type::Fn),
+        Ordering::Equal => sig.returns,
+        Ordering::Greater => None,
+    }
+}
+
+/// Returns the type of all expressions, if it's known and the same.
+fn same_type<'a>(
+    mut exprs: impl Iterator<Item = &'a Expr>,
+    is_stdlib: &dyn Fn(&str) -> bool,
+) -> Option<Type> {
+    let first = expr_type(exprs.next()?, is_stdlib)?;
+    exprs
+        .all(|expr| expr_type(expr, is_stdlib) == Some(first))
+        .then_some(first)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::is_stdlib;
+    use crate::parse;
+
+    fn type_of(src: &str) -> Option<Type> {
+        let prog = parse(&format!("a = {}", src)).unwrap();
+        expr_type(&prog.0[0].body, &|name| is_stdlib(name))
+    }
+
+    #[test]
+    fn types() {
+        assert_eq!(type_of("1"), Some(Type::Number));
+        assert_eq!(type_of("@2024-01-15"), Some(Type::String));
+        assert_eq!(type_of("[x, 1]"), Some(Type::List));
+        assert_eq!(type_of("\\x -> x"), Some(Type::Fn));
+        assert_eq!(type_of("floor"), Some(Type::Fn));
+        assert_eq!(type_of("map floor"), Some(Type::Fn));
+        assert_eq!(type_of("(length x) + 1"), Some(Type::Number));
+        assert_eq!(type_of("7 % 2"), Some(Type::Number));
+        assert_eq!(type_of("x < y < z"), Some(Type::Bool));
+        assert_eq!(type_of("!x"), Some(Type::Bool));
+        assert_eq!(type_of("if x then 1 else -y"), Some(Type::Number));
+        assert_eq!(
+            type_of("cond { x -> \"a\", else -> \"b\" }"),
+            Some(Type::String)
+        );
+
+        // unknown
+        assert_eq!(type_of("x"), None);
+        assert_eq!(type_of("ts_now"), None);
+        assert_eq!(type_of("if x then 1 else \"a\""), None);
+        assert_eq!(type_of("x ?? 1"), None);
+        assert_eq!(type_of("index x 1"), None);
+        assert_eq!(type_of("length x y"), None);
+        assert_eq!(type_of("let y = 1 in y + 1"), None);
+        assert_eq!(type_of("(\\x -> x) 1"), None);
+
+        // shadowed names are unknown
+        let prog = parse("a = length x + 1").unwrap();
+        let body = &prog.0[0].body;
+        assert_eq!(expr_type(body, &|name| name != "+"), None);
+        assert_eq!(expr_type(body, &|name| name == "+"), Some(Type::Number));
+    }
+}
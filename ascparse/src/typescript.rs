@@ -0,0 +1,152 @@
+//! Generates TypeScript type definitions for serialized definitions.
+//!
+//! The types are derived from how example definitions serialize, so tags and field names always
+//! match the serde attributes on `Def`.
+
+use crate::ir::{Def, Defs, SwitchCase};
+use serde_json::{json, Value};
+
+/// Returns one example of each kind of definition, with every field filled in so that its type
+/// can be told from the serialized value.
+fn examples() -> Vec<Def> {
+    let mut body = Defs::new();
+    body.insert("=".into(), Def::Null);
+
+    let examples = vec![
+        Def::Number { value: 0. },
+        Def::String {
+            value: String::new(),
+        },
+        Def::Matrix {
+            value: vec![json!(null), json!(true), json!(0), json!(""), json!([])],
+        },
+        Def::Bool { value: false },
+        Def::Null,
+        Def::List {
+            items: vec![String::new()],
+        },
+        Def::Call {
+            f: String::new(),
+            args: vec![String::new()],
+        },
+        Def::Fn {
+            params: vec![String::new()],
+            body,
+        },
+        Def::Switch {
+            cases: vec![
+                SwitchCase {
+                    cond: Some(String::new()),
+                    value: String::new(),
+                },
+                SwitchCase {
+                    cond: None,
+                    value: String::new(),
+                },
+            ],
+        },
+    ];
+
+    // a new kind of definition won't compile until it's handled here (and given an example)
+    for def in &examples {
+        match def {
+            Def::Number { .. }
+            | Def::String { .. }
+            | Def::Matrix { .. }
+            | Def::Bool { .. }
+            | Def::Null
+            | Def::List { .. }
+            | Def::Call { .. }
+            | Def::Fn { .. }
+            | Def::Switch { .. } => (),
+        }
+    }
+    examples
+}
+
+fn is_def(value: &Value) -> bool {
+    matches!(value, Value::Object(fields) if matches!(fields.get("t"), Some(Value::String(_))))
+}
+
+/// Returns the TypeScript type of a serialized value.
+fn ts_type(value: &Value) -> String {
+    match value {
+        Value::Null => "null".into(),
+        Value::Bool(_) => "boolean".into(),
+        Value::Number(_) => "number".into(),
+        Value::String(_) => "string".into(),
+        Value::Array(items) => {
+            let mut types: Vec<_> = items.iter().map(ts_type).collect();
+            types.dedup();
+            match types.len() {
+                0 => "unknown[]".into(),
+                1 => format!("{}[]", types[0]),
+                _ => format!("({})[]", types.join(" | ")),
+            }
+        }
+        Value::Object(fields) if fields.values().all(is_def) => "Defs".into(),
+        Value::Object(fields) => {
+            let fields: Vec<_> = fields
+                .iter()
+                .map(|(key, value)| format!("{}: {}", key, ts_type(value)))
+                .collect();
+            format!("{{ {} }}", fields.join("; "))
+        }
+    }
+}
+
+/// Returns the TypeScript type of a serialized definition, with its tag as a literal type.
+fn def_type(value: &Value) -> String {
+    let fields = match value {
+        Value::Object(fields) => fields,
+        _ => unreachable!("definitions serialize to objects"),
+    };
+    let mut out = vec![format!("t: {}", fields["t"])];
+    for (key, value) in fields.iter().filter(|(key, _)| *key != "t") {
+        out.push(format!("{}: {}", key, ts_type(value)));
+    }
+    format!("{{ {} }}", out.join("; "))
+}
+
+/// Returns a TypeScript declaration file describing definitions as they're serialized.
+pub fn definitions() -> String {
+    let mut out = String::from(
+        "// Generated by `ascparse --typescript`.\n\
+         export type Defs = { [id: string]: Def };\n\
+         export type Def =\n",
+    );
+    for def in examples() {
+        let value = serde_json::to_value(def).expect("definitions can be serialized");
+        out.push_str("    | ");
+        out.push_str(&def_type(&value));
+        out.push('\n');
+    }
+    out.pop();
+    out.push_str(";\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typescript() {
+        assert_eq!(
+            definitions(),
+            r#"// Generated by `ascparse --typescript`.
+export type Defs = { [id: string]: Def };
+export type Def =
+    | { t: "n"; v: number }
+    | { t: "s"; v: string }
+    | { t: "m"; v: (null | boolean | number | string | unknown[])[] }
+    | { t: "b"; v: boolean }
+    | { t: "u" }
+    | { t: "l"; v: string[] }
+    | { t: "c"; a: string[]; f: string }
+    | { t: "f"; b: Defs; p: string[] }
+    | { t: "w"; m: ({ c: string; v: string } | { c: null; v: string })[] };
+"#
+        );
+    }
+}
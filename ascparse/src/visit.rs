@@ -0,0 +1,332 @@
+//! Traversal of the syntax tree (`Visitor`) and of compiled definitions (`DefVisitor` and
+//! `DefVisitorMut`).
+//!
+//! Each method of `Visitor` recurses into the children of its node by default, by calling the
+//! `walk_*` function of the same name. An override can call it too, to keep recursing:
+//!
+//! ```
+//! use ascparse::ast::Lambda;
+//! use ascparse::visit::{walk_lambda, Visitor};
+//!
+//! #[derive(Default)]
+//! struct Lambdas(usize);
+//!
+//! impl<'ast> Visitor<'ast> for Lambdas {
+//!     fn visit_lambda(&mut self, lambda: &'ast Lambda) {
+//!         self.0 += 1;
+//!         walk_lambda(self, lambda);
+//!     }
+//! }
+//!
+//! let prog = ascparse::parse("f = \\x -> \\y -> x + y").unwrap();
+//! let mut lambdas = Lambdas::default();
+//! lambdas.visit_program(&prog);
+//! assert_eq!(lambdas.0, 2);
+//! ```
+//!
+//! Recursion is bounded by the parser's nesting limit (see `parse_with_max_depth`), except for
+//! chains of operators (which recurse once per operator), and not at all for syntax trees
+//! constructed by hand.
+
+use crate::ast::{Decl, Expr, ExprKind, Ident, Lambda, Op, Program};
+use crate::ir::{Def, Defs, Id};
+
+pub trait Visitor<'ast> {
+    fn visit_program(&mut self, prog: &'ast Program) {
+        walk_program(self, prog)
+    }
+
+    /// Visits a declaration at the top level or in a `let` or `where`.
+    fn visit_decl(&mut self, decl: &'ast Decl) {
+        walk_decl(self, decl)
+    }
+
+    fn visit_expr(&mut self, expr: &'ast Expr) {
+        walk_expr(self, expr)
+    }
+
+    fn visit_lambda(&mut self, lambda: &'ast Lambda) {
+        walk_lambda(self, lambda)
+    }
+
+    fn visit_op(&mut self, op: &'ast Op) {
+        walk_op(self, op)
+    }
+
+    /// Visits a declared name, a parameter, a reference, or an infix operator.
+    fn visit_ident(&mut self, _ident: &'ast Ident) {}
+}
+
+pub fn walk_program<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, prog: &'ast Program) {
+    for decl in &prog.0 {
+        visitor.visit_decl(decl);
+    }
+}
+
+/// Visits the name, then the parameters, then the body.
+pub fn walk_decl<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, decl: &'ast Decl) {
+    visitor.visit_ident(&decl.name);
+    for param in &decl.params {
+        visitor.visit_ident(param);
+    }
+    visitor.visit_expr(&decl.body);
+}
+
+/// Visits the children of an expression in source order (except for `Let`, where declarations
+/// come first, like in `Where`).
+pub fn walk_expr<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, expr: &'ast Expr) {
+    match &expr.kind {
+        ExprKind::Ident(ident) => visitor.visit_ident(ident),
+        ExprKind::Group(inner) | ExprKind::Spread(inner) => visitor.visit_expr(inner),
+        ExprKind::Index(list, index) => {
+            visitor.visit_expr(list);
+            visitor.visit_expr(index);
+        }
+        ExprKind::Let(decls, inner) | ExprKind::Where(inner, decls) => {
+            for decl in decls {
+                visitor.visit_decl(decl);
+            }
+            visitor.visit_expr(inner);
+        }
+        ExprKind::Apply(a, op, b) => {
+            visitor.visit_expr(a);
+            visitor.visit_op(op);
+            visitor.visit_expr(b);
+        }
+        ExprKind::Unary(op, operand) => {
+            visitor.visit_op(op);
+            visitor.visit_expr(operand);
+        }
+        ExprKind::List(items) => {
+            for item in items {
+                visitor.visit_expr(item);
+            }
+        }
+        ExprKind::Lambda(lambda) => visitor.visit_lambda(lambda),
+        ExprKind::If(c, t, e) => {
+            visitor.visit_expr(c);
+            visitor.visit_expr(t);
+            visitor.visit_expr(e);
+        }
+        ExprKind::Match(arms) => {
+            for (cond, value) in arms {
+                if let Some(cond) = cond {
+                    visitor.visit_expr(cond);
+                }
+                visitor.visit_expr(value);
+            }
+        }
+        ExprKind::Number(..)
+        | ExprKind::String(_)
+        | ExprKind::Bool(_)
+        | ExprKind::Null
+        | ExprKind::Date(_)
+        | ExprKind::DateTime(_) => (),
+    }
+}
+
+pub fn walk_lambda<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, lambda: &'ast Lambda) {
+    for param in &lambda.params {
+        visitor.visit_ident(param);
+    }
+    visitor.visit_expr(&lambda.body);
+}
+
+pub fn walk_op<'ast, V: Visitor<'ast> + ?Sized>(visitor: &mut V, op: &'ast Op) {
+    match op {
+        Op::Infix(ident) => visitor.visit_ident(ident),
+        Op::Apply => (),
+    }
+}
+
+/// Traverses compiled definitions. Function bodies are nested scopes, so each `Defs` is visited
+/// with `visit_defs`, which is a good place to track which ids are in scope.
+pub trait DefVisitor<'a> {
+    /// Visits the definitions of a scope, i.e. the top level or a function body.
+    fn visit_defs(&mut self, defs: &'a Defs) {
+        walk_defs(self, defs)
+    }
+
+    fn visit_def(&mut self, id: &'a Id, def: &'a Def) {
+        walk_def(self, id, def)
+    }
+
+    /// Visits a function's parameters (which are in scope in its body) and body.
+    fn visit_fn(&mut self, params: &'a [Id], body: &'a Defs) {
+        walk_fn(self, params, body)
+    }
+
+    /// Visits an id referenced by a list, call or switch. It may be defined in the same scope,
+    /// an enclosing one, or not at all (e.g. stdlib functions).
+    fn visit_ref(&mut self, _id: &'a Id) {}
+}
+
+pub fn walk_defs<'a, V: DefVisitor<'a> + ?Sized>(visitor: &mut V, defs: &'a Defs) {
+    for (id, def) in defs {
+        visitor.visit_def(id, def);
+    }
+}
+
+pub fn walk_def<'a, V: DefVisitor<'a> + ?Sized>(visitor: &mut V, _id: &'a Id, def: &'a Def) {
+    match def {
+        Def::Fn { params, body } => visitor.visit_fn(params, body),
+        def => {
+            for id in def.refs() {
+                visitor.visit_ref(id);
+            }
+        }
+    }
+}
+
+pub fn walk_fn<'a, V: DefVisitor<'a> + ?Sized>(visitor: &mut V, _params: &'a [Id], body: &'a Defs) {
+    visitor.visit_defs(body)
+}
+
+/// Like `DefVisitor`, but can change definitions in place. Ids of definitions can't be changed,
+/// since they're the keys of `Defs`.
+pub trait DefVisitorMut {
+    fn visit_defs_mut(&mut self, defs: &mut Defs) {
+        walk_defs_mut(self, defs)
+    }
+
+    fn visit_def_mut(&mut self, id: &Id, def: &mut Def) {
+        walk_def_mut(self, id, def)
+    }
+
+    fn visit_fn_mut(&mut self, params: &mut Vec<Id>, body: &mut Defs) {
+        walk_fn_mut(self, params, body)
+    }
+
+    fn visit_ref_mut(&mut self, _id: &mut Id) {}
+}
+
+pub fn walk_defs_mut<V: DefVisitorMut + ?Sized>(visitor: &mut V, defs: &mut Defs) {
+    for (id, def) in defs {
+        visitor.visit_def_mut(id, def);
+    }
+}
+
+pub fn walk_def_mut<V: DefVisitorMut + ?Sized>(visitor: &mut V, _id: &Id, def: &mut Def) {
+    match def {
+        Def::Fn { params, body } => visitor.visit_fn_mut(params, body),
+        def => {
+            for id in def.refs_mut() {
+                visitor.visit_ref_mut(id);
+            }
+        }
+    }
+}
+
+pub fn walk_fn_mut<V: DefVisitorMut + ?Sized>(
+    visitor: &mut V,
+    _params: &mut Vec<Id>,
+    body: &mut Defs,
+) {
+    visitor.visit_defs_mut(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compile_str, parse};
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    struct Names<'ast> {
+        idents: Vec<&'ast str>,
+        decls: usize,
+        exprs: usize,
+    }
+
+    impl<'ast> Visitor<'ast> for Names<'ast> {
+        fn visit_decl(&mut self, decl: &'ast Decl) {
+            self.decls += 1;
+            walk_decl(self, decl);
+        }
+
+        fn visit_expr(&mut self, expr: &'ast Expr) {
+            self.exprs += 1;
+            walk_expr(self, expr);
+        }
+
+        fn visit_ident(&mut self, ident: &'ast Ident) {
+            self.idents.push(&ident.name);
+        }
+    }
+
+    #[test]
+    fn visit() {
+        let prog = parse(
+            "f a = let b = -a in (\\c -> [b, ...c]) a `max` 1; \
+             g = h where h = cond { f 1 > 2 -> 3, else -> if true then 4 else 5 }",
+        )
+        .unwrap();
+        let mut names = Names::default();
+        names.visit_program(&prog);
+        assert_eq!(
+            names.idents,
+            ["f", "a", "b", "-", "a", "c", "b", "c", "a", "max", "g", "h", "f", ">", "h"]
+        );
+        assert_eq!(names.decls, 4);
+        // the default implementations reach every expression
+        assert_eq!(names.exprs, 26);
+
+        // not recursing skips the children
+        struct TopLevel(usize);
+        impl<'ast> Visitor<'ast> for TopLevel {
+            fn visit_expr(&mut self, _: &'ast Expr) {
+                self.0 += 1;
+            }
+        }
+        let mut top_level = TopLevel(0);
+        top_level.visit_program(&prog);
+        assert_eq!(top_level.0, 2);
+    }
+
+    #[test]
+    fn visit_defs() {
+        // counts references to each id, and the scopes they're in
+        #[derive(Default)]
+        struct Refs<'a> {
+            refs: HashMap<&'a str, usize>,
+            scopes: usize,
+        }
+        impl<'a> DefVisitor<'a> for Refs<'a> {
+            fn visit_defs(&mut self, defs: &'a Defs) {
+                self.scopes += 1;
+                walk_defs(self, defs);
+            }
+
+            fn visit_ref(&mut self, id: &'a Id) {
+                *self.refs.entry(id).or_default() += 1;
+            }
+        }
+
+        let mut defs =
+            compile_str("a = 1; f x = x + a; g = [a, f 2]; h b = if b then \\y -> y else a")
+                .unwrap();
+        let mut refs = Refs::default();
+        refs.visit_defs(&defs);
+        assert_eq!(refs.scopes, 4);
+        assert_eq!(refs.refs["a"], 3);
+        assert_eq!(refs.refs["x"], 1);
+        assert_eq!(refs.refs["f"], 1);
+        assert_eq!(refs.refs["y"], 1);
+        assert_eq!(refs.refs["+"], 1);
+
+        // renames references everywhere, including in nested functions
+        struct Rename;
+        impl DefVisitorMut for Rename {
+            fn visit_ref_mut(&mut self, id: &mut Id) {
+                if id == "a" {
+                    *id = "@a".into();
+                }
+            }
+        }
+        Rename.visit_defs_mut(&mut defs);
+        let mut refs = Refs::default();
+        refs.visit_defs(&defs);
+        assert!(!refs.refs.contains_key("a"));
+        assert_eq!(refs.refs["@a"], 3);
+    }
+}
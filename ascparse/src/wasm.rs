@@ -0,0 +1,81 @@
+//! JavaScript bindings, enabled with the `wasm` feature.
+
+use crate::diagnostic::Position;
+use crate::ir::CompileError;
+use js_sys::{Error, Reflect};
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+
+/// Returns the offset in UTF-16 code units (which JavaScript strings are indexed by) of a byte
+/// offset in the source.
+fn utf16_offset(src: &str, offset: usize) -> usize {
+    src.char_indices()
+        .take_while(|(i, _)| *i < offset)
+        .map(|(_, c)| c.len_utf16())
+        .sum()
+}
+
+/// Returns the line and column of a byte offset in the source, where the column counts UTF-16
+/// code units (both start at 1).
+fn utf16_position(src: &str, offset: usize) -> (usize, usize) {
+    let line = Position::of(src, offset).line;
+    let line_start = src[..offset.min(src.len())]
+        .rfind('\n')
+        .map_or(0, |i| i + 1);
+    let column = utf16_offset(src, offset) - utf16_offset(src, line_start) + 1;
+    (line, column)
+}
+
+/// Converts a compile error to a JS `Error` with additional `start` and `end` properties for
+/// the offsets in the source, and `line` and `column` properties for the position of the start
+/// (all `null` if unknown). Offsets and columns are in UTF-16 code units, like indices into a
+/// JavaScript string, rather than bytes.
+fn to_js_error(err: &CompileError, src: &str) -> JsValue {
+    let js_err = Error::new(&err.to_string());
+    let span = err.span();
+    let position = span.as_ref().map(|span| utf16_position(src, span.start));
+    let line = position.map_or(JsValue::NULL, |(line, _)| line.into());
+    let column = position.map_or(JsValue::NULL, |(_, column)| column.into());
+    let start = span
+        .as_ref()
+        .map_or(JsValue::NULL, |span| utf16_offset(src, span.start).into());
+    let end = span
+        .as_ref()
+        .map_or(JsValue::NULL, |span| utf16_offset(src, span.end).into());
+    // these can only fail on frozen objects or proxies
+    let _ = Reflect::set(&js_err, &"start".into(), &start);
+    let _ = Reflect::set(&js_err, &"end".into(), &end);
+    let _ = Reflect::set(&js_err, &"line".into(), &line);
+    let _ = Reflect::set(&js_err, &"column".into(), &column);
+    js_err.into()
+}
+
+/// Compiles source code to AKSO script definitions, returned as a plain object.
+///
+/// Throws an `Error` on parse and compile errors.
+#[wasm_bindgen]
+pub fn compile(src: &str) -> Result<JsValue, JsValue> {
+    let defs = crate::compile_str(src).map_err(|err| to_js_error(&err, src))?;
+    defs.serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+        .map_err(JsValue::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utf16_offsets() {
+        let src = "a = \"\u{e9}\u{1F600}\";\nb = \"\u{e9}\" ++ c";
+        let err = crate::compile_str(src).unwrap_err();
+        let span = err.span().unwrap();
+        assert_eq!(&src[span.clone()], "c");
+        // `é` is 2 bytes, but 1 code unit, and the emoji 4 bytes, but 2 code units
+        assert_eq!(span.start, 26);
+        assert_eq!(utf16_offset(src, span.start), 22);
+        assert_eq!(utf16_offset(src, span.end), 23);
+        assert_eq!(utf16_position(src, span.start), (2, 12));
+        assert_eq!(utf16_position(src, src.find('"').unwrap() + 7), (1, 9));
+        assert_eq!(utf16_offset(src, src.len()), src.encode_utf16().count());
+    }
+}